@@ -1,8 +1,381 @@
+// The kernel is linked in the upper half (see src/memory/layout.rs) but the
+// loader jumps here at its physical address with the MMU off. Until the jump
+// to higher_half only PC-relative addressing (adr/adrp) may be used.
+//
+// Two ways in: QEMU loads kernel64.elf at its link address and enters at
+// _Start, Image loaders (QEMU -kernel kernel64.bin, U-Boot booti) load
+// kernel64.bin wherever suits them and enter at the header. The direct map
+// needs the kernel at its link address, so _Start copies it there first:
+// the start of RAM on QEMU virt, another machine needs KERNEL_LOAD_ADDR
+// changed to suit.
+
+.equ PHYS_OFFSET, 0xffff000000000000
+
+// 1GiB L1 blocks: AF | AttrIndx | valid, inner shareable for normal memory
+.equ BOOT_BLOCK_DEVICE, (3 << 53) | (1 << 10) | (0 << 2) | 0b01
+.equ BOOT_BLOCK_NORMAL, (3 << 8) | (1 << 10) | (1 << 2) | 0b01
+// RAM covered by the boot tables: 1GiB up to this limit (virt puts RAM at 1GiB)
+.equ BOOT_RAM_END_GIB, 9
+
+// Exception level setup for loaders that enter above EL1
+.equ SCR_EL3_BOOT, (1 << 10) | (1 << 8) | (0b11 << 4) | (1 << 0)  // RW | HCE | RES1 | NS
+.equ HCR_EL2_BOOT, (1 << 31)      // RW: EL1 runs AArch64, nothing trapped
+.equ CPTR_EL2_BOOT, 0x33ff        // RES1 bits, FP/SIMD not trapped
+.equ SCTLR_EL1_BOOT, 0x30d00800   // RES1 bits, MMU and caches off
+.equ SCTLR_EL2_BOOT, 0x30c50830   // likewise for EL2
+.equ SPSR_EL1H, 0x3c5             // EL1 with SP_EL1, DAIF masked
+.equ SPSR_EL2H, 0x3c9             // EL2 with SP_EL2, DAIF masked
+
+// arm64 Image header flags: little endian, 4KiB pages, placed at a 2MiB
+// aligned base near the start of RAM
+.equ IMAGE_FLAGS, (0 << 0) | (1 << 1) | (0 << 3)
+
+// FPEN: FP/SIMD trapped at EL1 and EL0. The kernel is built softfloat
+// (Rust and C alike) and never saves the registers, so a stray FP
+// instruction faults instead of corrupting another thread's state.
+.equ CPACR_EL1_BOOT, (0b00 << 20)
+
+// BootInfo fields filled in here (src/boot_info.rs)
+.equ BOOT_INFO_DTB, 0
+.equ BOOT_INFO_BOOT_EL, 8
+.equ BOOT_INFO_LOAD_ADDR, 16
+
+.equ BOOT_MAIR, 0xff00            // attr0: device nGnRnE, attr1: normal Write-Back
+.equ BOOT_TCR, (16 << 0) | (0b01 << 8) | (0b01 << 10) | (0b11 << 12) | (16 << 16) | (0b01 << 24) | (0b01 << 26) | (0b11 << 28) | (0b10 << 30)
+
+// EL1 state needed before any C or Rust code: FP/SIMD trapping and the early
+// exception vectors, at whatever address the code runs at right now
+.macro SET_BOOT_EL1_STATE
+	ldr x0, =CPACR_EL1_BOOT
+	msr cpacr_el1, x0
+	adr x0, early_vectors
+	msr vbar_el1, x0
+	isb
+.endm
+
+// Memory attributes and translation control for the boot tables
+.macro SET_BOOT_TRANSLATION
+	ldr x0, =BOOT_MAIR
+	msr mair_el1, x0
+	ldr x0, =BOOT_TCR
+	mrs x1, id_aa64mmfr0_el1
+	and x1, x1, #0b111
+	orr x0, x0, x1, lsl #32   // IPS
+	msr tcr_el1, x0
+.endm
+
+// Copy x2 bytes (a multiple of 16) from x0 up to x1, lowest address first
+.macro COPY_DOWN
+1:	ldp x6, x7, [x0], #16
+	stp x6, x7, [x1], #16
+	subs x2, x2, #16
+	b.hi 1b
+.endm
+
+// Linux arm64 boot protocol header (Documentation/arch/arm64/booting.rst),
+// first in the image. No PE/COFF header: UEFI cannot start the kernel itself.
+.section .head.text, "ax"
+_head:
+	b _Start             // code0
+	.long 0              // code1
+	.quad 0              // text_offset from a 2MiB aligned base
+	.quad _image_size    // memory used from the load address, BSS and boot stack included
+	.quad IMAGE_FLAGS
+	.quad 0, 0, 0        // reserved
+	.ascii "ARM\x64"     // magic
+	.long 0              // no PE header
+
+.text
+
+// Continue at EL1 (the return address, in EL1) whatever EL the loader
+// entered at, and return that EL in x0. From EL3 this goes through EL2 when
+// it is implemented, which is then set up to stay out of the way: EL1 gets
+// the physical timer and counter, the GICv3 system registers and the real
+// MIDR and MPIDR. Clobbers x0 and x1 only, so it runs before any stack.
+enter_el1:
+	mrs x1, CurrentEL
+	lsr x1, x1, #2
+	cmp x1, #2
+	b.eq 2f
+	b.lo 3f
+
+	// EL3: non-secure, lower ELs AArch64, then on to EL2 or straight to EL1
+	ldr x0, =SCR_EL3_BOOT
+	msr scr_el3, x0
+	ldr x0, =SCTLR_EL1_BOOT
+	msr sctlr_el1, x0
+	mrs x0, id_aa64pfr0_el1
+	ubfx x0, x0, #8, #4       // EL2 implemented
+	cbz x0, 1f
+	ldr x0, =SCTLR_EL2_BOOT
+	msr sctlr_el2, x0
+	mov x0, #SPSR_EL2H
+	msr spsr_el3, x0
+	adr x0, 2f
+	msr elr_el3, x0
+	eret
+1:	mov x0, #SPSR_EL1H
+	msr spsr_el3, x0
+	msr elr_el3, x30
+	mov x0, x1
+	eret
+
+2:	// EL2
+	ldr x0, =HCR_EL2_BOOT
+	msr hcr_el2, x0
+	ldr x0, =CPTR_EL2_BOOT
+	msr cptr_el2, x0
+	msr hstr_el2, xzr
+	msr vttbr_el2, xzr
+	mrs x0, cnthctl_el2
+	orr x0, x0, #0b11         // EL1PCEN | EL1PCTEN
+	msr cnthctl_el2, x0
+	msr cntvoff_el2, xzr
+	mrs x0, midr_el1
+	msr vpidr_el2, x0
+	mrs x0, mpidr_el1
+	msr vmpidr_el2, x0
+	mrs x0, id_aa64pfr0_el1
+	ubfx x0, x0, #24, #4      // GIC system registers implemented
+	cbz x0, 4f
+	mrs x0, icc_sre_el2
+	orr x0, x0, #0b1111       // Enable | DIB | DFB | SRE: EL1 may use ICC_SRE_EL1
+	msr icc_sre_el2, x0
+	isb
+4:	ldr x0, =SCTLR_EL1_BOOT
+	msr sctlr_el1, x0
+	mov x0, #SPSR_EL1H
+	msr spsr_el2, x0
+	msr elr_el2, x30
+	mov x0, x1
+	eret
+
+3:	mov x0, x1                // EL1 already
+	ret
+
+// Exceptions taken before kernel_main (or secondary_main) installs the real
+// vectors. There is no console yet: the CPU parks with ESR, ELR and FAR in
+// x0-x2 for a debugger to look at.
+.balign 2048
+early_vectors:
+	.rept 16
+	.balign 128
+	b early_exception
+	.endr
+early_exception:
+	mrs x0, esr_el1
+	mrs x1, elr_el1
+	mrs x2, far_el1
+1:	wfe
+	b 1b
+
 .global _Start
 _Start:
+	mov x19, x0          // DTB pointer handed over by the loader
+	bl enter_el1
+	mov x20, x0          // EL the loader entered at
+	adr x21, _head       // where the loader put the kernel
+	SET_BOOT_EL1_STATE
+
+	// Copy the kernel to its link address if it was loaded elsewhere, moving
+	// the DTB out of the way first if it is in the destination. The link
+	// address is fixed (KERNEL_LOAD_ADDR, see linker64.ld) and has to be RAM
+	// on this machine; memory::frame_allocator::init checks that against the
+	// device tree.
+	adr x3, _head
+	ldr x4, =KERNEL_LOAD_ADDR
+	cmp x3, x4
+	b.eq relocated
+	ldr x5, =_image_size
+	add x0, x4, x5
+	cmp x19, x4
+	b.lo 3f
+	cmp x19, x0
+	b.hs 3f
+	cmp x3, x4
+	csel x1, x3, x4, hi
+	add x1, x1, x5       // above both the old and the new kernel
+	add x1, x1, #7
+	bic x1, x1, #7
+	ldr w2, [x19, #4]    // totalsize (big endian)
+	rev w2, w2
+	add x2, x2, #7
+	bic x2, x2, #7
+	add x0, x19, x2      // moving up: copy from the end
+	mov x19, x1
+	add x1, x1, x2
+1:	ldr x6, [x0, #-8]!
+	str x6, [x1, #-8]!
+	subs x2, x2, #8
+	b.hi 1b
+
+3:	ldr x5, =_kernel_size
+	add x5, x5, #15
+	bic x5, x5, #15
+	cmp x4, x3
+	b.hi 5f
+
+	// Moving down, over this very code if the two copies overlap: copy the
+	// boot code up to the end of the second loop on its own first and copy
+	// the rest from there. Loaders place the image 2MiB aligned, so the two
+	// copies of the boot code never overlap and the second loop only writes
+	// the bytes it is running from again.
+	mov x0, x3
+	mov x1, x4
+	adr x2, 6f
+	sub x2, x2, x3
+	add x2, x2, #15
+	bic x2, x2, #15
+	COPY_DOWN
+	ic iallu
+	dsb sy
+	isb
+	adr x0, 4f
+	sub x0, x0, x3
+	add x0, x0, x4
+	br x0
+4:	mov x0, x3
+	mov x1, x4
+	mov x2, x5
+	COPY_DOWN
+	ic iallu
+	dsb sy
+	isb
+	b relocated
+6:
+5:	// Moving up: copy from the end, the start (this code) comes last
+	add x0, x3, x5
+	add x1, x4, x5
+	mov x2, x5
+1:	ldp x6, x7, [x0, #-16]!
+	stp x6, x7, [x1, #-16]!
+	subs x2, x2, #16
+	b.hi 1b
+	ic iallu
+	dsb sy
+	isb
+	adr x0, relocated      // continue in the copy
+	sub x0, x0, x3
+	add x0, x0, x4
+	br x0
+
+relocated:
+	SET_BOOT_EL1_STATE
+
+	// Image loaders only load the file: clear BSS (the boot CPU's job alone)
+	adrp x0, __bss_start
+	add x0, x0, :lo12:__bss_start
+	adrp x1, __bss_end
+	add x1, x1, :lo12:__bss_end
+1:	cmp x0, x1
+	b.hs 2f
+	stp xzr, xzr, [x0], #16
+	b 1b
+2:
+
+	// Boot tables: one L0 entry covering the first 512GiB, then 1GiB blocks.
+	// The same L0 table goes into TTBR0 (identity map, for the instructions
+	// right after the MMU turns on) and TTBR1 (PHYS_OFFSET + pa).
+	adrp x0, boot_l0
+	adrp x1, boot_l1
+	orr x2, x1, #0b11
+	str x2, [x0]
+
+	ldr x2, =BOOT_BLOCK_DEVICE     // 0-1GiB: device windows
+	str x2, [x1]
+	ldr x2, =(BOOT_BLOCK_NORMAL | (1 << 30))
+	mov x3, #1
+	mov x4, #(1 << 30)
+1:	str x2, [x1, x3, lsl #3]
+	add x2, x2, x4
+	add x3, x3, #1
+	cmp x3, #BOOT_RAM_END_GIB
+	b.lo 1b
+	dsb sy
+
+	SET_BOOT_TRANSLATION
+	adrp x0, boot_l0
+	msr ttbr0_el1, x0
+	msr ttbr1_el1, x0
+	isb
+	tlbi vmalle1
+	dsb nsh
+	isb
+
+	// MMU only: the caches are turned on by memory::paging::init
+	mrs x0, sctlr_el1
+	orr x0, x0, #1
+	msr sctlr_el1, x0
+	isb
+
+	ldr x0, =higher_half
+	br x0
+
+higher_half:
+	ldr x0, =early_vectors
+	msr vbar_el1, x0
+	isb
 	ldr x0, =stack_top
 	mov sp, x0
+	cbz x19, 2f
+	ldr x0, =PHYS_OFFSET
+	add x19, x19, x0     // reach the DTB through the direct map
+2:	ldr x0, =BOOT_INFO
+	str x19, [x0, #BOOT_INFO_DTB]
+	str x20, [x0, #BOOT_INFO_BOOT_EL]
+	str x21, [x0, #BOOT_INFO_LOAD_ADDR]
 	mov x29, xzr
 	mov x30, xzr
 	bl kernel_main
 	b .
+
+// Secondary CPUs enter here from PSCI CPU_ON, MMU off, with their CPU number
+// in x0, at the EL the boot CPU was entered at. SECONDARY_BOOT (see
+// src/smp.rs) holds the kernel tables and the stack to start on; the boot
+// tables only provide the identity map in TTBR0.
+.global _secondary_start
+_secondary_start:
+	mov x19, x0
+	bl enter_el1
+	SET_BOOT_EL1_STATE
+	adrp x0, SECONDARY_BOOT
+	add x0, x0, :lo12:SECONDARY_BOOT
+	ldp x20, x21, [x0]        // kernel TTBR1, stack top
+
+	SET_BOOT_TRANSLATION
+	adrp x0, boot_l0
+	msr ttbr0_el1, x0
+	msr ttbr1_el1, x20
+	isb
+	tlbi vmalle1
+	dsb nsh
+	isb
+
+	// Caches on right away, the other CPUs already run with them
+	mrs x0, sctlr_el1
+	orr x0, x0, #1
+	orr x0, x0, #(1 << 2)
+	orr x0, x0, #(1 << 12)
+	msr sctlr_el1, x0
+	isb
+
+	ldr x0, =secondary_higher_half
+	br x0
+
+secondary_higher_half:
+	ldr x0, =early_vectors
+	msr vbar_el1, x0
+	isb
+	mov sp, x21
+	mov x29, xzr
+	mov x30, xzr
+	mov x0, x19
+	bl secondary_main
+	b .
+
+.section .data
+.balign 4096
+boot_l0:
+	.space 4096
+boot_l1:
+	.space 4096
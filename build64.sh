@@ -22,7 +22,7 @@ echo "Assembling boot.s..."
 aarch64-elf-as -g boot64.s -o boot.o
 
 echo "Building Rust kernel..."
-cargo build --target aarch64-unknown-none --release
+cargo build --target aarch64-unknown-none-softfloat --release
 
 echo "Generating C bindings..."
 cbindgen --config cbindgen.toml --crate mvos_arm --lang c --output src/include/mvos_bindings.h
@@ -31,7 +31,7 @@ echo "Building C parts..."
 make 
 
 echo "Linking kernel..."
-aarch64-elf-ld -T linker64.ld boot.o --whole-archive build/libckernel.a --whole-archive target/aarch64-unknown-none/release/libmvos_arm.a -o kernel64.elf
+aarch64-elf-ld -T linker64.ld boot.o --whole-archive build/libckernel.a --whole-archive target/aarch64-unknown-none-softfloat/release/libmvos_arm.a -o kernel64.elf
 
 # echo "Creating binary..."
 # aarch64-elf-objcopy -O binary kernel.elf kernel.bin
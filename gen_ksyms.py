@@ -0,0 +1,54 @@
+#!/usr/bin/env python3
+"""Build the kernel symbol table embedded in the .ksyms section.
+
+Reads `nm -n -C --defined-only` output for the kernel ELF on stdin and writes
+the table to the file given as the only argument. Layout (little endian):
+
+    u32 magic "KSYM", u32 count
+    count x { u64 address, u32 name offset, u32 name length }, sorted by address
+    names, not NUL terminated
+"""
+
+import struct
+import sys
+
+MAGIC = 0x4D59534B  # "KSYM"
+KERNEL_BASE = 0xFFFF000000000000
+TEXT_TYPES = "tTwW"
+
+
+def read_symbols(lines):
+    symbols = {}
+    for line in lines:
+        parts = line.rstrip("\n").split(" ", 2)
+        if len(parts) != 3:
+            continue
+        address, kind, name = parts
+        address = int(address, 16)
+        # Mapping symbols ($x, $d) and local labels carry no function name
+        if kind not in TEXT_TYPES or address < KERNEL_BASE or name.startswith(("$", ".L")):
+            continue
+        symbols.setdefault(address, name)
+    return sorted(symbols.items())
+
+
+def main():
+    if len(sys.argv) != 2:
+        sys.exit("usage: nm -n -C --defined-only kernel.elf | gen_ksyms.py <output>")
+
+    symbols = read_symbols(sys.stdin)
+    names = bytearray()
+    entries = bytearray()
+    for address, name in symbols:
+        encoded = name.encode("utf-8")
+        entries += struct.pack("<QII", address, len(names), len(encoded))
+        names += encoded
+
+    with open(sys.argv[1], "wb") as out:
+        out.write(struct.pack("<II", MAGIC, len(symbols)))
+        out.write(entries)
+        out.write(names)
+
+
+if __name__ == "__main__":
+    main()
@@ -0,0 +1,24 @@
+//! Host-side unit tests for kernel code that does not touch the hardware.
+//!
+//! The kernel crate only builds for `aarch64-unknown-none`, so the modules
+//! under test are compiled in here through `#[path]`, next to stand-ins for
+//! the few kernel items they use. Run with `make test`.
+
+/// Wrapper for spin::Mutex to permit trait impl
+pub struct Locked<A> {
+    inner: spin::Mutex<A>,
+}
+
+impl<A> Locked<A> {
+    pub fn lock(&self) -> spin::MutexGuard<'_, A> {
+        self.inner.lock()
+    }
+}
+
+/// Align the given address upwards to given alignment
+pub fn align_up(addr: usize, align: usize) -> usize {
+    addr.next_multiple_of(align)
+}
+
+#[path = "../src/memory/allocator/free_list.rs"]
+pub mod free_list;
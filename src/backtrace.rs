@@ -0,0 +1,166 @@
+//! Frame pointer backtraces.
+//!
+//! Every function starts its frame with a record holding the caller's x29 and
+//! x30 (the code is built with frame pointers forced on), so following x29
+//! from the current frame visits each caller in turn. `boot64.s` enters
+//! `kernel_main` with x29 = 0, which ends the chain.
+//!
+//! Return addresses are symbolised against the table the build embeds in the
+//! `.ksyms` section (see `gen_ksyms.py`). A kernel linked without it still
+//! gets raw addresses.
+//!
+//! A panic raised while reporting an exception is traced from the exception
+//! instead (see [`set_exception_frame`]): the handler's own chain starts in
+//! the exception and panic code and can miss the function that faulted.
+
+use core::{arch::asm, fmt, iter, sync::atomic::{AtomicU64, Ordering}};
+
+use crate::{GPU_DEVICE, SCALE, SCREENWIDTH, THEME, console_println, memory::layout::is_kernel_address, serial_println};
+
+/// Most frames printed for one backtrace
+const MAX_FRAMES: usize = 32;
+/// "KSYM"
+const KSYMS_MAGIC: u32 = 0x4d59_534b;
+
+/// ELR and x29 of the exception being reported, ELR 0 if there is none
+static EXCEPTION_PC: AtomicU64 = AtomicU64::new(0);
+static EXCEPTION_FP: AtomicU64 = AtomicU64::new(0);
+
+unsafe extern "C" {
+    static ksyms_start: u8;
+    static ksyms_end: u8;
+    static kernel_start: u8;
+    static kernel_end: u8;
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct KsymEntry {
+    address: u64,
+    name_offset: u32,
+    name_len: u32,
+}
+
+/// The embedded symbol table: entries sorted by address and the name blob.
+fn symbol_table() -> Option<(&'static [KsymEntry], &'static [u8])> {
+    unsafe {
+        let start = &raw const ksyms_start;
+        let size = (&raw const ksyms_end as usize).checked_sub(start as usize)?;
+        if size < 8 || (start as *const u32).read() != KSYMS_MAGIC {
+            return None;
+        }
+
+        let count = (start as *const u32).add(1).read() as usize;
+        let entries_size = count * size_of::<KsymEntry>();
+        if 8 + entries_size > size {
+            return None;
+        }
+        let entries = core::slice::from_raw_parts(start.add(8) as *const KsymEntry, count);
+        let names = core::slice::from_raw_parts(start.add(8 + entries_size), size - 8 - entries_size);
+        Some((entries, names))
+    }
+}
+
+/// Function containing `addr` and the offset into it.
+pub fn symbolize(addr: u64) -> Option<(&'static str, u64)> {
+    let in_image = (&raw const kernel_start as u64..&raw const kernel_end as u64).contains(&addr);
+    let (entries, names) = symbol_table().filter(|_| in_image)?;
+
+    let index = entries.partition_point(|e| e.address <= addr).checked_sub(1)?;
+    let entry = entries[index];
+    let name = names.get(entry.name_offset as usize..(entry.name_offset + entry.name_len) as usize)?;
+    Some((core::str::from_utf8(name).unwrap_or("<invalid name>"), addr - entry.address))
+}
+
+/// Displays an address as `function+offset`, or `??` if it is unknown.
+pub struct Symbol(pub u64);
+
+impl fmt::Display for Symbol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match symbolize(self.0) {
+            Some((name, offset)) => write!(f, "{}+{:#x}", name, offset),
+            None => write!(f, "??"),
+        }
+    }
+}
+
+/// Whether `addr` can be read without faulting (asks the MMU, takes no locks).
+fn readable(addr: u64) -> bool {
+    let par: u64;
+    unsafe { asm!("at s1e1r, {}", "isb", "mrs {}, par_el1", in(reg) addr, out(reg) par); }
+    par & 1 == 0
+}
+
+/// Return addresses found by walking the frame records from `fp`.
+pub struct Frames {
+    fp: u64,
+    left: usize,
+}
+
+impl Iterator for Frames {
+    type Item = u64;
+
+    fn next(&mut self) -> Option<u64> {
+        let fp = self.fp;
+        if self.left == 0 || fp == 0 || !fp.is_multiple_of(8) || !is_kernel_address(fp) || !readable(fp) || !readable(fp + 8) {
+            return None;
+        }
+        self.left -= 1;
+
+        let (next_fp, lr) = unsafe { ((fp as *const u64).read(), (fp as *const u64).add(1).read()) };
+        // Callers' frames are higher up the stack: anything else is a broken chain
+        self.fp = if next_fp > fp { next_fp } else { 0 };
+        // x30 points after the call, report the call itself
+        (lr != 0).then(|| lr - 4)
+    }
+}
+
+pub fn frames_from(fp: u64) -> Frames {
+    Frames { fp, left: MAX_FRAMES }
+}
+
+/// Frame pointer of the caller
+#[inline(always)]
+pub fn current_fp() -> u64 {
+    let fp: u64;
+    unsafe { asm!("mov {}, x29", out(reg) fp); }
+    fp
+}
+
+/// Start the next backtrace at an exception taken at `pc` with frame pointer
+/// `fp`. Called by the exception handlers before they report a fatal one.
+pub fn set_exception_frame(pc: u64, fp: u64) {
+    EXCEPTION_FP.store(fp, Ordering::Relaxed);
+    EXCEPTION_PC.store(pc, Ordering::Release);
+}
+
+/// Print the call chain of a panic: from the exception being reported if
+/// there is one, else from frame `fp`.
+pub fn print_panic_backtrace(fp: u64) {
+    match EXCEPTION_PC.swap(0, Ordering::Acquire) {
+        0 => print_backtrace(fp),
+        pc => print_frames(iter::once(pc).chain(frames_from(EXCEPTION_FP.load(Ordering::Relaxed)))),
+    }
+}
+
+/// Print the call chain starting at frame `fp` to the UART and, once a GPU is
+/// up, to the console.
+pub fn print_backtrace(fp: u64) {
+    print_frames(frames_from(fp));
+}
+
+fn print_frames(frames: impl Iterator<Item = u64>) {
+    let console = unsafe { GPU_DEVICE }.is_some();
+    let theme = unsafe { THEME };
+
+    serial_println!("[ BACKTRACE ] Call trace:");
+    if console {
+        console_println!("[ BACKTRACE ] Call trace:" ; color: theme.error());
+    }
+    for (i, pc) in frames.enumerate() {
+        serial_println!("[ BACKTRACE ]   #{:<2} {:#018x} {}", i, pc, Symbol(pc));
+        if console {
+            console_println!("[ BACKTRACE ]   #{:<2} {:#018x} {}", i, pc, Symbol(pc) ; color: theme.error());
+        }
+    }
+}
@@ -0,0 +1,72 @@
+//! What the boot code hands `kernel_main`.
+//!
+//! `boot64.s` fills in the fields it knows (DTB pointer, entry EL, load
+//! address; keep their offsets in sync with the `BOOT_INFO_*` constants
+//! there) and passes [`BOOT_INFO`] to `kernel_main`. The memory map and the
+//! command line come from the device tree, read in place by
+//! [`BootInfo::load_dtb`] before the heap exists, so both are fixed-size.
+
+use crate::drivers::dtb_parser::{DeviceTreeParser, DtRegion, MemoryKind};
+
+/// Most RAM banks kept in the memory map
+pub const MAX_MEMORY_REGIONS: usize = 16;
+/// Longest command line kept, longer ones are cut short
+pub const CMDLINE_SIZE: usize = 512;
+
+#[repr(C)]
+pub struct BootInfo {
+    /// Device tree blob (direct map address), null if the loader passed none
+    pub dtb: *const u8,
+    /// Exception level the loader entered the kernel at
+    pub boot_el: u64,
+    /// Physical address the loader put the kernel at. The kernel runs at its
+    /// link address, it moved itself there if the two differ.
+    pub load_addr: u64,
+    memory: [DtRegion; MAX_MEMORY_REGIONS],
+    memory_regions: usize,
+    cmdline: [u8; CMDLINE_SIZE],
+    cmdline_len: usize,
+}
+
+#[unsafe(no_mangle)]
+pub static mut BOOT_INFO: BootInfo = BootInfo {
+    dtb: core::ptr::null(),
+    boot_el: 0,
+    load_addr: 0,
+    memory: [DtRegion { address: 0, size: 0 }; MAX_MEMORY_REGIONS],
+    memory_regions: 0,
+    cmdline: [0; CMDLINE_SIZE],
+    cmdline_len: 0,
+};
+
+impl BootInfo {
+    /// Take the RAM banks and `/chosen/bootargs` from the device tree
+    pub fn load_dtb(&mut self, dtb: &DeviceTreeParser) {
+        self.memory_regions = 0;
+        dtb.scan_memory(|kind, region| {
+            if kind == MemoryKind::Ram && self.memory_regions < MAX_MEMORY_REGIONS {
+                self.memory[self.memory_regions] = region;
+                self.memory_regions += 1;
+            }
+        });
+
+        let bootargs = dtb.bootargs().unwrap_or("");
+        // Cut on a character boundary
+        let mut len = bootargs.len().min(CMDLINE_SIZE);
+        while !bootargs.is_char_boundary(len) {
+            len -= 1;
+        }
+        self.cmdline[..len].copy_from_slice(&bootargs.as_bytes()[..len]);
+        self.cmdline_len = len;
+    }
+
+    /// RAM banks, empty without a device tree
+    pub fn memory(&self) -> &[DtRegion] {
+        &self.memory[..self.memory_regions]
+    }
+
+    /// Kernel command line, empty if the loader gave none
+    pub fn cmdline(&self) -> &str {
+        core::str::from_utf8(&self.cmdline[..self.cmdline_len]).unwrap_or("")
+    }
+}
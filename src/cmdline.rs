@@ -0,0 +1,107 @@
+//! Kernel command line (`/chosen/bootargs`, filled in by QEMU's `-append`).
+//!
+//! Options are whitespace separated `key=value` pairs or bare `key` flags;
+//! a value may be double-quoted to hold spaces. They are parsed once at boot
+//! into a fixed table, before the heap exists. Subsystems look up their own
+//! options with [`get`], or [`get_as`] for a typed value, so unknown keys
+//! are simply kept. A key given twice takes the last value.
+//!
+//! Options in use:
+//!
+//! ```text
+//! mvos.theme=default|templeos     console color scheme
+//! mvos.panic=hang|reboot|poweroff what to do after a panic
+//! gpu=ramfb|virtio                display device
+//! fb=<width>x<height>             framebuffer resolution
+//! loglevel=<level>                least level logged (see `log`)
+//! log.<TAG>=<level>               least level logged for one tag
+//! ```
+
+use core::str::FromStr;
+
+use spin::Mutex;
+
+use crate::{klog, log::Level};
+
+/// Most distinct options kept
+const MAX_OPTIONS: usize = 32;
+
+struct Options {
+    entries: [(&'static str, &'static str); MAX_OPTIONS],
+    len: usize,
+}
+
+static OPTIONS: Mutex<Options> = Mutex::new(Options { entries: [("", ""); MAX_OPTIONS], len: 0 });
+
+/// Split `cmdline` into its arguments: runs of non-whitespace, where quoted
+/// whitespace does not count
+fn split(cmdline: &str) -> impl Iterator<Item = &str> {
+    let mut rest = cmdline.trim_start();
+    core::iter::from_fn(move || {
+        if rest.is_empty() {
+            return None;
+        }
+        let mut quoted = false;
+        let end = rest.char_indices()
+            .find(|&(_, c)| {
+                if c == '"' {
+                    quoted = !quoted;
+                }
+                !quoted && c.is_whitespace()
+            })
+            .map_or(rest.len(), |(i, _)| i);
+        let (arg, tail) = rest.split_at(end);
+        rest = tail.trim_start();
+        Some(arg)
+    })
+}
+
+/// Parse the command line. Called once, right after the device tree is found.
+pub fn init(cmdline: &'static str) {
+    let mut options = OPTIONS.lock();
+    for arg in split(cmdline) {
+        let (key, value) = arg.split_once('=').unwrap_or((arg, ""));
+        let value = value.strip_prefix('"').and_then(|v| v.strip_suffix('"')).unwrap_or(value);
+
+        let len = options.len;
+        if let Some(entry) = options.entries[..len].iter_mut().find(|(k, _)| *k == key) {
+            entry.1 = value;
+        } else if len < MAX_OPTIONS {
+            options.entries[len] = (key, value);
+            options.len += 1;
+        } else {
+            klog!(Level::Warn, "CMDLINE", "Too many options, ignoring {}", arg);
+        }
+    }
+}
+
+/// Value of option `key`: `Some("")` for a bare flag, `None` if not given
+pub fn get(key: &str) -> Option<&'static str> {
+    let options = OPTIONS.lock();
+    options.entries[..options.len].iter().find(|(k, _)| *k == key).map(|(_, v)| *v)
+}
+
+/// Call `f` with the key (`prefix` left out) and value of every option whose
+/// key starts with `prefix`
+pub fn with_prefix(prefix: &str, mut f: impl FnMut(&'static str, &'static str)) {
+    let options = OPTIONS.lock();
+    for &(key, value) in &options.entries[..options.len] {
+        if let Some(key) = key.strip_prefix(prefix) {
+            f(key, value);
+        }
+    }
+}
+
+/// Whether option `key` was given at all
+pub fn is_set(key: &str) -> bool {
+    get(key).is_some()
+}
+
+/// Value of option `key` parsed as a `T`. A value that does not parse is
+/// reported and treated as not given.
+pub fn get_as<T: FromStr>(key: &str) -> Option<T> {
+    let value = get(key)?;
+    value.parse()
+        .inspect_err(|_| klog!(Level::Warn, "CMDLINE", "Ignoring {}={}: bad value", key, value))
+        .ok()
+}
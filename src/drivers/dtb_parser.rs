@@ -1,6 +1,17 @@
+//! Flattened device tree (FDT) parser.
+//!
+//! `DeviceTreeParser` is a thin view over the raw blob handed to `kernel_main`
+//! by the loader. `DeviceTreeParser::parse` walks the structure block once and
+//! builds an owned `DeviceTree` whose nodes already carry their `reg` entries
+//! translated to CPU physical addresses (through every parent `ranges`).
+
 use core::mem;
 
-use crate::serial_println;
+use alloc::vec::Vec;
+
+use crate::{memory::layout::virt_to_phys, klog, log::Level};
+
+pub const FDT_MAGIC: u32 = 0xd00dfeed;
 
 pub const FDT_BEGIN_NODE: u32 = 0x1;
 pub const FDT_END_NODE: u32 = 0x2;
@@ -8,6 +19,13 @@ pub const FDT_PROP: u32 = 0x3;
 pub const FDT_NOP: u32 = 0x4;
 pub const FDT_END: u32 = 0x9;
 
+/// Default cell counts the spec mandates when a parent does not specify them.
+const DEFAULT_ADDRESS_CELLS: u32 = 2;
+const DEFAULT_SIZE_CELLS: u32 = 1;
+
+/// The parsed device tree, built once at boot.
+pub static mut DEVICE_TREE: Option<DeviceTree> = None;
+
 #[repr(C)]
 struct FdtHeader {
     magic: u32, //0xd00dfeed (BE)
@@ -30,12 +48,21 @@ pub struct DeviceTreeParser {
 }
 
 impl DeviceTreeParser {
-    pub fn new(dtb_ptr: *const u8) -> Result<Self, &'static str> {
+    /// Check the header of the blob at `dtb_ptr`.
+    ///
+    /// # Safety
+    /// A non-null `dtb_ptr` must point to readable memory holding at least a
+    /// blob header, and to the whole blob if the magic matches.
+    pub unsafe fn new(dtb_ptr: *const u8) -> Result<Self, &'static str> {
+        if dtb_ptr.is_null() || !(dtb_ptr as usize).is_multiple_of(8) {
+            return Err("[DEVICE TREE] \x1B[1;31mERROR: Invalid DTB pointer\x1B[0m");
+        }
+
         unsafe {
             let header = &*(dtb_ptr as *const FdtHeader);
 
             //Verify magic number (convert from BE)
-            if u32::from_be(header.magic) != 0xd00dfeed {
+            if u32::from_be(header.magic) != FDT_MAGIC {
                 return Err("[DEVICE TREE] \x1B[1;31mERROR: Invalid DTB magic\x1B[0m");
             }
 
@@ -46,6 +73,37 @@ impl DeviceTreeParser {
         }
     }
 
+    /// Address of the blob in memory.
+    pub fn base(&self) -> *const u8 {
+        self.dtb_base
+    }
+
+    /// Total size of the blob in bytes, as reported by its header.
+    pub fn total_size(&self) -> usize {
+        u32::from_be(self.header.totalsize) as usize
+    }
+
+    /// Physical id of the CPU the loader booted us on.
+    pub fn boot_cpuid(&self) -> u32 {
+        u32::from_be(self.header.boot_cpuid_phys)
+    }
+
+    /// Entries of the memory reservation block (address, size).
+    pub fn mem_reservations(&self) -> Vec<DtRegion> {
+        self.reservation_entries().collect()
+    }
+
+    fn reservation_entries(&self) -> impl Iterator<Item = DtRegion> {
+        let mut ptr = unsafe { self.dtb_base.add(u32::from_be(self.header.off_mem_rsvmap) as usize) as *const u64 };
+        core::iter::from_fn(move || unsafe {
+            let address = u64::from_be(ptr.read_unaligned());
+            let size = u64::from_be(ptr.add(1).read_unaligned());
+            if address == 0 && size == 0 { return None; }
+            ptr = ptr.add(2);
+            Some(DtRegion { address, size })
+        })
+    }
+
     pub fn get_string(&self, offset: u32) -> &'static str {
         unsafe {
             let str_ptr = self.strings_block.add(offset as usize);
@@ -57,102 +115,264 @@ impl DeviceTreeParser {
     }
 
     pub fn debug_dtb(&self) {
-        unsafe {
-            serial_println!("[DEVICE TREE] \x1B[0;33mHeader validation:\x1B[0m");
-            serial_println!("[DEVICE TREE] \x1B[0;33m  Magic: 0x{:x} (expected: 0xd00dfeed)\x1B[0m", u32::from_be(self.header.magic));
-            serial_println!("[DEVICE TREE] \x1B[0;33m  Total size: {}\x1B[0m", u32::from_be(self.header.totalsize));
-            serial_println!("[DEVICE TREE] \x1B[0;33m  Structure offset: 0x{:x}\x1B[0m", u32::from_be(self.header.off_dt_struct));
-            serial_println!("[DEVICE TREE] \x1B[0;33m  Strings offset: 0x{:x}\x1B[0m", u32::from_be(self.header.off_dt_strings));
-            serial_println!("[DEVICE TREE] \x1B[0;33m  Version: {}\x1B[0m", u32::from_be(self.header.version));
-        }
+        klog!(Level::Debug, "DTB", "Header validation:");
+        klog!(Level::Debug, "DTB", "  Magic: 0x{:x} (expected: 0xd00dfeed)", u32::from_be(self.header.magic));
+        klog!(Level::Debug, "DTB", "  Total size: {}", u32::from_be(self.header.totalsize));
+        klog!(Level::Debug, "DTB", "  Structure offset: 0x{:x}", u32::from_be(self.header.off_dt_struct));
+        klog!(Level::Debug, "DTB", "  Strings offset: 0x{:x}", u32::from_be(self.header.off_dt_strings));
+        klog!(Level::Debug, "DTB", "  Version: {}", u32::from_be(self.header.version));
     }
 }
 
 impl DeviceTreeParser {
-    pub fn find_ramfb(&self) -> Option<(u64, u64)> {
+    /// Walk the structure block and build the full node tree.
+    pub fn parse(&self) -> Result<DeviceTree, &'static str> {
+        unsafe {
+            let mut ptr = self.struct_block;
+
+            // Skip any leading NOPs before the root node
+            while u32::from_be(*ptr) == FDT_NOP { ptr = ptr.add(1); }
+
+            if u32::from_be(*ptr) != FDT_BEGIN_NODE {
+                return Err("[DEVICE TREE] \x1B[1;31mERROR: Structure block does not start with a node\x1B[0m");
+            }
+            ptr = ptr.add(1);
+
+            let root_ctx = BusContext {
+                address_cells: DEFAULT_ADDRESS_CELLS,
+                size_cells: DEFAULT_SIZE_CELLS,
+                translation: Translation::Identity,
+                interrupt_parent: None,
+            };
+
+            let root = self.parse_node(&mut ptr, &root_ctx)?;
+
+            Ok(DeviceTree { root, reservations: self.mem_reservations(), blob: DtRegion { address: virt_to_phys(self.dtb_base as u64), size: self.total_size() as u64 } })
+        }
+    }
+
+    /// Parse one node whose `FDT_BEGIN_NODE` token has already been consumed.
+    ///
+    /// `ctx` describes the bus the node sits on (its parent's cells and translation).
+    unsafe fn parse_node(&self, ptr: &mut *const u32, ctx: &BusContext) -> Result<DtNode, &'static str> {
+        unsafe {
+            let name = self.read_string_at(*ptr as *const u8);
+            *ptr = self.align_ptr((*ptr as *const u8).add(name.len() + 1)) as *const u32;
+
+            let mut node = DtNode {
+                name,
+                properties: Vec::new(),
+                children: Vec::new(),
+                regions: Vec::new(),
+                interrupt_parent: ctx.interrupt_parent,
+            };
+
+            // Properties always precede subnodes
+            loop {
+                match u32::from_be(**ptr) {
+                    FDT_PROP => {
+                        let len = u32::from_be(*ptr.add(1)) as usize;
+                        let nameoff = u32::from_be(*ptr.add(2));
+                        let data = ptr.add(3) as *const u8;
+
+                        node.properties.push(DtProperty {
+                            name: self.get_string(nameoff),
+                            value: core::slice::from_raw_parts(data, len),
+                        });
+
+                        *ptr = self.align_ptr(data.add(len)) as *const u32;
+                    }
+                    FDT_NOP => { *ptr = ptr.add(1); }
+                    _ => break,
+                }
+            }
+
+            if let Some(phandle) = node.prop_u32("interrupt-parent") {
+                node.interrupt_parent = Some(phandle);
+            }
+
+            if let Some(reg) = node.property("reg") {
+                node.regions = decode_regions(reg, ctx.address_cells, ctx.size_cells)
+                    .into_iter()
+                    .map(|r| DtRegion { address: ctx.translation.translate(r.address).unwrap_or(r.address), size: r.size })
+                    .collect();
+            }
+
+            // Context for our own children
+            let child_ctx = BusContext {
+                address_cells: node.prop_u32("#address-cells").unwrap_or(DEFAULT_ADDRESS_CELLS),
+                size_cells: node.prop_u32("#size-cells").unwrap_or(DEFAULT_SIZE_CELLS),
+                translation: match node.property("ranges") {
+                    None => Translation::None,
+                    Some([]) => ctx.translation.clone(),
+                    Some(ranges) => Translation::Ranges(self.compose_ranges(&node, ranges, ctx)),
+                },
+                interrupt_parent: node.interrupt_parent,
+            };
+
+            loop {
+                let token = u32::from_be(**ptr);
+                *ptr = ptr.add(1);
+
+                match token {
+                    FDT_BEGIN_NODE => node.children.push(self.parse_node(ptr, &child_ctx)?),
+                    FDT_END_NODE => return Ok(node),
+                    FDT_NOP => {}
+                    FDT_END => return Err("[DEVICE TREE] \x1B[1;31mERROR: Unexpected FDT_END inside a node\x1B[0m"),
+                    _ => return Err("[DEVICE TREE] \x1B[1;31mERROR: Invalid structure block token\x1B[0m"),
+                }
+            }
+        }
+    }
+
+    /// Report every RAM bank and reserved range without touching the heap, so
+    /// physical memory can be set up before the tree is parsed.
+    ///
+    /// Only top-level `device_type = "memory"` nodes, the children of
+    /// `/reserved-memory` and the reservation block are considered; their
+    /// addresses are taken as CPU addresses without `ranges` translation.
+    pub fn scan_memory(&self, mut f: impl FnMut(MemoryKind, DtRegion)) {
+        for region in self.reservation_entries() {
+            f(MemoryKind::Reserved, region);
+        }
+
         unsafe {
             let mut ptr = self.struct_block;
-            let mut in_ramfb_node = false;
-            
+            let mut depth = 0;
+            let mut root_cells = (DEFAULT_ADDRESS_CELLS, DEFAULT_SIZE_CELLS);
+            let mut reserved_cells = (DEFAULT_ADDRESS_CELLS, DEFAULT_SIZE_CELLS);
+            // Top-level node currently being walked
+            let mut top_name = "";
+            let mut top_reg: &[u8] = &[];
+            let mut top_is_memory = false;
+
             loop {
                 let token = u32::from_be(*ptr);
                 ptr = ptr.add(1);
-                
+
                 match token {
                     FDT_BEGIN_NODE => {
-                        // Read node name
-                        let name_ptr = ptr as *const u8;
-                        let name = self.read_string_at(name_ptr);
-                        
-                        // Skip to next 4-byte boundary
-                        ptr = self.align_ptr(ptr as *const u8) as *const u32;
-                        
-                        // Check if this is a RAMFB node (name often starts with address)
-                        in_ramfb_node = false; // Will be set by compatible property
+                        let name = self.read_string_at(ptr as *const u8);
+                        ptr = self.align_ptr((ptr as *const u8).add(name.len() + 1)) as *const u32;
+                        depth += 1;
+                        if depth == 2 {
+                            top_name = name.split('@').next().unwrap_or(name);
+                            top_reg = &[];
+                            top_is_memory = false;
+                        }
+                    }
+                    FDT_END_NODE => {
+                        if depth == 2 && top_is_memory {
+                            regions_in(top_reg, root_cells.0, root_cells.1)
+                                .filter(|r| r.size != 0)
+                                .for_each(|r| f(MemoryKind::Ram, r));
+                        }
+                        depth -= 1;
+                        if depth == 0 { break; }
                     }
-                    
                     FDT_PROP => {
-                        let len = u32::from_be(*ptr);
-                        ptr = ptr.add(1);
-                        let nameoff = u32::from_be(*ptr);
-                        ptr = ptr.add(1);
-                        
-                        let prop_name = self.get_string(nameoff);
-                        let prop_data = ptr as *const u8;
-                        
-                        if prop_name == "compatible" {
-                            let compat_str = self.read_string_at(prop_data);
-                            if compat_str == "qemu,fw-cfg-mmio" {
-                                in_ramfb_node = true;
+                        let len = u32::from_be(*ptr) as usize;
+                        let name = self.get_string(u32::from_be(*ptr.add(1)));
+                        let value = core::slice::from_raw_parts(ptr.add(2) as *const u8, len);
+                        ptr = self.align_ptr((ptr.add(2) as *const u8).add(len)) as *const u32;
+
+                        let cell = || value.first_chunk::<4>().map(|c| u32::from_be_bytes(*c));
+                        let in_reserved = top_name == "reserved-memory";
+                        match (depth, name) {
+                            (1, "#address-cells") => root_cells.0 = cell().unwrap_or(DEFAULT_ADDRESS_CELLS),
+                            (1, "#size-cells") => root_cells.1 = cell().unwrap_or(DEFAULT_SIZE_CELLS),
+                            (2, "#address-cells") if in_reserved => reserved_cells.0 = cell().unwrap_or(DEFAULT_ADDRESS_CELLS),
+                            (2, "#size-cells") if in_reserved => reserved_cells.1 = cell().unwrap_or(DEFAULT_SIZE_CELLS),
+                            (2, "reg") => top_reg = value,
+                            (2, "device_type") => top_is_memory = value == b"memory\0",
+                            (3, "reg") if in_reserved => {
+                                regions_in(value, reserved_cells.0, reserved_cells.1).for_each(|r| f(MemoryKind::Reserved, r));
                             }
+                            _ => {}
                         }
-                        
-                        if prop_name == "reg" && in_ramfb_node {
-                            // Parse reg property (address, size)
-                            let reg_data = prop_data as *const u32;
-                            let addr_high = u32::from_be(*reg_data);
-                            let addr_low = u32::from_be(*reg_data.add(1));
-                            let size_high = u32::from_be(*reg_data.add(2));
-                            let size_low = u32::from_be(*reg_data.add(3));
-                            
-                            let addr = ((addr_high as u64) << 32) | (addr_low as u64);
-                            let size = ((size_high as u64) << 32) | (size_low as u64);
-
-                            serial_println!("[DEVICE TREE] \x1B[0;33mReturning Some({:x}, {:x})\x1B[0m", addr, size);
-                            
-                            return Some((addr, size));
+                    }
+                    FDT_NOP => {}
+                    _ => break,
+                }
+            }
+        }
+    }
+
+    /// `/chosen/bootargs`, found without building the tree: the heap does not
+    /// exist yet when the boot command line is read.
+    pub fn bootargs(&self) -> Option<&'static str> {
+        unsafe {
+            let mut ptr = self.struct_block;
+            let mut depth = 0;
+            let mut in_chosen = false;
+
+            loop {
+                let token = u32::from_be(*ptr);
+                ptr = ptr.add(1);
+
+                match token {
+                    FDT_BEGIN_NODE => {
+                        let name = self.read_string_at(ptr as *const u8);
+                        ptr = self.align_ptr((ptr as *const u8).add(name.len() + 1)) as *const u32;
+                        depth += 1;
+                        if depth == 2 {
+                            in_chosen = name == "chosen";
                         }
-                        
-                        // Skip property data (align to 4 bytes)
-                        ptr = self.align_ptr(prop_data.add(len as usize)) as *const u32;
                     }
-                    
                     FDT_END_NODE => {
-                        in_ramfb_node = false;
+                        depth -= 1;
+                        if depth == 0 { break; }
+                    }
+                    FDT_PROP => {
+                        let len = u32::from_be(*ptr) as usize;
+                        let name = self.get_string(u32::from_be(*ptr.add(1)));
+                        let value = core::slice::from_raw_parts(ptr.add(2) as *const u8, len);
+                        ptr = self.align_ptr((ptr.add(2) as *const u8).add(len)) as *const u32;
+
+                        if depth == 2 && in_chosen && name == "bootargs" {
+                            return core::str::from_utf8(value.strip_suffix(&[0]).unwrap_or(value)).ok();
+                        }
                     }
-                    
-                    FDT_END => break,
-                    
-                    FDT_NOP => {} // Skip
-                    
-                    _ =>  { 
-                        serial_println!("[DEVICE TREE] \x1B[0;33mInvalid token; returning None\x1B[0m");
-                        return None 
-                    }, // Invalid token
+                    FDT_NOP => {}
+                    _ => break,
                 }
             }
         }
-        serial_println!("[DEVICE TREE -> FIND_RAMFB] \x1B[0;33mLoop break; returning None\x1B[0m");
         None
     }
-    
+
+    /// Decode a node's `ranges` and resolve the parent side of every entry
+    /// against `ctx`, so child bus addresses map straight to CPU addresses.
+    fn compose_ranges(&self, node: &DtNode, ranges: &[u8], ctx: &BusContext) -> Vec<DtRange> {
+        let child_cells = node.prop_u32("#address-cells").unwrap_or(DEFAULT_ADDRESS_CELLS) as usize;
+        let size_cells = node.prop_u32("#size-cells").unwrap_or(DEFAULT_SIZE_CELLS) as usize;
+        let parent_cells = ctx.address_cells as usize;
+
+        let cells = cells_of(ranges);
+        let entry_len = child_cells + parent_cells + size_cells;
+        if entry_len == 0 { return Vec::new(); }
+
+        cells.chunks_exact(entry_len).filter_map(|entry| {
+            // PCI child addresses are 3 cells wide; the upper cell holds flags.
+            // Only the low 64 bits form the bus address.
+            let child = read_cells(&entry[child_cells.saturating_sub(2)..child_cells]);
+            let parent = read_cells(&entry[child_cells..child_cells + parent_cells]);
+            let size = read_cells(&entry[child_cells + parent_cells..]);
+            ctx.translation.translate(parent).map(|cpu| DtRange { child, cpu, size })
+        }).collect()
+    }
+
     pub fn align_ptr(&self, ptr: *const u8) -> *const u8 {
         let addr = ptr as usize;
         let aligned = (addr + 3) & !3; // Align to 4 bytes
         aligned as *const u8
     }
-    
-    pub fn read_string_at(&self, ptr: *const u8) -> &'static str {
+
+    /// NUL-terminated string at `ptr`, which must point into the blob
+    ///
+    /// # Safety
+    /// `ptr` must point to a NUL-terminated string that lives as long as the blob.
+    pub unsafe fn read_string_at(&self, ptr: *const u8) -> &'static str {
         unsafe {
             let mut len = 0;
             while *ptr.add(len) != 0 { len += 1; }
@@ -160,4 +380,289 @@ impl DeviceTreeParser {
             core::str::from_utf8_unchecked(slice)
         }
     }
-}
\ No newline at end of file
+}
+
+/// What a region reported by `DeviceTreeParser::scan_memory` is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemoryKind {
+    /// A RAM bank
+    Ram,
+    /// Memory the kernel must not hand out
+    Reserved,
+}
+
+/// A contiguous (address, size) region.
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+pub struct DtRegion {
+    pub address: u64,
+    pub size: u64,
+}
+
+impl DtRegion {
+    pub fn end(&self) -> u64 {
+        self.address + self.size
+    }
+}
+
+/// One `ranges` entry, already resolved down to the CPU address space.
+#[derive(Debug, Clone, Copy)]
+struct DtRange {
+    child: u64,
+    cpu: u64,
+    size: u64,
+}
+
+/// How addresses on a bus map to CPU physical addresses.
+#[derive(Debug, Clone)]
+enum Translation {
+    /// The bus is the CPU address space (the root node, or `ranges;`)
+    Identity,
+    /// The bus is mapped through these windows
+    Ranges(Vec<DtRange>),
+    /// No `ranges` property: addresses are not memory mapped (e.g. `/cpus`)
+    None,
+}
+
+impl Translation {
+    fn translate(&self, address: u64) -> Option<u64> {
+        match self {
+            Translation::Identity => Some(address),
+            Translation::Ranges(ranges) => ranges.iter()
+                .find(|r| address >= r.child && address - r.child < r.size)
+                .map(|r| r.cpu + (address - r.child)),
+            Translation::None => None,
+        }
+    }
+}
+
+/// Properties a node inherits from its parent while parsing.
+struct BusContext {
+    address_cells: u32,
+    size_cells: u32,
+    translation: Translation,
+    interrupt_parent: Option<u32>,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct DtProperty {
+    pub name: &'static str,
+    pub value: &'static [u8],
+}
+
+#[derive(Debug)]
+pub struct DtNode {
+    /// Full node name including the unit address (e.g. `pl011@9000000`)
+    pub name: &'static str,
+    pub properties: Vec<DtProperty>,
+    pub children: Vec<DtNode>,
+    /// `reg` entries translated to CPU physical addresses
+    regions: Vec<DtRegion>,
+    /// Phandle of the interrupt controller (own or inherited)
+    interrupt_parent: Option<u32>,
+}
+
+impl DtNode {
+    /// Node name without the unit address.
+    pub fn base_name(&self) -> &'static str {
+        self.name.split('@').next().unwrap_or(self.name)
+    }
+
+    pub fn property(&self, name: &str) -> Option<&'static [u8]> {
+        self.properties.iter().find(|p| p.name == name).map(|p| p.value)
+    }
+
+    pub fn prop_u32(&self, name: &str) -> Option<u32> {
+        let value = self.property(name)?;
+        if value.len() < 4 { return None; }
+        Some(u32::from_be_bytes([value[0], value[1], value[2], value[3]]))
+    }
+
+    /// First string of a string (or string list) property.
+    pub fn prop_str(&self, name: &str) -> Option<&'static str> {
+        self.prop_strings(name).next()
+    }
+
+    /// Iterate over a NUL-separated string list property.
+    pub fn prop_strings(&self, name: &str) -> impl Iterator<Item = &'static str> {
+        self.property(name).unwrap_or(&[])
+            .split(|&b| b == 0)
+            .filter(|s| !s.is_empty())
+            .filter_map(|s| core::str::from_utf8(s).ok())
+    }
+
+    pub fn compatible(&self) -> impl Iterator<Item = &'static str> {
+        self.prop_strings("compatible")
+    }
+
+    pub fn is_compatible(&self, compat: &str) -> bool {
+        self.compatible().any(|c| c == compat)
+    }
+
+    /// `reg` entries translated to CPU physical addresses.
+    pub fn regions(&self) -> &[DtRegion] {
+        &self.regions
+    }
+
+    pub fn phandle(&self) -> Option<u32> {
+        self.prop_u32("phandle").or_else(|| self.prop_u32("linux,phandle"))
+    }
+
+    /// `status` is absent or "okay"
+    pub fn is_enabled(&self) -> bool {
+        matches!(self.prop_str("status"), None | Some("okay") | Some("ok"))
+    }
+}
+
+/// An `interrupts` specifier split according to the controller's `#interrupt-cells`.
+#[derive(Debug, Clone)]
+pub struct DtInterrupt {
+    pub cells: Vec<u32>,
+}
+
+impl DtInterrupt {
+    /// GIC interrupt id for a standard 3-cell GIC specifier.
+    ///
+    /// Cell 0 is the type (0 = SPI, 1 = PPI), cell 1 the number.
+    pub fn gic_irq_id(&self) -> Option<u32> {
+        if self.cells.len() < 2 { return None; }
+        match self.cells[0] {
+            0 => Some(self.cells[1] + 32),
+            1 => Some(self.cells[1] + 16),
+            _ => None,
+        }
+    }
+
+    /// GIC trigger flags (cell 2): 1 = edge rising, 4 = level high, ...
+    pub fn gic_flags(&self) -> Option<u32> {
+        self.cells.get(2).copied()
+    }
+}
+
+pub struct DeviceTree {
+    pub root: DtNode,
+    /// Memory reservation block entries
+    pub reservations: Vec<DtRegion>,
+    /// Physical location of the blob itself (must stay reserved while the tree is alive)
+    pub blob: DtRegion,
+}
+
+impl DeviceTree {
+    /// Pre-order iterator over every node in the tree.
+    pub fn nodes(&self) -> impl Iterator<Item = &DtNode> {
+        let mut stack: Vec<&DtNode> = alloc::vec![&self.root];
+        core::iter::from_fn(move || {
+            let node = stack.pop()?;
+            stack.extend(node.children.iter().rev());
+            Some(node)
+        })
+    }
+
+    /// First enabled node whose `compatible` list contains `compat`.
+    pub fn find_compatible(&self, compat: &str) -> Option<&DtNode> {
+        self.nodes().find(|n| n.is_enabled() && n.is_compatible(compat))
+    }
+
+    /// Every enabled node whose `compatible` list contains `compat`.
+    pub fn find_all_compatible<'a>(&'a self, compat: &'a str) -> impl Iterator<Item = &'a DtNode> {
+        self.nodes().filter(move |n| n.is_enabled() && n.is_compatible(compat))
+    }
+
+    /// Look a node up by absolute path (e.g. `/chosen`, `/memory@40000000`).
+    ///
+    /// Path components without a unit address match any unit address.
+    pub fn find_node(&self, path: &str) -> Option<&DtNode> {
+        let mut node = &self.root;
+        for component in path.split('/').filter(|c| !c.is_empty()) {
+            node = node.children.iter().find(|c| {
+                c.name == component || (!component.contains('@') && c.base_name() == component)
+            })?;
+        }
+        Some(node)
+    }
+
+    pub fn find_by_phandle(&self, phandle: u32) -> Option<&DtNode> {
+        self.nodes().find(|n| n.phandle() == Some(phandle))
+    }
+
+    /// Decode `interrupts` of `node` using its interrupt parent's `#interrupt-cells`.
+    pub fn interrupts(&self, node: &DtNode) -> Vec<DtInterrupt> {
+        let Some(raw) = node.property("interrupts") else { return Vec::new(); };
+        let cells_per = node.interrupt_parent
+            .and_then(|p| self.find_by_phandle(p))
+            .and_then(|controller| controller.prop_u32("#interrupt-cells"))
+            .unwrap_or(1) as usize;
+        if cells_per == 0 { return Vec::new(); }
+
+        cells_of(raw).chunks_exact(cells_per).map(|c| DtInterrupt { cells: c.to_vec() }).collect()
+    }
+
+    /// All RAM banks from `device_type = "memory"` nodes.
+    pub fn memory_regions(&self) -> Vec<DtRegion> {
+        self.nodes()
+            .filter(|n| n.prop_str("device_type") == Some("memory"))
+            .flat_map(|n| n.regions().iter().copied())
+            .filter(|r| r.size != 0)
+            .collect()
+    }
+
+    /// Memory the kernel must not touch: the reservation block plus every
+    /// child of `/reserved-memory`.
+    pub fn reserved_regions(&self) -> Vec<DtRegion> {
+        let mut regions = self.reservations.clone();
+        if let Some(reserved) = self.find_node("/reserved-memory") {
+            regions.extend(reserved.children.iter().flat_map(|n| n.regions().iter().copied()));
+        }
+        regions
+    }
+
+    pub fn debug_tree(&self) {
+        fn walk(node: &DtNode, depth: usize) {
+            klog!(Level::Debug, "DTB", "{:1$}{2}", "", depth * 2, if node.name.is_empty() { "/" } else { node.name });
+            for region in node.regions() {
+                klog!(Level::Debug, "DTB", "{:1$}  reg <{2:#x} {3:#x}>", "", depth * 2, region.address, region.size);
+            }
+            for child in &node.children { walk(child, depth + 1); }
+        }
+        walk(&self.root, 0);
+    }
+}
+
+/// Reinterpret a property value as a list of big-endian cells.
+fn cells_of(value: &[u8]) -> Vec<u32> {
+    value.chunks_exact(mem::size_of::<u32>()).map(|c| u32::from_be_bytes([c[0], c[1], c[2], c[3]])).collect()
+}
+
+/// Combine up to two cells into one number (most significant first).
+fn read_cells(cells: &[u32]) -> u64 {
+    cells.iter().fold(0u64, |acc, &c| (acc << 32) | c as u64)
+}
+
+fn decode_regions(value: &[u8], address_cells: u32, size_cells: u32) -> Vec<DtRegion> {
+    regions_in(value, address_cells, size_cells).collect()
+}
+
+/// Iterate the (address, size) pairs of a `reg`-style property without allocating.
+fn regions_in(value: &[u8], address_cells: u32, size_cells: u32) -> impl Iterator<Item = DtRegion> + '_ {
+    let (ac, sc) = (address_cells as usize * 4, size_cells as usize * 4);
+    let be = |bytes: &[u8]| bytes.chunks_exact(4).fold(0u64, |acc, c| (acc << 32) | u32::from_be_bytes([c[0], c[1], c[2], c[3]]) as u64);
+    value.chunks_exact((ac + sc).max(1))
+        .filter(move |_| ac + sc != 0)
+        .map(move |c| DtRegion { address: be(&c[..ac]), size: be(&c[ac..]) })
+}
+
+/// Read and parse the blob, storing the result in [`DEVICE_TREE`].
+pub fn init(parser: DeviceTreeParser) -> Result<&'static DeviceTree, &'static str> {
+    let tree = parser.parse()?;
+    unsafe {
+        let stored = &raw mut DEVICE_TREE;
+        *stored = Some(tree);
+        Ok((*stored).as_ref().unwrap())
+    }
+}
+
+/// The device tree, if one was found at boot.
+pub fn device_tree() -> Option<&'static DeviceTree> {
+    let tree = &raw const DEVICE_TREE;
+    unsafe { (*tree).as_ref() }
+}
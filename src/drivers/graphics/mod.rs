@@ -1,2 +1,39 @@
+use core::str::FromStr;
+
 pub mod ramfb;
-pub mod virtio;
\ No newline at end of file
+pub mod virtio;
+
+/// Display device to drive (`gpu=` on the command line)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GpuKind {
+    RamFB,
+    Virtio,
+}
+
+impl FromStr for GpuKind {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, ()> {
+        match s {
+            "ramfb" => Ok(Self::RamFB),
+            "virtio" => Ok(Self::Virtio),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Framebuffer size in pixels, written `<width>x<height>` (`fb=`)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Resolution {
+    pub width: u32,
+    pub height: u32,
+}
+
+impl FromStr for Resolution {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, ()> {
+        let (width, height) = s.split_once('x').ok_or(())?;
+        Ok(Self { width: width.parse().map_err(|_| ())?, height: height.parse().map_err(|_| ())? })
+    }
+}
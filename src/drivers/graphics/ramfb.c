@@ -24,23 +24,33 @@ typedef struct __attribute__((packed)) RamFBCfg {
 } RamFBCfg; 
 
 void qemu_dma_transfer(u32 control, u32 len, u64 addr) {
-    volatile u64* fw_cfg_dma = (volatile u64*)0x9020010;
+    volatile u64* fw_cfg_dma = (volatile u64*)phys_to_virt(platform_info()->fw_cfg_base + 0x10);
     
-    static volatile struct FWCfgDmaAccess dma __attribute__((aligned(8)));
+    // Own cache line so invalidating it while polling cannot clobber neighbours
+    static volatile struct FWCfgDmaAccess dma __attribute__((aligned(64)));
     dma.control = __builtin_bswap32(control);
     dma.len = __builtin_bswap32(len);
-    dma.addr = __builtin_bswap64(addr);
+    // The device works with physical addresses
+    dma.addr = __builtin_bswap64(virt_to_phys(addr));
     
-    u64 dma_addr = (u64)&dma;
+    u64 dma_addr = virt_to_phys((u64)&dma);
+
+    // The device accesses RAM directly: push the buffer and descriptor out
+    // of the data cache before starting the transfer
+    clean_invalidate((size_t)addr, len);
+    clean_dcache_range((size_t)&dma, sizeof(dma));
 
     *fw_cfg_dma = __builtin_bswap64(dma_addr);
 
     asm volatile ("dmb sy" ::: "memory");
 
-    c_dbg("Enter loop ☦️");
+    klog(LOG_LEVEL_TRACE, "RAMFB", "Waiting for the DMA transfer");
 
-    while (dma.control & ~__builtin_bswap32(QEMU_CFG_DMA_CTL_ERROR)) asm volatile ("" ::: "memory");
-    if ((__builtin_bswap32(dma.control) & QEMU_CFG_DMA_CTL_ERROR) == 1) c_serial_println("[   RAMFB   ] \x1b[0;31mAn error occured in qemu_dma_transfer\x1b[0m");
+    do {
+        invalidate_dcache_range((size_t)&dma, sizeof(dma));
+    } while (dma.control & ~__builtin_bswap32(QEMU_CFG_DMA_CTL_ERROR));
+    invalidate_dcache_range((size_t)addr, len);
+    if ((__builtin_bswap32(dma.control) & QEMU_CFG_DMA_CTL_ERROR) == 1) klog(LOG_LEVEL_ERROR, "RAMFB", "An error occured in qemu_dma_transfer");
 }
 
 boolean compare_etc_ramfb(uint8_t* bytes, size_t len) {
@@ -57,7 +67,7 @@ boolean compare_etc_ramfb(uint8_t* bytes, size_t len) {
 
     // If no null terminator found, print warning and assume null_pos = 0
     if (null_pos == 0 && len > 0 && bytes[0] != 0) {
-        c_serial_println("[   RAMFB   ] WARNING: compare_etc_ramfb(): input buffer is not null terminated.");
+        klog(LOG_LEVEL_WARN, "RAMFB", "compare_etc_ramfb(): input buffer is not null terminated.");
     }
 
     // Compare bytes up to null_pos with ramfb_key
@@ -86,8 +96,8 @@ int c_setup_ramfb(char* fb_addr, u32 width, u32 height) {
     for (u32 i = 0; i < num_entries; i++) {
         qemu_dma_transfer(QEMU_CFG_DMA_CTL_READ, (u32)sizeof(FWCfgFile), (u64)&ramfb);
 
-        if (compare_etc_ramfb(ramfb.name, 56)) { c_serial_println("[   RAMFB   ] \x1b[0;32mFound entry \"etc/ramfb\", break.\x1b[0;m"); ret = 0; break; }
-        else c_serial_println("[   RAMFB   ] \x1b[0;31mNo entry found\x1b[0m"); ret--;
+        if (compare_etc_ramfb(ramfb.name, 56)) { klog(LOG_LEVEL_INFO, "RAMFB", "Found entry \"etc/ramfb\", break."); ret = 0; break; }
+        else klog(LOG_LEVEL_DEBUG, "RAMFB", "No entry found"); ret--;
     }
 
     //u32 pixel_format = ((u32)'R') | (((u32)'X') << 8) | (((u32)'2') << 16) | (((u32)'4') << 24);
@@ -95,7 +105,7 @@ int c_setup_ramfb(char* fb_addr, u32 width, u32 height) {
     u32 bpp = BPP;
 
     struct RamFBCfg ramfb_cfg = {
-        __builtin_bswap64((u64)fb_addr),
+        __builtin_bswap64(virt_to_phys((u64)fb_addr)),
         __builtin_bswap32(fourcc),
         0,
         __builtin_bswap32((u32)width),
@@ -103,31 +113,22 @@ int c_setup_ramfb(char* fb_addr, u32 width, u32 height) {
         __builtin_bswap32((u32)width * bpp),
     };
 
-    c_dbg("RamFB config:");
-    c_dbg("  addr: ");
-    c_dgb_hex(__builtin_bswap64(ramfb_cfg.addr));
-    c_dbg("  fourcc: ");
-    c_dgb_hex(__builtin_bswap32(ramfb_cfg.fmt));
-    c_dbg("  flags: ");
-    c_dgb_hex(__builtin_bswap32(ramfb_cfg.flags));
-    c_dbg("  width: ");
-    c_dgb_hex(__builtin_bswap32(ramfb_cfg.w));
-    c_dbg("  height: ");
-    c_dgb_hex(__builtin_bswap32(ramfb_cfg.h));
-    c_dbg("  stride: ");
-    c_dgb_hex(__builtin_bswap32(ramfb_cfg.st));
-
-    c_dbg("RamFB select:");
-    c_dgb_hex(ramfb.select);
-
-    c_dbg("Full control word:");
-    c_dgb_hex(((u32)(ramfb.select)) << 16 | QEMU_CFG_DMA_CTL_SELECT | QEMU_CFG_DMA_CTL_WRITE);
+    klog(LOG_LEVEL_DEBUG, "RAMFB", "RamFB config:");
+    klog_hex(LOG_LEVEL_DEBUG, "RAMFB", "  addr:", __builtin_bswap64(ramfb_cfg.addr));
+    klog_hex(LOG_LEVEL_DEBUG, "RAMFB", "  fourcc:", __builtin_bswap32(ramfb_cfg.fmt));
+    klog_hex(LOG_LEVEL_DEBUG, "RAMFB", "  flags:", __builtin_bswap32(ramfb_cfg.flags));
+    klog_hex(LOG_LEVEL_DEBUG, "RAMFB", "  width:", __builtin_bswap32(ramfb_cfg.w));
+    klog_hex(LOG_LEVEL_DEBUG, "RAMFB", "  height:", __builtin_bswap32(ramfb_cfg.h));
+    klog_hex(LOG_LEVEL_DEBUG, "RAMFB", "  stride:", __builtin_bswap32(ramfb_cfg.st));
+
+    klog_hex(LOG_LEVEL_DEBUG, "RAMFB", "RamFB select:", ramfb.select);
+    klog_hex(LOG_LEVEL_DEBUG, "RAMFB", "Full control word:", ((u32)(ramfb.select)) << 16 | QEMU_CFG_DMA_CTL_SELECT | QEMU_CFG_DMA_CTL_WRITE);
 
     fb_addr[0] = 0xde;
     asm volatile ("dc cvac, %0" :: "r"(&fb_addr[0]) : "memory");
     char readback = fb_addr[0];
-    if (readback == 0xde) c_dbg("FB works");
-    else c_dbg("FB does not work");
+    if (readback == 0xde) klog(LOG_LEVEL_DEBUG, "RAMFB", "FB works");
+    else klog(LOG_LEVEL_WARN, "RAMFB", "FB does not work");
 
     qemu_dma_transfer(((u32)__builtin_bswap16(ramfb.select)) << 16 | QEMU_CFG_DMA_CTL_SELECT | QEMU_CFG_DMA_CTL_WRITE, (u32)sizeof(RamFBCfg), (u64)&ramfb_cfg);
 
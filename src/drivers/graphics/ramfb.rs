@@ -2,7 +2,7 @@ use core::{ffi::c_char, ptr::null_mut};
 use alloc::vec::Vec;
 use spin::mutex;
 
-use crate::{BPP, SCREENHEIGHT, SCREENWIDTH, bootscreen::bootscreen_visual, dbg, memory::allocator::alloc_ffi::kmalloc_aligned, mvulkan::{MVulkanGPUDriver, MVulkanGeometry, MVulkanText}, serial_println, serial_println_prefixed, thread};
+use crate::{BPP, SCREENHEIGHT, SCREENWIDTH, bootscreen::bootscreen_visual, dbg, klog, log::Level, memory::{cache::clean_dcache_range, frame_allocator::{PAGE_SIZE, alloc_contiguous}, layout::phys_to_virt}, mvulkan::{MVulkanGPUDriver, MVulkanGeometry, MVulkanText}, thread};
 use crate::{min, max};
 
 pub mod c {
@@ -35,15 +35,31 @@ impl RamFBDriver {
             return Err("Error: attempted to display bootscreen before RamFB framebuffer allocation.");
         }
         bootscreen_visual(self.fb_addr);
+        self.flush(0, SCREENWIDTH, 0, SCREENHEIGHT);
         Ok(())
     }
+
+    /// Write the pixels in `[minx, maxx) x [miny, maxy)` out of the data cache
+    /// so the device (which reads RAM directly) shows them.
+    fn flush(&self, minx: u32, maxx: u32, miny: u32, maxy: u32) {
+        let (maxx, maxy) = (maxx.min(SCREENWIDTH), maxy.min(SCREENHEIGHT));
+        if self.fb_addr.is_null() || minx >= maxx { return; }
+        for y in miny..maxy {
+            let offset = ((y * SCREENWIDTH + minx) * BPP) as usize;
+            clean_dcache_range(self.fb_addr as usize + offset, ((maxx - minx) * BPP) as usize);
+        }
+    }
 }
 
 impl MVulkanGPUDriver for RamFBDriver {
     fn setup(&mut self) -> Result<(), &'static str> {
-        serial_println_prefixed!("Allocating Ramfb framebuffer...");
-        let fb_addr = kmalloc_aligned((BPP*SCREENWIDTH*SCREENHEIGHT) as usize, 4096);
-        self.fb_addr = fb_addr;
+        klog!(Level::Info, "DRIVERS", "Allocating Ramfb framebuffer...");
+        let fb_size = (BPP*SCREENWIDTH*SCREENHEIGHT) as usize;
+        let fb_addr = alloc_contiguous(fb_size.div_ceil(PAGE_SIZE as usize), PAGE_SIZE as usize);
+        if fb_addr == 0 {
+            return Err("Error: failed to allocate RamFB framebuffer (out of physical memory).");
+        }
+        self.fb_addr = phys_to_virt(fb_addr) as *mut c_char;
         unsafe { 
             let res = c::c_setup_ramfb(self.fb_addr, SCREENWIDTH, SCREENHEIGHT); 
             if res != 0 {
@@ -58,24 +74,28 @@ impl MVulkanGPUDriver for RamFBDriver {
         unsafe {
             c::ramfb_clear(color, self.fb_addr);
         }
+        self.flush(0, SCREENWIDTH, 0, SCREENHEIGHT);
     }
 
     fn draw_rect(&mut self, minx: u32, maxx: u32, miny: u32, maxy: u32, r: u8, g: u8, b: u8) {
         unsafe {
             c::ramfb_draw_rect(minx, maxx, miny, maxy, r, g, b, self.fb_addr);
         }
+        self.flush(minx, maxx, miny, maxy);
     }
 
     fn set_pixel(&mut self, x: u32, y: u32, r: u8, g: u8, b: u8) {
         unsafe {
             c::ramfb_set_pixel(x, y, r, g, b, self.fb_addr);
         }
+        self.flush(x, x + 1, y, y + 1);
     }
 
     fn draw_char(&mut self, utf8: usize, r: u8, g: u8, b: u8, x: u32, y: u32, scale: u8) {
         unsafe {
             c::ramfb_draw_letter(utf8, r, g, b, x, y, self.fb_addr, scale);
         }
+        self.flush(x, x + 8 * scale as u32, y, y + 8 * scale as u32);
     }
 
     fn as_geometry(&self) -> Option<&dyn crate::mvulkan::MVulkanGeometry> {
@@ -1,4 +1,4 @@
-use crate::{drivers, mvulkan::MVulkanGPUDriver, serial_println};
+use crate::{drivers, mvulkan::MVulkanGPUDriver, klog, log::Level};
 
 unsafe extern "C" {
     fn virtio_generic_setup_c(virtio_base: u64, device_id: u16) -> i32;
@@ -14,10 +14,10 @@ impl VirtioDriver {
         let virtio_gpu_base = drivers::pci::find_pci_device(0x1af4, 0x1050);
 
         if virtio_gpu_base == 0x0 {
-            serial_println!("[  DRIVERS  ] Finding VirtIO GPU device... \x1b[0;31mFAILED\x1b[0m");
+            klog!(Level::Error, "DRIVERS", "Finding VirtIO GPU device... FAILED");
             return Err(1);
         } else {
-            serial_println!("[  DRIVERS  ] Finding VirtIO GPU device... \x1b[0;32mSUCCESS\x1b[0m");
+            klog!(Level::Info, "DRIVERS", "Finding VirtIO GPU device... SUCCESS");
             Ok(Self{base: virtio_gpu_base})
         }
     }
@@ -27,7 +27,11 @@ impl MVulkanGPUDriver for VirtioDriver {
     fn setup(&mut self) -> Result<(), &'static str> {
         unsafe { 
             let virtio_gpu_enabled =  pci_enable_device_c(self.base); 
-            serial_println!("[  DRIVERS  ] Enabling VirtIO GPU device... {}\x1b[0m", {if virtio_gpu_enabled {"\x1b[0;32mSUCCESS"} else {"\x1b[0;31mFAILED"}});
+            if virtio_gpu_enabled {
+                klog!(Level::Info, "DRIVERS", "Enabling VirtIO GPU device... SUCCESS");
+            } else {
+                klog!(Level::Error, "DRIVERS", "Enabling VirtIO GPU device... FAILED");
+            }
         }
         unsafe {
             match virtio_generic_setup_c(self.base, 0x10) {
@@ -1,5 +1,8 @@
 pub mod graphics;
-//pub mod dtb_parser;
+pub mod dtb_parser;
+pub mod platform;
+pub mod psci;
+pub mod rtc;
 pub mod uart;
 pub mod pci;
 pub mod xhci;
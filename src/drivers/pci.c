@@ -4,20 +4,17 @@
 #define PCI_BAR_BASE_OFFSET 0x10
 
 bool pci_enable_device_c(size_t base) {
-    c_dbg("pci.c:8");
     size_t* cmd_addr = (size_t*)(base + 0x04);
-    c_dbg("pci.c:10");
     size_t cmd_before = *cmd_addr;
-    c_dbg("pci.c:12");
+    klog_hex(LOG_LEVEL_TRACE, "PCI", "Command register before:", cmd_before);
     size_t cmd = cmd_before | 0x07;
     *cmd_addr = cmd;
-    c_dbg("pci.c:15");
     size_t cmd_after = *cmd_addr;
+    klog_hex(LOG_LEVEL_TRACE, "PCI", "Command register after:", cmd_after);
     if ((cmd_after & 0x7) == 0x7) {
-        c_dbg("pci.c:18 true");
         return true;
     } else {
-        c_dbg("pci.c:21 false");
+        klog(LOG_LEVEL_ERROR, "PCI", "Failed to enable PCI device (MSE/BME not set).");
         return false;
     }
 }
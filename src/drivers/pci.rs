@@ -1,8 +1,7 @@
 use core::arch::asm;
 use alloc::string;
-use crate::{memory::mmio::{mmio_read, mmio_read32, mmio_write32}, serial_print, serial_println, serial_println_prefixed};
+use crate::{drivers::platform::platform, memory::{layout::phys_to_virt, mmio::{mmio_read, mmio_read32, mmio_write32}}, klog, log::Level, serial_print, serial_println};
 
-const PCI_ECAM_BASE: u64 = 0x4010000000;
 const PCI_BUS_MAX: u64 = 256;
 const PCI_SLOT_MAX: u64 = 32;
 const PCI_FUNC_MAX: u64 = 8;
@@ -10,7 +9,7 @@ const PCI_FUNC_MAX: u64 = 8;
 const PCI_CMD_REG: u64 = 0x04;
 
 pub fn pci_make_addr(bus: u32, slot: u32, func: u32, offset: u32) -> u64 {
-    PCI_ECAM_BASE | ((bus as u64) << 20) | ((slot as u64) << 15) | ((func as u64) << 12) | (offset & 0xFFF) as u64
+    phys_to_virt(platform().pci_ecam_base) | ((bus as u64) << 20) | ((slot as u64) << 15) | ((func as u64) << 12) | (offset & 0xFFF) as u64
 }
 
 #[unsafe(no_mangle)]
@@ -50,7 +49,7 @@ pub fn inspect_bar(base: u64, offset: u8) {
     //     }
     // }
 
-    serial_println!("[PCI] Inspecting GPU BARs...");
+    klog!(Level::Info, "PCI", "Inspecting GPU BARs...");
     for bar_offset in (0x0..=0x18).step_by(4) {
         debug_read_bar(base, bar_offset, offset);
     }
@@ -65,7 +64,7 @@ pub extern "C" fn find_pci_device(vendor_id: u32, device_id: u32) -> u64 {
                 let vendor_device = mmio_read(device_address);
 
                 if (vendor_device & 0xFFFF) == vendor_id as u64 && (vendor_device >> 16) & 0xFFFF == device_id as u64 {
-                    serial_println!("[    PCI    ] Found device at bus {:x}, slot {:x}, func {:x}", bus, slot, func);
+                    klog!(Level::Info, "PCI", "Found device at bus {:x}, slot {:x}, func {:x}", bus, slot, func);
 
                     return device_address;
                 }
@@ -89,13 +88,12 @@ pub fn dump_pci_config(base: u64) {
 pub fn pci_enable_device(base: u64) {
     let cmd_before: u64 = 0x00100000;
 
-    serial_println!("[    PCI    ] PCI Command Register before: {:x}", cmd_before);
+    klog!(Level::Debug, "PCI", "PCI Command Register before: {:x}", cmd_before);
 
     //Set the Memory Space Enable (MSE) and Bus Master Enable (BME) bits
     let cmd = cmd_before | 0x7;
 
-    serial_println!("[    PCI    ] Setting CMD: {:x}", cmd);
-    serial_println!("[   TERRY   ] NIGGER");
+    klog!(Level::Debug, "PCI", "Setting CMD: {:x}", cmd);
 
     //mmio_write(base + 0x4, cmd as u32);
     unsafe {
@@ -103,12 +101,12 @@ pub fn pci_enable_device(base: u64) {
     }
 
     let cmd_after = mmio_read(base + 0x04);
-    serial_println!("[    PCI    ] PCI Command Register after: {:x}", cmd_after);
+    klog!(Level::Debug, "PCI", "PCI Command Register after: {:x}", cmd_after);
 
     if (cmd_after & 0x7) == 0x7 {
-        serial_println!("[    PCI    ] \x1b[0;32mPCI device succesfully enabled.\x1b[0m");
+        klog!(Level::Info, "PCI", "PCI device succesfully enabled.");
     } else {
-        serial_println!("[    PCI    ] \x1B[0;31m[Error]: Failed to enable PCI device (MSE/BME not set).\x1B[0m");
+        klog!(Level::Error, "PCI", "Failed to enable PCI device (MSE/BME not set).");
     }
 }
 
@@ -118,17 +116,17 @@ pub unsafe extern "C" fn pci_setup_bar(pci_addr: u64, bar_index: u32, mmio_start
     let original = mmio_read32(bar_addr);
     mmio_write32(bar_addr, 0xffffffff);
     let bar_low= mmio_read32(bar_addr);
-    serial_println!("[{:^10}] First bar size: {:#x}", "PCI", bar_low);
+    klog!(Level::Debug, "PCI", "First bar size: {:#x}", bar_low);
 
     let mut size: u64;
     if (original & 0x6) == 0x4 {
         let bar_addr_hi = pci_get_bar(pci_addr, 0x10, bar_index as u8+1);
         let original_hi = mmio_read32(bar_addr_hi);
-        serial_println_prefixed!("Original second bar {:#x}", original_hi);
+        klog!(Level::Debug, "PCI", "Original second bar {:#x}", original_hi);
 
         mmio_write32(bar_addr_hi, 0xffffffff);
         let bar_high = mmio_read32(bar_addr_hi);
-        serial_println_prefixed!("Second bar size: {:#x}", bar_high);
+        klog!(Level::Debug, "PCI", "Second bar size: {:#x}", bar_high);
     }
     0
 }
\ No newline at end of file
@@ -0,0 +1,150 @@
+//! Addresses of the platform devices the kernel drives directly.
+//!
+//! Every field starts out with the QEMU `virt` board layout so the UART works
+//! before anything else is up. `probe` then overrides them with whatever the
+//! device tree reports.
+
+use crate::{drivers::dtb_parser::{DeviceTree, DtRegion}, klog, log::Level};
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct PlatformInfo {
+    /// PL011 UART registers
+    pub uart_base: u64,
+    /// GIC distributor registers
+    pub gicd_base: u64,
+    pub gicd_size: u64,
+    /// GIC CPU interface registers (GICv2 only)
+    pub gicc_base: u64,
+    pub gicc_size: u64,
+    /// GIC redistributors, one frame per CPU (GICv3 only)
+    pub gicr_base: u64,
+    pub gicr_size: u64,
+    /// GIC architecture version, 2 or 3
+    pub gic_version: u32,
+    /// PCIe ECAM configuration space
+    pub pci_ecam_base: u64,
+    pub pci_ecam_size: u64,
+    /// QEMU fw_cfg MMIO interface
+    pub fw_cfg_base: u64,
+    /// PL031 real-time clock
+    pub rtc_base: u64,
+    /// First RAM bank
+    pub mem_base: u64,
+    pub mem_size: u64,
+}
+
+impl PlatformInfo {
+    /// Layout of the QEMU `virt` machine with `-m 1G`.
+    pub const fn qemu_virt() -> Self {
+        Self {
+            uart_base: 0x0900_0000,
+            gicd_base: 0x0800_0000,
+            gicd_size: 0x1_0000,
+            gicc_base: 0x0801_0000,
+            gicc_size: 0x1_0000,
+            gicr_base: 0x080a_0000,
+            gicr_size: 0,
+            gic_version: 2,
+            pci_ecam_base: 0x40_1000_0000,
+            pci_ecam_size: 0x1000_0000,
+            fw_cfg_base: 0x0902_0000,
+            rtc_base: 0x0901_0000,
+            mem_base: 0x4000_0000,
+            mem_size: 0x4000_0000,
+        }
+    }
+}
+
+pub static mut PLATFORM: PlatformInfo = PlatformInfo::qemu_virt();
+
+/// Current platform layout.
+pub fn platform() -> &'static PlatformInfo {
+    let info = &raw const PLATFORM;
+    unsafe { &*info }
+}
+
+/// Platform layout for C sources
+#[unsafe(no_mangle)]
+pub extern "C" fn platform_info() -> *const PlatformInfo {
+    &raw const PLATFORM
+}
+
+/// Record the first RAM bank, found before the device tree is parsed.
+pub fn set_memory(bank: DtRegion) {
+    let info = &raw mut PLATFORM;
+    let info = unsafe { &mut *info };
+    info.mem_base = bank.address;
+    info.mem_size = bank.size;
+}
+
+/// Fill in [`PLATFORM`] from the device tree.
+///
+/// Devices missing from the tree keep their `virt` defaults.
+pub fn probe(tree: &DeviceTree) {
+    let info = &raw mut PLATFORM;
+    let info = unsafe { &mut *info };
+
+    if let Some(uart) = tree.find_compatible("arm,pl011").and_then(|n| n.regions().first()) {
+        info.uart_base = uart.address;
+    }
+
+    let gic = tree.find_compatible("arm,cortex-a15-gic")
+        .or_else(|| tree.find_compatible("arm,gic-400"));
+    if let Some(gic) = gic {
+        let regions = gic.regions();
+        if regions.len() >= 2 {
+            info.gic_version = 2;
+            info.gicd_base = regions[0].address;
+            info.gicd_size = regions[0].size;
+            info.gicc_base = regions[1].address;
+            info.gicc_size = regions[1].size;
+        }
+    }
+
+    if let Some(gic) = tree.find_compatible("arm,gic-v3") {
+        let regions = gic.regions();
+        if regions.len() >= 2 {
+            info.gic_version = 3;
+            info.gicd_base = regions[0].address;
+            info.gicd_size = regions[0].size;
+            info.gicr_base = regions[1].address;
+            info.gicr_size = regions[1].size;
+            info.gicc_size = 0;
+        }
+    }
+
+    if let Some(ecam) = tree.find_compatible("pci-host-ecam-generic").and_then(|n| n.regions().first()) {
+        info.pci_ecam_base = ecam.address;
+        info.pci_ecam_size = ecam.size;
+    }
+
+    if let Some(fw_cfg) = tree.find_compatible("qemu,fw-cfg-mmio").and_then(|n| n.regions().first()) {
+        info.fw_cfg_base = fw_cfg.address;
+    }
+
+    if let Some(rtc) = tree.find_compatible("arm,pl031").and_then(|n| n.regions().first()) {
+        info.rtc_base = rtc.address;
+    }
+
+    if let Some(mem) = tree.memory_regions().first() {
+        info.mem_base = mem.address;
+        info.mem_size = mem.size;
+    }
+}
+
+pub fn debug_platform() {
+    let info = platform();
+    klog!(Level::Info, "PLATFORM", "UART:   {:#x}", info.uart_base);
+    klog!(Level::Info, "PLATFORM", "GICv{}", info.gic_version);
+    klog!(Level::Info, "PLATFORM", "GICD:   {:#x} ({:#x})", info.gicd_base, info.gicd_size);
+    if info.gic_version >= 3 {
+        klog!(Level::Info, "PLATFORM", "GICR:   {:#x} ({:#x})", info.gicr_base, info.gicr_size);
+    } else {
+        klog!(Level::Info, "PLATFORM", "GICC:   {:#x} ({:#x})", info.gicc_base, info.gicc_size);
+    }
+    klog!(Level::Info, "PLATFORM", "ECAM:   {:#x} ({:#x})", info.pci_ecam_base, info.pci_ecam_size);
+    klog!(Level::Info, "PLATFORM", "fw_cfg: {:#x}", info.fw_cfg_base);
+    klog!(Level::Info, "PLATFORM", "RTC:    {:#x}", info.rtc_base);
+    klog!(Level::Info, "PLATFORM", "RAM:    {:#x} ({} MiB)", info.mem_base, info.mem_size >> 20);
+}
@@ -0,0 +1,118 @@
+//! Power State Coordination Interface.
+//!
+//! PSCI is the firmware (or hypervisor) interface for turning CPUs and the
+//! whole system on and off. Calls go through `hvc` or `smc`, whichever the
+//! `/psci` device tree node names as its `method`.
+
+use core::{arch::asm, sync::atomic::{AtomicU8, Ordering}};
+
+use crate::{drivers::dtb_parser::DeviceTree, klog, log::Level};
+
+// Function IDs (SMC64 calling convention where there is a choice)
+const PSCI_VERSION: u32 = 0x8400_0000;
+const CPU_ON: u32 = 0xc400_0003;
+const SYSTEM_OFF: u32 = 0x8400_0008;
+const SYSTEM_RESET: u32 = 0x8400_0009;
+
+/// How PSCI calls reach the firmware
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Conduit {
+    None = 0,
+    Hvc = 1,
+    Smc = 2,
+}
+
+static CONDUIT: AtomicU8 = AtomicU8::new(Conduit::None as u8);
+
+pub fn conduit() -> Conduit {
+    match CONDUIT.load(Ordering::Relaxed) {
+        1 => Conduit::Hvc,
+        2 => Conduit::Smc,
+        _ => Conduit::None,
+    }
+}
+
+/// Pick up the conduit from the device tree
+pub fn probe(tree: &DeviceTree) {
+    let psci = tree.find_compatible("arm,psci-1.0")
+        .or_else(|| tree.find_compatible("arm,psci-0.2"))
+        .or_else(|| tree.find_compatible("arm,psci"));
+    let conduit = match psci.and_then(|n| n.prop_str("method")) {
+        Some("hvc") => Conduit::Hvc,
+        Some("smc") => Conduit::Smc,
+        _ => Conduit::None,
+    };
+    CONDUIT.store(conduit as u8, Ordering::Relaxed);
+
+    match version() {
+        Some((major, minor)) => klog!(Level::Info, "PSCI", "PSCI {}.{} via {:?}", major, minor, conduit),
+        None => klog!(Level::Warn, "PSCI", "No PSCI firmware"),
+    }
+}
+
+/// Make a PSCI call. Per the SMC calling convention x0-x17 may be clobbered.
+fn call(function: u32, arg0: u64, arg1: u64, arg2: u64) -> i64 {
+    let mut ret = function as u64;
+    unsafe {
+        match conduit() {
+            Conduit::Hvc => asm!(
+                "hvc #0",
+                inout("x0") ret, inout("x1") arg0 => _, inout("x2") arg1 => _, inout("x3") arg2 => _,
+                out("x4") _, out("x5") _, out("x6") _, out("x7") _, out("x8") _, out("x9") _,
+                out("x10") _, out("x11") _, out("x12") _, out("x13") _, out("x14") _, out("x15") _,
+                out("x16") _, out("x17") _,
+            ),
+            Conduit::Smc => asm!(
+                "smc #0",
+                inout("x0") ret, inout("x1") arg0 => _, inout("x2") arg1 => _, inout("x3") arg2 => _,
+                out("x4") _, out("x5") _, out("x6") _, out("x7") _, out("x8") _, out("x9") _,
+                out("x10") _, out("x11") _, out("x12") _, out("x13") _, out("x14") _, out("x15") _,
+                out("x16") _, out("x17") _,
+            ),
+            Conduit::None => return -1,
+        }
+    }
+    ret as i64
+}
+
+/// Turn a PSCI status code into a result
+fn status(ret: i64) -> Result<(), &'static str> {
+    match ret {
+        0 => Ok(()),
+        -1 => Err("[   PSCI    ] \x1b[1;31mNot supported\x1b[0m"),
+        -2 => Err("[   PSCI    ] \x1b[1;31mInvalid parameters\x1b[0m"),
+        -3 => Err("[   PSCI    ] \x1b[1;31mDenied\x1b[0m"),
+        -4 => Err("[   PSCI    ] \x1b[1;31mCPU already on\x1b[0m"),
+        -5 => Err("[   PSCI    ] \x1b[1;31mCPU on pending\x1b[0m"),
+        -7 => Err("[   PSCI    ] \x1b[1;31mCPU not present\x1b[0m"),
+        -8 => Err("[   PSCI    ] \x1b[1;31mCPU disabled\x1b[0m"),
+        -9 => Err("[   PSCI    ] \x1b[1;31mInvalid entry address\x1b[0m"),
+        _ => Err("[   PSCI    ] \x1b[1;31mInternal failure\x1b[0m"),
+    }
+}
+
+/// PSCI version (major, minor), if there is a PSCI implementation at all
+pub fn version() -> Option<(u16, u16)> {
+    if conduit() == Conduit::None {
+        return None;
+    }
+    let ret = call(PSCI_VERSION, 0, 0, 0);
+    (ret >= 0).then_some(((ret >> 16) as u16, ret as u16))
+}
+
+/// Start the CPU with affinity `mpidr` at physical address `entry`, MMU off,
+/// with `context` in x0
+pub fn cpu_on(mpidr: u64, entry: u64, context: u64) -> Result<(), &'static str> {
+    status(call(CPU_ON, mpidr, entry, context))
+}
+
+/// Power the system off. Only returns if that failed.
+pub fn system_off() -> Result<(), &'static str> {
+    status(call(SYSTEM_OFF, 0, 0, 0))
+}
+
+/// Reset the system. Only returns if that failed.
+pub fn system_reset() -> Result<(), &'static str> {
+    status(call(SYSTEM_RESET, 0, 0, 0))
+}
@@ -0,0 +1,123 @@
+//! PL031 real-time clock.
+//!
+//! The RTC only counts whole seconds, so it is read once at boot and wall
+//! clock time is derived from the monotonic counter after that.
+
+use core::{fmt, sync::atomic::{AtomicU64, Ordering}, time::Duration};
+
+use crate::{drivers::platform::platform, memory::{layout::phys_to_virt, mmio::{mmio_read32, mmio_write32}}, klog, log::Level, time::{self, Instant}};
+
+/// Data register: seconds since the Unix epoch
+const RTCDR: u64 = 0x000;
+/// Control register, bit 0 starts the clock
+const RTCCR: u64 = 0x00c;
+/// Primecell peripheral id, low byte of the part number
+const RTC_PERIPH_ID0: u64 = 0xfe0;
+const PL031_PART_LOW: u32 = 0x31;
+
+const SECS_PER_DAY: u64 = 86400;
+
+/// Unix time (seconds) read at boot, 0 while unknown
+static BOOT_UNIX_SECS: AtomicU64 = AtomicU64::new(0);
+/// Counter value at the time of that read
+static BOOT_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+fn rtc_base() -> u64 {
+    phys_to_virt(platform().rtc_base)
+}
+
+/// Read the RTC. Needs the device mapped (`paging::map_platform`).
+pub fn init() -> Result<(), &'static str> {
+    if mmio_read32(rtc_base() + RTC_PERIPH_ID0) & 0xff != PL031_PART_LOW {
+        return Err("[    RTC    ] \x1b[1;31mNo PL031 found\x1b[0m");
+    }
+    mmio_write32(rtc_base() + RTCCR, 1);
+
+    let secs = mmio_read32(rtc_base() + RTCDR) as u64;
+    BOOT_COUNTER.store(Instant::now().ticks(), Ordering::Relaxed);
+    BOOT_UNIX_SECS.store(secs, Ordering::Relaxed);
+
+    klog!(Level::Info, "RTC", "Wall clock time: {}", DateTime::from_unix(Duration::from_secs(secs)));
+    Ok(())
+}
+
+/// Time since the Unix epoch, if the RTC has been read
+pub fn unix_time() -> Option<Duration> {
+    let secs = BOOT_UNIX_SECS.load(Ordering::Relaxed);
+    if secs == 0 {
+        return None;
+    }
+    let base = Instant::now().ticks() - BOOT_COUNTER.load(Ordering::Relaxed);
+    Some(Duration::from_secs(secs) + time::ticks_to_duration(base))
+}
+
+/// Current UTC date and time, if the RTC has been read
+pub fn now_utc() -> Option<DateTime> {
+    unix_time().map(DateTime::from_unix)
+}
+
+/// A UTC date and time, displayed as ISO-8601 (`2025-09-08T12:34:56.789Z`)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DateTime {
+    pub year: i64,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+    pub millis: u16,
+}
+
+impl DateTime {
+    pub fn from_unix(since_epoch: Duration) -> Self {
+        let secs = since_epoch.as_secs();
+        let (year, month, day) = civil_from_days((secs / SECS_PER_DAY) as i64);
+        let time = secs % SECS_PER_DAY;
+        Self {
+            year,
+            month,
+            day,
+            hour: (time / 3600) as u8,
+            minute: (time / 60 % 60) as u8,
+            second: (time % 60) as u8,
+            millis: since_epoch.subsec_millis() as u16,
+        }
+    }
+}
+
+impl fmt::Display for DateTime {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z", self.year, self.month, self.day, self.hour, self.minute, self.second, self.millis)
+    }
+}
+
+/// Year, month and day of the `days`th day after 1970-01-01
+/// (H. Hinnant's `civil_from_days`)
+fn civil_from_days(days: i64) -> (i64, u8, u8) {
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u8;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u8;
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+/// Log line timestamp: the wall clock time once the RTC has been read,
+/// the uptime in seconds before that
+pub struct Timestamp;
+
+impl fmt::Display for Timestamp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match now_utc() {
+            Some(now) => write!(f, "{}", now),
+            None => {
+                let uptime = time::uptime();
+                write!(f, "+{}.{:06}", uptime.as_secs(), uptime.subsec_micros())
+            }
+        }
+    }
+}
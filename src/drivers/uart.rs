@@ -1,9 +1,12 @@
 use core::{ffi::{c_char, CStr}, fmt::Write};
 
-use crate::{GPU_DEVICE, SCALE, SCREENHEIGHT, SCREENWIDTH, THEME, console_print, console_println, dbg, memory::mmio::mmio_write32, mvulkan::{color::GENERIC_WHITE, console::{self, newline}}, trinkets::templeos_color_palette::WHITE};
+use crate::{GPU_DEVICE, SCALE, SCREENHEIGHT, SCREENWIDTH, THEME, console_print, console_println, dbg, klog, log::Level, drivers::platform::platform, exceptions::irq, memory::{layout::phys_to_virt, mmio::mmio_write32}, mvulkan::{color::GENERIC_WHITE, console::{self, newline}}, thread, trinkets::templeos_color_palette::WHITE};
 
-// UART base address for QEMU virt machine
-const UART_BASE: *mut u8 = 0x09000000 as *mut u8;
+/// UART base address in the direct map (from the device tree, QEMU virt
+/// default until probed)
+fn uart_base() -> *mut u8 {
+    phys_to_virt(platform().uart_base) as *mut u8
+}
 
 // UART register offsets
 const UART_DR: isize = 0x00;    // Data Register
@@ -16,6 +19,9 @@ const UART_FR_TXFF: u8 = 1 << 5; // Transmit FIFO full
 const UART_RXIM: u8 = 1 << 4;
 const UART_RTIM: u8 = 1 << 6;
 
+/// Receive interrupt of the PL011 (SPI 1 on QEMU virt)
+pub const UART_IRQ: u32 = 33;
+
 // Input buffer
 const BUF_SIZE: usize = 256;
 static mut RX_BUFFER: [char; BUF_SIZE] = [0 as char; BUF_SIZE];
@@ -23,28 +29,55 @@ static mut RX_HEAD: usize = 0; // where irq writes
 static mut RX_TAIL: usize = 0; // read
 
 pub unsafe fn uart_enable_rxim() {
-    (*((UART_BASE as isize+UART_IMSC) as *mut usize)) |= UART_RXIM as usize | UART_RTIM as usize;
+    (*((uart_base() as isize+UART_IMSC) as *mut usize)) |= UART_RXIM as usize | UART_RTIM as usize;
+    if let Err(e) = irq::register_handler(UART_IRQ, uart_irq_handler, irq::PRIORITY_DEFAULT, irq::Trigger::Level) {
+        klog!(Level::Error, "SERIAL", "{}", e);
+    }
 }
 
-pub fn uart_irq_handler() {
-    let mut flags: *mut u32 = (UART_BASE as isize+UART_FR) as *mut u32;
-    let mut data: *mut u32 = (UART_BASE as isize+UART_DR) as *mut u32;
-    let mut icr: *mut u32 = (UART_BASE as isize+UART_ICR) as *mut u32;
+/// Move received characters into the input buffer. Echoing them is left to
+/// `uart_echo`, as drawing on the console allocates.
+pub fn uart_irq_handler(_irq: u32) {
+    let flags: *mut u32 = (uart_base() as isize+UART_FR) as *mut u32;
+    let data: *mut u32 = (uart_base() as isize+UART_DR) as *mut u32;
+    let icr: *mut u32 = (uart_base() as isize+UART_ICR) as *mut u32;
 
     unsafe {
-        let theme = THEME;
-        while ((*flags)&(1<<4)) == 0 {
-            let c: char = ((*data) & 0xff) as u8 as char;
+        // Volatile: the flags change under the loop as the FIFO drains
+        while (flags.read_volatile() & (1<<4)) == 0 {
+            let c: char = (data.read_volatile() & 0xff) as u8 as char;
             // store in buffer
             RX_BUFFER[RX_HEAD] = c;
             RX_HEAD = (RX_HEAD + 1) % BUF_SIZE;
+        }
+        mmio_write32(icr as u64, UART_RXIM as u32 | UART_RTIM as u32);
+    }
+}
+
+/// Next character from the input buffer, if any
+pub fn uart_read_char() -> Option<char> {
+    unsafe {
+        if RX_TAIL == RX_HEAD {
+            return None;
+        }
+        let c = RX_BUFFER[RX_TAIL];
+        RX_TAIL = (RX_TAIL + 1) % BUF_SIZE;
+        Some(c)
+    }
+}
+
+/// Echo UART input on the console, forever. Runs as its own thread.
+pub fn uart_echo() -> ! {
+    loop {
+        while let Some(c) = uart_read_char() {
+            let theme = unsafe { THEME };
             // print on screen
             dbg!("UART: got {:?}", c.as_ascii());
             if c == '\r' { console::newline();}
             else if c == '\x7f' { console::backspace(); }
             else { console_print!("{}", c ; color: theme.white()); }
         }
-        mmio_write32(icr as u64, UART_RXIM as u32 | UART_RTIM as u32);
+        thread::yield_now();
     }
 }
 
@@ -52,11 +85,11 @@ pub fn uart_irq_handler() {
 fn uart_write_byte(byte: u8) {
     unsafe {
         // Wait until transmit FIFO is not full
-        while (UART_BASE.offset(UART_FR).read_volatile() & UART_FR_TXFF) != 0 {
+        while (uart_base().offset(UART_FR).read_volatile() & UART_FR_TXFF) != 0 {
             core::hint::spin_loop();
         }
         // Write byte to data register
-        UART_BASE.offset(UART_DR).write_volatile(byte);
+        uart_base().offset(UART_DR).write_volatile(byte);
     }
 }
 
@@ -101,111 +134,15 @@ macro_rules! serial_println {
     };
 }
 
-/// Print formatted string to UART with newline and prefix
-#[macro_export]
-#[macro_use]
-macro_rules! serial_println_prefixed {
-    () => {
-        $crate::serial_print!("\n")
-    };
-    ($prefix:expr => $($arg:tt)*) => {
-        {
-            use core::fmt::Write;
-            use alloc::format;
-            let mut writer = $crate::UartWriter;
-            let prefixed = format!("[{:^11}] ", $prefix);
-            write!(writer, "{}", prefixed).ok();
-            writeln!(writer, $($arg)*).ok();
-        }
-    };
-    ($fmt:expr, $($arg:expr),*) => {
-        {
-            let formatted = ::alloc::format!($fmt, $($arg),*);
-            use core::fmt::Write;
-            use alloc::format;
-            let mut writer = $crate::UartWriter;
-            let prefixed = format!("[{:^11}] ", module_path!().split("::").last().unwrap_or("unknown").to_uppercase());
-            write!(writer, "{}", prefixed).ok();
-            writeln!(writer, "{formatted}").ok();
-        }
-    };
-    ($fmt:expr) => {
-        {
-            let formatted = ::alloc::format!($fmt);
-            use core::fmt::Write;
-            use alloc::format;
-            let mut writer = $crate::UartWriter;
-            let prefixed = format!("[{:^11}] ", module_path!().split("::").last().unwrap_or("unknown").to_uppercase());
-            write!(writer, "{}", prefixed).ok();
-            writeln!(writer, "{formatted}").ok();
-        }
-    };
-    ($fmt:expr, $($arg:expr),* ; color: $color:expr) => {
-        {
-            let formatted = ::alloc::format!($fmt, $($arg),*);
-            let color_pre = match $color {
-                "red" => {"\x1b[0;31m"},
-                "green" => {"\x1b[0;32m"},
-                "yellow" => {"\x1b[0;33m"},
-                "rb" => {"\x1b[1;31m"},
-                "gb" => {"\x1b[1;32m"},
-                "yb" => {"\x1b[1;33m"},
-            };
-            let color_post = "\x1b[0m"
-            use core::fmt::Write;
-            use alloc::format;
-            let mut writer = $crate::UartWriter;
-            let prefixed = format!("[{:^11}] ", module_path!().split("::").last().unwrap_or("unknown").to_uppercase());
-            write!(writer, "{}", prefixed).ok();
-            write!(writer, color_pre).ok();
-            write!(writer, formatted).ok();
-            writeln!(writer, color_post);
-        }
-    };
-    ($fmt:expr ; color: $color:literal) => {
-        {
-            let formatted = ::alloc::format!($fmt, );
-            let color_pre = match $color {
-                1 => {"\x1b[0;31m"},
-                2 => {"\x1b[0;32m"},
-                3 => {"\x1b[0;33m"},
-                10 => {"\x1b[1;31m"},
-                20 => {"\x1b[1;32m"},
-                30 => {"\x1b[1;33m"},
-                _ => {""},
-            };
-            let color_post = "\x1b[0m";
-            use core::fmt::Write;
-            use alloc::format;
-            let mut writer = $crate::UartWriter;
-            let prefixed = format!("[{:^11}] ", module_path!().split("::").last().unwrap_or("unknown").to_uppercase());
-            write!(writer, "{}", prefixed).ok();
-            write!(writer, "{color_pre}").ok();
-            write!(writer, "{formatted}").ok();
-            writeln!(writer, "{color_post}");
-        }
-    };
-}
-
-/// Print formatted debug string to UART with colored output and DEBUG prefix
+/// Log a formatted debug string, tagged DEBUG (see `klog!`)
 #[macro_export]
 #[macro_use]
 macro_rules! dbg {
     () => {
-        {
-            use core::fmt::Write;
-            let mut writer = $crate::UartWriter;
-            write!(writer, "[   DEBUG   ] \x1b[0;33m\x1b[0m\n").ok();
-        }
+        $crate::klog!($crate::log::Level::Debug, "DEBUG", "")
     };
     ($($arg:tt)*) => {
-        {
-            use core::fmt::Write;
-            let mut writer = $crate::UartWriter;
-            write!(writer, "[   DEBUG   ] \x1b[0;33m").ok();
-            write!(writer, $($arg)*).ok();
-            write!(writer, "\x1b[0m\n").ok();
-        }
+        $crate::klog!($crate::log::Level::Debug, "DEBUG", $($arg)*)
     };
 }
 
@@ -221,31 +158,7 @@ pub extern "C" fn c_serial_println(message: *const c_char) {
         let c_str = CStr::from_ptr(message);
         match c_str.to_str() {
             Ok(str_slice) => serial_println!("{}", str_slice),
-            Err(_) => serial_println!("[  SERIAL   ] \x1b[0;31mError: Invalid UTF-8 string passed from C.\x1b[0m"),
+            Err(_) => klog!(Level::Error, "SERIAL", "Invalid UTF-8 string passed from C."),
         }
     }
 }
-
-/// dbg FFI binding for C (no varargs)
-#[unsafe(no_mangle)]
-pub extern "C" fn c_dbg(message: *const c_char) {
-    unsafe {
-        let c_str = CStr::from_ptr(message);
-        match c_str.to_str() {
-            Ok(str_slice) => dbg!("{}", str_slice),
-            Err(_) => serial_println!("[  SERIAL   ] \x1b[0;31mError: Invalid UTF-8 string passed from C.\x1b[0m"),
-        }
-    }
-}
-
-/// dgb for integers (hex format, u64)
-#[unsafe(no_mangle)]
-pub extern "C" fn c_dgb_hex(hex: u64) {
-    dbg!("{:x}", hex);
-}
-
-/// dgb for integers (bin format, u64)
-#[unsafe(no_mangle)]
-pub extern "C" fn c_dbg_bin(bin: u64) {
-    dbg!("{:b}", bin);
-}
\ No newline at end of file
@@ -67,17 +67,17 @@ int virtio_generic_setup_c(uint64_t virtio_base, uint16_t device_id) {
     uint64_t virtio_bar0 = virtio_base + 0x10;
     
     mmio_write32(virtio_bar0, 0xffffffff);
-    c_dgb_hex(mmio_read32(virtio_bar0));
+    klog_hex(LOG_LEVEL_DEBUG, "VIRTIO", "BAR0 size mask:", mmio_read32(virtio_bar0));
     uint32_t size0 = ~(mmio_read32(virtio_bar0) & ~0xf) + 1;
     
-    c_dgb_hex(size0);
+    klog_hex(LOG_LEVEL_DEBUG, "VIRTIO", "BAR0 size:", size0);
     
     // Allocate 16kb, 16kb-aligned for BAR0 and 16b, 16b-aligned for BAR1
     uint8_t* bar0_ptr = kmalloc_aligned(size0, size0);
     
-    c_dgb_hex((uint64_t)bar0_ptr);
+    klog_hex(LOG_LEVEL_DEBUG, "VIRTIO", "BAR0 at:", (uint64_t)bar0_ptr);
     
-    mmio_write32(virtio_bar0, (uint32_t)bar0_ptr);
+    mmio_write32(virtio_bar0, (uint32_t)virt_to_phys((uint64_t)bar0_ptr));
     uint64_t virtio_mmio_base = (uint64_t)bar0_ptr;
 
     uint64_t status_register = mmio_read64(virtio_mmio_base + 0x06);
@@ -93,7 +93,7 @@ int virtio_generic_setup_c(uint64_t virtio_base, uint16_t device_id) {
     uint32_t device_cfg_offset, device_cfg_len;
     uint8_t timeout = 0;
     while (capabilities_pointer) {
-        c_dbg("iteration");
+        klog(LOG_LEVEL_TRACE, "VIRTIO", "Next capability");
         struct virtio_pci_cap* cap = (struct virtio_pci_cap*)(virtio_mmio_base + capabilities_pointer);
         if (cap->cap_vndr == 0x09) {
             switch (cap->cfg_type) {
@@ -101,25 +101,25 @@ int virtio_generic_setup_c(uint64_t virtio_base, uint16_t device_id) {
                     common_cfg_bar = cap->bar;
                     common_cfg_len = cap->length;
                     common_cfg_offset = cap->offset;
-                    c_dbg("found common cfg");
+                    klog(LOG_LEVEL_DEBUG, "VIRTIO", "Found common cfg");
                 }
                 case VIRTIO_PCI_CAP_NOTIFY_CFG: {
                     notify_cfg_bar = cap->bar;
                     notify_cfg_offset = cap->offset;
                     notify_cfg_len = cap->length;
-                    c_dbg("found notify cfg");
+                    klog(LOG_LEVEL_DEBUG, "VIRTIO", "Found notify cfg");
                 }
                 case VIRTIO_PCI_CAP_ISR_CFG: {
                     isr_cfg_bar = cap->bar;
                     isr_cfg_offset = cap->offset;
                     isr_cfg_len = cap->length;
-                    c_dbg("found isr cfg");
+                    klog(LOG_LEVEL_DEBUG, "VIRTIO", "Found isr cfg");
                 }
                 case VIRTIO_PCI_CAP_DEVICE_CFG: {
                     device_cfg_bar = cap->bar;
                     device_cfg_offset = cap->offset;
                     device_cfg_len = cap->length;
-                    c_dbg("found device cfg");
+                    klog(LOG_LEVEL_DEBUG, "VIRTIO", "Found device cfg");
                 }
                 default: {};
             }
@@ -128,8 +128,9 @@ int virtio_generic_setup_c(uint64_t virtio_base, uint16_t device_id) {
     }
     if (common_cfg_bar == 0) return -2;
 
-    for (uint64_t addr = common_cfg_bar - 0x1000; addr <= common_cfg_bar + 0x1000; addr += GRANULE_4KB) mmu_map_4kb(addr, addr, MAIR_IDX_DEVICE, 1);
-    virtio_pci_common_cfg* common_cfg = (virtio_pci_common_cfg*)(common_cfg_bar + common_cfg_offset);
+    uint64_t cfg_page = (common_cfg_bar & ~(uint64_t)(GRANULE_4KB - 1)) - GRANULE_4KB;
+    paging_map(phys_to_virt(cfg_page), cfg_page, 3 * GRANULE_4KB, PAGE_DEVICE);
+    virtio_pci_common_cfg* common_cfg = (virtio_pci_common_cfg*)phys_to_virt(common_cfg_bar + common_cfg_offset);
 
     // Reset the device
     common_cfg->device_status = RESET;
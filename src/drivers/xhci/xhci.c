@@ -117,17 +117,17 @@ int c_init_xhci() {
     mmio_write32(xhci_bar1, 0xffffffff);
     uint32_t size1 = ~(mmio_read32(xhci_bar1) & ~0xf) + 1;
     
-    c_dgb_hex(size0);
-    c_dgb_hex(size1);
+    klog_hex(LOG_LEVEL_DEBUG, "XHCI", "BAR0 size:", size0);
+    klog_hex(LOG_LEVEL_DEBUG, "XHCI", "BAR1 size:", size1);
     
     // Allocate 16kb, 16kb-aligned for BAR0 and 16b, 16b-aligned for BAR1
     uint8_t* bar0_ptr = kmalloc_aligned(size0, size0);
     uint8_t* bar1_ptr = kmalloc_aligned(size1, size1); // additional registers
     
-    c_dgb_hex((uint64_t)bar0_ptr);
+    klog_hex(LOG_LEVEL_DEBUG, "XHCI", "BAR0 at:", (uint64_t)bar0_ptr);
     
-    mmio_write32(xhci_bar0, (uint32_t)bar0_ptr);
-    mmio_write32(xhci_bar1, (uint32_t)bar1_ptr);
+    mmio_write32(xhci_bar0, (uint32_t)virt_to_phys((uint64_t)bar0_ptr));
+    mmio_write32(xhci_bar1, (uint32_t)virt_to_phys((uint64_t)bar1_ptr));
     
     bool x = pci_enable_device_c(xhci_base);
     if (!x) return -1;
@@ -136,15 +136,16 @@ int c_init_xhci() {
     xhci_op_registers* op = (xhci_op_registers*)bar0_ptr + sizeof(xhci_cap_registers);
     
     while (((op->usbsts) >> 11) & 1) {}
-    c_dbg("CNR0");
+    klog(LOG_LEVEL_DEBUG, "XHCI", "Controller ready (CNR clear)");
     
     uint64_t max_slots = (cap->hcs_params_1) & 0xff;
-    c_dgb_hex(max_slots);
+    klog_hex(LOG_LEVEL_DEBUG, "XHCI", "Max device slots:", max_slots);
     if (!max_slots) return -2;
     op->config |= max_slots;
     
     xhci_dcbaa* dcbaap = (xhci_dcbaa*)kmalloc_aligned(sizeof(xhci_dcbaa), 64);
-    op->dcbaap = (uint64_t)dcbaap;
+    clean_dcache_range((size_t)dcbaap, sizeof(xhci_dcbaa));
+    op->dcbaap = virt_to_phys((uint64_t)dcbaap);
     
     xhci_command_ring cmd = {0};
     for (int i = 0; i < 256; i++) {
@@ -153,8 +154,9 @@ int c_init_xhci() {
         cmd.trb[i]->dw2 = 0;
         cmd.trb[i]->dw3 = 0;
     }
-    cmd.trb[255]->dw01 = (uint64_t)cmd.trb[0];
+    cmd.trb[255]->dw01 = virt_to_phys((uint64_t)cmd.trb[0]);
     cmd.trb[255]->dw3 |= 0b11 << 11 | 0b11; 
+    for (int i = 0; i < 256; i++) clean_dcache_range((size_t)cmd.trb[i], sizeof(xhci_trb_generic));
     
     
     xhci_erst_entry* erst = (xhci_erst_entry*)kmalloc_aligned(sizeof(xhci_erst_entry),64);
@@ -165,16 +167,19 @@ int c_init_xhci() {
         er.trb[i]->dw01 = 0;
         er.trb[i]->dw2 = 0;
         er.trb[i]->dw3 = 0;
+        clean_dcache_range((size_t)er.trb[i], sizeof(xhci_trb_generic));
     }
     
-    erst->pa = (uint64_t)&er;
+    erst->pa = virt_to_phys((uint64_t)&er);
     erst->size = 256;
     erst->reserved = 0;
+    // The controller reads these straight from RAM
+    clean_dcache_range((size_t)erst, sizeof(xhci_erst_entry));
 
     xhci_runtime_registers* runtime = (xhci_runtime_registers*)bar0_ptr + ((cap->rtsoff & 0xfffffff0) >> 4);
     runtime->interrupter.erstsz=1;
-    runtime->interrupter.erstba = (uint64_t)&erst;
-    runtime->interrupter.erdp = (uint64_t)&er | 0b000;
+    runtime->interrupter.erstba = virt_to_phys((uint64_t)&erst);
+    runtime->interrupter.erdp = virt_to_phys((uint64_t)&er) | 0b000;
     runtime->interrupter.iman |= 0b11;
     runtime->interrupter.imod = 0;  
 
@@ -5,19 +5,19 @@
 // Handlers for current el exceptions with sp0
 // These shouldnt happen in normal kernel code, panic
 
-void sync_current_el_sp0_handler() {
+void sync_current_el_sp0_handler(struct TrapFrame *frame) {
     c_panic("SYNC_CURRENT_EL_SP0_HANDLER INVOKED");
 }
 
-void fiq_current_el_sp0_handler() {
+void fiq_current_el_sp0_handler(struct TrapFrame *frame) {
     c_panic("FIQ_CURRENT_EL_SP0_HANDLER INVOKED");
 }
 
-void irq_current_el_sp0_handler() {
+void irq_current_el_sp0_handler(struct TrapFrame *frame) {
     c_panic("IRQ_CURRENT_EL_SP0_HANDLER INVOKED");
 }
 
-void serror_current_el_sp0_handler() {
+void serror_current_el_sp0_handler(struct TrapFrame *frame) {
     c_panic("SERROR_CURRENT_EL_SP0_HANDLER INVOKED");
 }
 
@@ -25,37 +25,37 @@ void serror_current_el_sp0_handler() {
 // Handlers for 64bit userspace exceptions
 // No userspace yet, panic
 
-void sync_lower_el_aarch64_handler() {
+void sync_lower_el_aarch64_handler(struct TrapFrame *frame) {
     c_panic("SYNC_LOWER_EL_AARCH64_HANDLER INVOKED");
 }
 
-void fiq_lower_el_aarch64_handler() {
+void fiq_lower_el_aarch64_handler(struct TrapFrame *frame) {
     c_panic("FIQ_LOWER_EL_AARCH64_HANDLER INVOKED");
 }
 
-void irq_lower_el_aarch64_handler() {
+void irq_lower_el_aarch64_handler(struct TrapFrame *frame) {
     c_panic("IRQ_LOWER_EL_AARCH64_HANDLER INVOKED");
 }
 
-void serror_lower_el_aarch64_handler() {
+void serror_lower_el_aarch64_handler(struct TrapFrame *frame) {
     c_panic("SERROR_LOWER_EL_AARCH64_HANDLER INVOKED");
 }
 
 // Handlers for 32bit userspace exceptions
 // No userspace yet, panic
 
-void sync_lower_el_aarch32_handler() {
+void sync_lower_el_aarch32_handler(struct TrapFrame *frame) {
     c_panic("SYNC_LOWER_EL_AARCH32_HANDLER INVOKED");
 }
 
-void fiq_lower_el_aarch32_handler() {
+void fiq_lower_el_aarch32_handler(struct TrapFrame *frame) {
     c_panic("FIQ_LOWER_EL_AARCH32_HANDLER INVOKED");
 }
 
-void irq_lower_el_aarch32_handler() {
+void irq_lower_el_aarch32_handler(struct TrapFrame *frame) {
     c_panic("IRQ_LOWER_EL_AARCH32_HANDLER INVOKED");
 }
 
-void serror_lower_el_aarch32_handler() {
+void serror_lower_el_aarch32_handler(struct TrapFrame *frame) {
     c_panic("SERROR_LOWER_EL_AARCH32_HANDLER INVOKED");
 }
\ No newline at end of file
@@ -0,0 +1,89 @@
+//! GICv2: memory-mapped distributor and CPU interface.
+
+use core::sync::atomic::{AtomicU8, Ordering};
+
+use crate::{drivers::platform::platform, exceptions::irq::{self, FIRST_SPI, InterruptController, Trigger, gicd}, memory::{layout::phys_to_virt, mmio::{mmio_read32, mmio_write32, mmio_write8}}, smp::{self, MAX_CPUS}};
+
+// Distributor registers
+const GICD_CTLR: u64 = 0x000;
+const GICD_ISENABLER: u64 = 0x100;
+const GICD_ICENABLER: u64 = 0x180;
+const GICD_IPRIORITYR: u64 = 0x400;
+const GICD_ITARGETSR: u64 = 0x800;
+const GICD_ICFGR: u64 = 0xc00;
+const GICD_SGIR: u64 = 0xf00;
+// CPU interface registers
+const GICC_CTLR: u64 = 0x000;
+const GICC_PMR: u64 = 0x004;
+const GICC_IAR: u64 = 0x00c;
+const GICC_EOIR: u64 = 0x010;
+
+/// CPU interface mask of each CPU (ITARGETSR/SGIR bit), by CPU number
+static CPU_MASKS: [AtomicU8; MAX_CPUS] = [const { AtomicU8::new(0) }; MAX_CPUS];
+
+/// GIC CPU interface base address (in the direct map)
+pub fn gicc() -> u64 {
+    phys_to_virt(platform().gicc_base)
+}
+
+pub struct GicV2;
+
+impl InterruptController for GicV2 {
+    fn init(&self) {
+        // Reset
+        mmio_write32(gicd() + GICD_CTLR, 0);
+        for i in 0..0x20 {
+            mmio_write32(gicd() + GICD_ICENABLER + 4*i, 0xffff_ffff);
+        }
+
+        // Enable Distributor
+        let d = mmio_read32(gicd() + GICD_CTLR);
+        mmio_write32(gicd() + GICD_CTLR, d | 1);
+    }
+
+    fn init_cpu(&self) {
+        // The banked ITARGETSR0 reads as the calling CPU's own mask
+        let mask = mmio_read32(gicd() + GICD_ITARGETSR) as u8;
+        CPU_MASKS[smp::cpu_id()].store(mask, Ordering::Relaxed);
+
+        mmio_write32(gicc() + GICC_CTLR, 0);
+
+        // Set priority mask
+        mmio_write8(gicc() + GICC_PMR, 0xff);
+
+        // Enable CPU interface
+        let d = mmio_read32(gicc() + GICC_CTLR);
+        mmio_write32(gicc() + GICC_CTLR, d | 1);
+    }
+
+    fn configure(&self, irq: u32, priority: u8, trigger: Trigger) {
+        mmio_write8(gicd() + GICD_IPRIORITYR + irq as u64, priority);
+        irq::write_trigger(gicd() + GICD_ICFGR, irq, trigger);
+        if irq >= FIRST_SPI {
+            mmio_write8(gicd() + GICD_ITARGETSR + irq as u64, CPU_MASKS[0].load(Ordering::Relaxed));
+        }
+    }
+
+    fn enable(&self, irq: u32) {
+        mmio_write32(gicd() + GICD_ISENABLER + (irq as u64 / 32) * 4, 1 << (irq % 32));
+    }
+
+    fn disable(&self, irq: u32) {
+        mmio_write32(gicd() + GICD_ICENABLER + (irq as u64 / 32) * 4, 1 << (irq % 32));
+    }
+
+    fn acknowledge(&self) -> (u32, u32) {
+        // Bits 10-12 hold the sending CPU of an SGI
+        let iar = mmio_read32(gicc() + GICC_IAR);
+        (iar & 0x3ff, iar)
+    }
+
+    fn end_of_interrupt(&self, ack: u32) {
+        mmio_write32(gicc() + GICC_EOIR, ack);
+    }
+
+    fn send_sgi(&self, sgi: u32, cpu: usize) {
+        let mask = CPU_MASKS[cpu].load(Ordering::Relaxed) as u32;
+        mmio_write32(gicd() + GICD_SGIR, (mask << 16) | (sgi & 0xf));
+    }
+}
@@ -0,0 +1,190 @@
+//! GICv3: distributor with affinity routing, one redistributor per CPU and
+//! the system register CPU interface (`ICC_*_EL1`).
+//!
+//! SGIs and PPIs are configured in the calling CPU's redistributor, SPIs in
+//! the distributor. All interrupts are put in non-secure Group 1.
+
+use core::{arch::asm, sync::atomic::{AtomicU64, Ordering}};
+
+use crate::{drivers::platform::platform, exceptions::irq::{self, FIRST_SPI, InterruptController, Trigger, gicd}, memory::{layout::phys_to_virt, mmio::{mmio_read32, mmio_read64, mmio_write32, mmio_write64, mmio_write8}}, klog, log::Level, smp};
+
+// Distributor registers
+const GICD_CTLR: u64 = 0x0000;
+const GICD_TYPER: u64 = 0x0004;
+const GICD_IGROUPR: u64 = 0x0080;
+const GICD_ISENABLER: u64 = 0x0100;
+const GICD_ICENABLER: u64 = 0x0180;
+const GICD_IPRIORITYR: u64 = 0x0400;
+const GICD_ICFGR: u64 = 0x0c00;
+const GICD_IROUTER: u64 = 0x6000;
+const GICD_CTLR_ENABLE_GRP1: u32 = 1 << 1;
+const GICD_CTLR_ARE: u32 = 1 << 4;
+/// Register write pending (same bit in GICD_CTLR and GICR_CTLR)
+const CTLR_RWP: u32 = 1 << 31;
+
+// Redistributor registers, RD_base frame
+const GICR_CTLR: u64 = 0x0000;
+const GICR_TYPER: u64 = 0x0008;
+const GICR_WAKER: u64 = 0x0014;
+const GICR_TYPER_VLPIS: u64 = 1 << 1;
+const GICR_TYPER_LAST: u64 = 1 << 4;
+const GICR_WAKER_PROCESSOR_SLEEP: u32 = 1 << 1;
+const GICR_WAKER_CHILDREN_ASLEEP: u32 = 1 << 2;
+// Redistributor registers, SGI_base frame
+const GICR_SGI_BASE: u64 = 0x1_0000;
+const GICR_IGROUPR0: u64 = 0x0080;
+const GICR_ICENABLER0: u64 = 0x0180;
+
+/// RD_base + SGI_base, twice that with the GICv4 VLPI frames
+const GICR_STRIDE: u64 = 0x2_0000;
+
+/// Affinity of the boot CPU, which SPIs are routed to
+static BOOT_AFFINITY: AtomicU64 = AtomicU64::new(0);
+
+/// GIC redistributor region base address (in the direct map)
+pub fn gicr() -> u64 {
+    phys_to_virt(platform().gicr_base)
+}
+
+/// MPIDR_EL1 affinity fields of the calling CPU, laid out as in GICD_IROUTER
+fn affinity() -> u64 {
+    let mpidr: u64;
+    unsafe { asm!("mrs {}, mpidr_el1", out(reg) mpidr); }
+    mpidr & 0xff_00ff_ffff
+}
+
+/// RD_base of the calling CPU's redistributor
+fn redistributor() -> Option<u64> {
+    let aff = affinity();
+    // GICR_TYPER packs Aff3.Aff2.Aff1.Aff0 into its upper word
+    let wanted = (aff >> 8 & 0xff00_0000) | (aff & 0xff_ffff);
+    let end = gicr() + platform().gicr_size;
+    let mut rd = gicr();
+    while rd < end {
+        let typer = mmio_read64(rd + GICR_TYPER);
+        if typer >> 32 == wanted {
+            return Some(rd);
+        }
+        if typer & GICR_TYPER_LAST != 0 {
+            break;
+        }
+        rd += if typer & GICR_TYPER_VLPIS != 0 { 2 * GICR_STRIDE } else { GICR_STRIDE };
+    }
+    None
+}
+
+/// Registers of `irq`: the calling CPU's SGI frame for SGIs and PPIs, the
+/// distributor for SPIs. The SGI frame uses the distributor's offsets.
+fn banked_base(irq: u32) -> Option<u64> {
+    if irq < FIRST_SPI {
+        redistributor().map(|rd| rd + GICR_SGI_BASE)
+    } else {
+        Some(gicd())
+    }
+}
+
+fn wait_for_rwp(ctlr: u64) {
+    while mmio_read32(ctlr) & CTLR_RWP != 0 {}
+}
+
+pub struct GicV3;
+
+impl InterruptController for GicV3 {
+    fn init(&self) {
+        mmio_write32(gicd() + GICD_CTLR, 0);
+        wait_for_rwp(gicd() + GICD_CTLR);
+
+        // Disable every SPI and put it in Group 1
+        let lines = ((mmio_read32(gicd() + GICD_TYPER) & 0x1f) + 1) * 32;
+        for i in (FIRST_SPI..lines.min(irq::MAX_IRQS as u32)).step_by(32) {
+            let reg = (i / 32) as u64 * 4;
+            mmio_write32(gicd() + GICD_ICENABLER + reg, 0xffff_ffff);
+            mmio_write32(gicd() + GICD_IGROUPR + reg, 0xffff_ffff);
+        }
+        wait_for_rwp(gicd() + GICD_CTLR);
+
+        // Enable Distributor with affinity routing. With a single security
+        // state these are ARE and EnableGrp1, otherwise ARE_NS and
+        // EnableGrp1NS: the same bits.
+        mmio_write32(gicd() + GICD_CTLR, GICD_CTLR_ARE | GICD_CTLR_ENABLE_GRP1);
+        wait_for_rwp(gicd() + GICD_CTLR);
+
+        BOOT_AFFINITY.store(affinity(), Ordering::Relaxed);
+    }
+
+    fn init_cpu(&self) {
+        let Some(rd) = redistributor() else {
+            klog!(Level::Error, "IRQ", "No GICv3 redistributor for this CPU");
+            return;
+        };
+
+        // Wake the redistributor
+        let waker = mmio_read32(rd + GICR_WAKER);
+        mmio_write32(rd + GICR_WAKER, waker & !GICR_WAKER_PROCESSOR_SLEEP);
+        while mmio_read32(rd + GICR_WAKER) & GICR_WAKER_CHILDREN_ASLEEP != 0 {}
+
+        // Disable SGIs and PPIs and put them in Group 1
+        let sgi = rd + GICR_SGI_BASE;
+        mmio_write32(sgi + GICR_ICENABLER0, 0xffff_ffff);
+        wait_for_rwp(rd + GICR_CTLR);
+        mmio_write32(sgi + GICR_IGROUPR0, 0xffff_ffff);
+
+        unsafe {
+            // Use the system register interface instead of the GICC frame
+            let mut sre: u64;
+            asm!("mrs {}, icc_sre_el1", out(reg) sre);
+            sre |= 1;
+            asm!("msr icc_sre_el1, {}", "isb", in(reg) sre);
+
+            // Set priority mask, no preemption grouping, enable Group 1
+            asm!("msr icc_pmr_el1, {}", in(reg) 0xffu64);
+            asm!("msr icc_bpr1_el1, {}", in(reg) 0u64);
+            asm!("msr icc_igrpen1_el1, {}", "isb", in(reg) 1u64);
+        }
+    }
+
+    fn configure(&self, irq: u32, priority: u8, trigger: Trigger) {
+        let Some(base) = banked_base(irq) else { return; };
+        mmio_write8(base + GICD_IPRIORITYR + irq as u64, priority);
+        irq::write_trigger(base + GICD_ICFGR, irq, trigger);
+        if irq >= FIRST_SPI {
+            mmio_write64(gicd() + GICD_IROUTER + irq as u64 * 8, BOOT_AFFINITY.load(Ordering::Relaxed));
+        }
+    }
+
+    fn enable(&self, irq: u32) {
+        if let Some(base) = banked_base(irq) {
+            mmio_write32(base + GICD_ISENABLER + (irq as u64 / 32) * 4, 1 << (irq % 32));
+        }
+    }
+
+    fn disable(&self, irq: u32) {
+        if let Some(base) = banked_base(irq) {
+            mmio_write32(base + GICD_ICENABLER + (irq as u64 / 32) * 4, 1 << (irq % 32));
+        }
+    }
+
+    fn acknowledge(&self) -> (u32, u32) {
+        let iar: u64;
+        unsafe { asm!("mrs {}, icc_iar1_el1", out(reg) iar); }
+        let irq = (iar & 0xff_ffff) as u32;
+        (irq, irq)
+    }
+
+    fn end_of_interrupt(&self, ack: u32) {
+        unsafe { asm!("msr icc_eoir1_el1, {}", in(reg) ack as u64); }
+    }
+
+    fn send_sgi(&self, sgi: u32, cpu: usize) {
+        // Target list of 16 CPUs within Aff3.Aff2.Aff1, picked by RS
+        let aff = smp::percpu(cpu).mpidr;
+        let aff0 = aff & 0xff;
+        let sgi1r = (1 << (aff0 % 16))
+            | ((aff >> 8 & 0xff) << 16)     // Aff1
+            | ((sgi as u64 & 0xf) << 24)
+            | ((aff >> 16 & 0xff) << 32)    // Aff2
+            | ((aff0 / 16) << 44)           // RS
+            | ((aff >> 32 & 0xff) << 48);   // Aff3
+        unsafe { asm!("msr icc_sgi1r_el1, {}", "isb", in(reg) sgi1r); }
+    }
+}
@@ -1,42 +1,96 @@
 use core::arch::asm;
 
-use crate::{memory::mmio::{mmio_read32, mmio_read64, mmio_write32, mmio_write64, mmio_write8}, TIMER};
+use crate::{drivers::platform::platform, klog, log::Level, exceptions::{gicv2::GicV2, gicv3::GicV3}, memory::{layout::phys_to_virt, mmio::{mmio_read32, mmio_write32}}, smp, thread::scheduler, time::{self, Instant}, timer};
 
-pub const GICD: usize = 0x08000000;
-pub const GICC: usize = 0x08010000;
+/// Interrupt IDs the GIC can deliver (1020 and up are special)
+pub const MAX_IRQS: usize = 1020;
+/// Read from the IAR when there is nothing (left) to acknowledge
+pub const SPURIOUS_IRQ: u32 = 1023;
+/// First shared peripheral interrupt: lower IDs are per CPU
+pub(super) const FIRST_SPI: u32 = 32;
 
-pub fn gic_init() {
-    // Reset
-    mmio_write32(GICD as u64 + 0x000, 0);
-    for i in (0..0x60) {
-        mmio_write32(GICD as u64 + 0x100 + 4*i, 0);
-    }
-    mmio_write32(GICC as u64 + 0x000, 0);
-    mmio_write8(GICC as u64 + 0x004, 0);
+/// Non-secure EL1 physical timer (PPI 14)
+pub const TIMER_IRQ: u32 = 30;
+/// Priority of the timer tick (lower is more urgent)
+pub const PRIORITY_TIMER: u8 = 0x80;
+/// Priority for device interrupts without special needs
+pub const PRIORITY_DEFAULT: u8 = 0xa0;
+
+/// Handler for one interrupt line, called with its ID. Runs with IRQs masked
+/// and must neither block nor allocate.
+pub type IrqHandler = fn(irq: u32);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Trigger {
+    Level,
+    Edge,
+}
 
-    // Enable Distributor
-    let mut d = mmio_read32(GICD as u64);
-    d |= 1;
-    mmio_write32(GICD as u64, d);
+/// The GIC operations the dispatch code needs, implemented by the GICv2
+/// (`gicv2`) and GICv3 (`gicv3`) drivers
+pub trait InterruptController: Sync {
+    /// Reset and enable the distributor, once, on the boot CPU
+    fn init(&self);
+    /// Enable the calling CPU's interface to the GIC
+    fn init_cpu(&self);
+    /// Set the priority and trigger mode of `irq`. SPIs are sent to the boot CPU.
+    fn configure(&self, irq: u32, priority: u8, trigger: Trigger);
+    fn enable(&self, irq: u32);
+    fn disable(&self, irq: u32);
+    /// Acknowledge the highest priority pending interrupt. Returns its ID and
+    /// the value to hand back to `end_of_interrupt`.
+    fn acknowledge(&self) -> (u32, u32);
+    fn end_of_interrupt(&self, ack: u32);
+    /// Raise software generated interrupt `sgi` (0-15) on CPU `cpu`
+    fn send_sgi(&self, sgi: u32, cpu: usize);
+}
 
-    // Enable timer interrupt (id: 30)
-    enable_interrupt(30);
+static HANDLERS: spin::Mutex<[Option<IrqHandler>; MAX_IRQS]> = spin::Mutex::new([None; MAX_IRQS]);
 
-    // Enable uart interrupt (id: 33)
-    enable_interrupt(33);
+/// The GIC driver, chosen by `gic_init` from the probed GIC version
+static mut CONTROLLER: &dyn InterruptController = &GicV2;
 
-    // Set priority mask
-    mmio_write8(GICC as u64 + 0x4, 0xff);
+fn controller() -> &'static dyn InterruptController {
+    unsafe { CONTROLLER }
+}
 
-    // Enable CPU interface
-    let mut d = mmio_read32(GICC as u64);
-    d |= 1;
-    mmio_write32(GICC as u64, d);
+/// GIC distributor base address (in the direct map)
+pub fn gicd() -> u64 {
+    phys_to_virt(platform().gicd_base)
+}
+
+/// Set the trigger mode of `irq` in the ICFGR array at `icfgr`. The trigger
+/// mode of SGIs is fixed and left alone.
+pub(super) fn write_trigger(icfgr: u64, irq: u32, trigger: Trigger) {
+    if irq < 16 {
+        return;
+    }
+    let reg = icfgr + (irq as u64 / 16) * 4;
+    let bit = 1 << (irq % 16 * 2 + 1);
+    let cfg = mmio_read32(reg);
+    mmio_write32(reg, if trigger == Trigger::Edge { cfg | bit } else { cfg & !bit });
+}
+
+pub fn gic_init() {
+    unsafe {
+        CONTROLLER = match platform().gic_version {
+            3 => &GicV3,
+            _ => &GicV2,
+        };
+    }
+    controller().init();
+    controller().init_cpu();
+    klog!(Level::Info, "IRQ", "GICv{} initialized", platform().gic_version);
 
     // Unmask interrupts
     unsafe { asm!("msr daifclr, #2") };
 }
 
+/// Enable the calling secondary CPU's interface to the GIC
+pub fn init_cpu() {
+    controller().init_cpu();
+}
+
 pub fn enable_timer() {
     // Read CNTFRQ_EL0
     let cntfrq: usize;
@@ -51,25 +105,105 @@ pub fn enable_timer() {
         asm!("orr x0, x0, #0b01");
         asm!("msr cntp_ctl_el0, x0");
     }
+
+    // One handler for all CPUs, the PPI itself is enabled on each
+    let result = match smp::cpu_id() {
+        0 => register_handler(TIMER_IRQ, tick_timer, PRIORITY_TIMER, Trigger::Level),
+        _ => enable_percpu(TIMER_IRQ, PRIORITY_TIMER, Trigger::Level),
+    };
+    if let Err(e) = result {
+        klog!(Level::Error, "IRQ", "{}", e);
+    }
 }
 
-pub fn tick_timer() {
-    unsafe {
-        TIMER += 1;
-        let cntfrq: usize;
-        asm!("mrs {}, cntfrq_el0", out(reg) cntfrq);
-        let cval0: usize;
-        asm!("mrs {}, cntp_cval_el0", out(reg) cval0);
-        let cval = cval0 + (cntfrq/1000);
-        asm!("msr cntp_cval_el0, {}", in(reg) cval);
-        
+/// Timer interrupt (on every CPU): run the due timers, charge the running
+/// thread for the tick and program the next interrupt.
+fn tick_timer(_irq: u32) {
+    timer::tick(Instant::now());
+    scheduler::tick();
+    time::arm_next_tick();
+}
+
+/// Route interrupt `irq` to `handler` and enable it. SPIs are sent to the
+/// boot CPU; the trigger mode of SGIs is fixed and left alone.
+pub fn register_handler(irq: u32, handler: IrqHandler, priority: u8, trigger: Trigger) -> Result<(), &'static str> {
+    if irq as usize >= MAX_IRQS {
+        return Err("[    IRQ    ] \x1b[1;31mInterrupt ID out of range\x1b[0m");
+    }
+
+    without_interrupts(|| {
+        let mut handlers = HANDLERS.lock();
+        if handlers[irq as usize].is_some() {
+            return Err("[    IRQ    ] \x1b[1;31mInterrupt already has a handler\x1b[0m");
+        }
+        handlers[irq as usize] = Some(handler);
+
+        controller().configure(irq, priority, trigger);
+        enable_interrupt(irq as u64);
+        Ok(())
+    })
+}
+
+/// Enable SGI or PPI `irq`, whose handler is already registered, on the
+/// calling CPU as well. They are banked per CPU, `register_handler` only
+/// enables them on the CPU it runs on.
+pub fn enable_percpu(irq: u32, priority: u8, trigger: Trigger) -> Result<(), &'static str> {
+    if irq >= FIRST_SPI {
+        return Err("[    IRQ    ] \x1b[1;31mNot a per-CPU interrupt\x1b[0m");
     }
+    without_interrupts(|| {
+        if HANDLERS.lock()[irq as usize].is_none() {
+            return Err("[    IRQ    ] \x1b[1;31mInterrupt has no handler\x1b[0m");
+        }
+        controller().configure(irq, priority, trigger);
+        controller().enable(irq);
+        Ok(())
+    })
+}
+
+/// Raise SGI `sgi` on CPU `cpu`. Memory written before is visible to its
+/// handler.
+pub fn send_sgi(sgi: u32, cpu: usize) {
+    unsafe { asm!("dsb ishst"); }
+    controller().send_sgi(sgi, cpu);
+}
+
+/// Disable interrupt `irq` and forget its handler
+pub fn unregister_handler(irq: u32) {
+    if irq as usize >= MAX_IRQS {
+        return;
+    }
+    without_interrupts(|| {
+        controller().disable(irq);
+        HANDLERS.lock()[irq as usize] = None;
+    });
+}
+
+/// Acknowledge the pending interrupt, run its handler and signal the end of
+/// it. Spurious interrupts are not acknowledged, so get no EOI either.
+pub fn handle_irq() {
+    let (irq, ack) = controller().acknowledge();
+    if irq == SPURIOUS_IRQ {
+        return;
+    }
+
+    let handler = HANDLERS.lock().get(irq as usize).copied().flatten();
+    match handler {
+        Some(handler) => handler(irq),
+        None => klog!(Level::Warn, "IRQ", "Unhandled interrupt {}", irq),
+    }
+    controller().end_of_interrupt(ack);
 }
 
 pub fn enable_interrupt(irq_num: u64) {
-    let reg = 0x100 + (irq_num/32)*4;
-    let bit = irq_num % 32;
-    let mut r = mmio_read32(GICD as u64 + reg);
-    r |= 1 << bit;
-    mmio_write32(GICD as u64 + reg, r);
-} 
\ No newline at end of file
+    controller().enable(irq_num as u32);
+} 
+/// Run `f` with IRQs masked, restoring the previous mask afterwards.
+/// Needed around anything an interrupt handler may also lock.
+pub fn without_interrupts<R>(f: impl FnOnce() -> R) -> R {
+    let daif: u64;
+    unsafe { asm!("mrs {}, daif", "msr daifset, #2", out(reg) daif); }
+    let result = f();
+    unsafe { asm!("msr daif, {}", in(reg) daif); }
+    result
+}
@@ -1,6 +1,6 @@
 use core::{arch::asm, panic};
 
-use crate::{dbg, drivers::uart::uart_irq_handler, exceptions::irq::{GICC, tick_timer}, memory::mmio::{mmio_read32, mmio_write32}, serial_println, serial_println_prefixed};
+use crate::{backtrace::{self, Symbol}, memory::{paging::dump_walk, vma::{self, Fault}}, klog, log::Level, serial_println, thread::{SVC_YIELD, scheduler}};
 
 pub unsafe fn set_exception_vectors() {
     unsafe extern "C" { static exception_vectors: [u8; 0]; }
@@ -18,36 +18,32 @@ pub unsafe fn set_exception_vectors() {
         options(nostack, preserves_flags)
     );
 
-    serial_println_prefixed!("Exception Vectors set." ; color: 20);
+    klog!(Level::Info, "EXCEPTIONS", "Exception Vectors set.");
 }
 
-#[derive(Debug,Copy,Clone)]
+/// Exception level the CPU runs at. The boot code drops to EL1 from any
+/// higher EL, so this is always 1.
+pub fn current_el() -> u64 {
+    let el: u64;
+    unsafe { asm!("mrs {}, CurrentEL", out(reg) el); }
+    el >> 2 & 0b11
+}
+
+/// Register state saved on exception entry by `exception_vectors_as.sx`.
+/// Everything but ESR and FAR is restored from it on return, so handlers can
+/// change the registers, the return address or the saved PSTATE.
+#[derive(Debug, Clone, Copy)]
 #[repr(C)]
-pub struct InterruptFrame {
-    x0: u64,
-    x1: u64,
-    x2: u64,
-    x3: u64,
-    x4: u64,
-    x5: u64,
-    x6: u64,
-    x7: u64,
-    x8: u64,
-    x9: u64,
-    x10: u64,
-    x11: u64,
-    x12: u64,
-    x13: u64,
-    x14: u64,
-    x15: u64,
-    x16: u64,
-    x17: u64,
-    x18: u64,
-    x29: u64,
-    x30: u64,
-    elr: u64,
-    esr: u64, 
-    far: u64,
+pub struct TrapFrame {
+    /// x0 to x30
+    pub x: [u64; 31],
+    pub sp_el0: u64,
+    /// Return address
+    pub elr: u64,
+    /// PSTATE to return to
+    pub spsr: u64,
+    pub esr: u64,
+    pub far: u64,
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -155,6 +151,11 @@ impl DataAbortInfo {
         }
     }
 
+    /// Nothing was mapped at the address (as opposed to a permission fault)
+    fn is_translation_fault(&self) -> bool {
+        self.fault_status_code & 0x3C == 0x04
+    }
+
     fn get_fault_type(&self) -> &'static str {
         let fsc = self.fault_status_code;
         match fsc & 0x3C {
@@ -198,12 +199,29 @@ impl DataAbortInfo {
 }
 
 #[unsafe(no_mangle)]
-pub unsafe extern "C" fn sync_current_el_spx_handler(frame: *mut InterruptFrame) {
-    let frame = *frame;
-    serial_println!("[ EXCEPTION ] Synchronous exception occured, ELR: 0x{:x}, ESR: 0x{:x}, FAR: 0x{:x}", frame.elr, frame.esr, frame.far);
+pub extern "C" fn sync_current_el_spx_handler(frame: &mut TrapFrame) -> *mut TrapFrame {
+    let esr_info = EsrInfo::parse(frame.esr);
+
+    if let ExceptionClass::SvcAarch64 = esr_info.exception_class && esr_info.instruction_specific_syndrome & 0xffff == SVC_YIELD as u32 {
+        return scheduler::schedule(frame);
+    }
+
+    // Demand paging: faults on lazily backed areas are resolved quietly, the
+    // rest are reported below
+    let fault = if let ExceptionClass::DataAbortLowerEL | ExceptionClass::DataAbortSameEL = esr_info.exception_class {
+        let abort_info = DataAbortInfo::parse_data_abort_iss(esr_info.instruction_specific_syndrome);
+        match vma::handle_fault(frame.far, abort_info.is_translation_fault()) {
+            Fault::Resolved => return frame,
+            fault => Some(fault),
+        }
+    } else {
+        None
+    };
+
+    backtrace::set_exception_frame(frame.elr, frame.x[29]);
+    serial_println!("[ EXCEPTION ] Synchronous exception occured, ELR: 0x{:x} <{}>, ESR: 0x{:x}, FAR: 0x{:x}", frame.elr, Symbol(frame.elr), frame.esr, frame.far);
     serial_println!("[ EXCEPTION ] Attempting to parse exception...");
     
-    let esr_info = EsrInfo::parse(frame.esr);
     match esr_info.exception_class {
         ExceptionClass::DataAbortLowerEL | ExceptionClass::DataAbortSameEL => {
             let abort_info = DataAbortInfo::parse_data_abort_iss(esr_info.instruction_specific_syndrome);
@@ -214,7 +232,7 @@ pub unsafe extern "C" fn sync_current_el_spx_handler(frame: *mut InterruptFrame)
             serial_println!("[ EXCEPTION ] Fault type: {}", abort_info.get_fault_type());
             serial_println!("[ EXCEPTION ] Access: {}", if abort_info.write_not_read {"Write"} else {"Read"});
 
-            handle_page_fault(fault_addr, fault_pc, abort_info.write_not_read);
+            handle_page_fault(fault_addr, fault_pc, &abort_info, fault);
         },
 
         ExceptionClass::InstructionAbortLowerEL | ExceptionClass::InstructionAbortSameEL => {
@@ -240,98 +258,72 @@ pub unsafe extern "C" fn sync_current_el_spx_handler(frame: *mut InterruptFrame)
     }
 }
 
-fn handle_page_fault(fault_addr: u64, fault_pc: u64, access: bool) {
-    unimplemented!()
+/// Report a data abort the VMA list could not resolve (`fault` is what
+/// `vma::handle_fault` made of it), then panic.
+fn handle_page_fault(fault_addr: u64, fault_pc: u64, abort_info: &DataAbortInfo, fault: Option<Fault>) -> ! {
+    if let Some(Fault::StackOverflow(owner)) = fault {
+        panic!("Fatal: stack overflow in thread {} (guard page hit at {:#x}, PC {:#x})", owner, fault_addr, fault_pc);
+    }
+
+    match fault {
+        Some(Fault::Invalid(Some(area))) => serial_println!("[ EXCEPTION ] Area: {} [{:#x}, {:#x}) {:?}, page flags {:#x}", area.name, area.start, area.end, area.kind, area.flags.bits()),
+        _ => serial_println!("[ EXCEPTION ] Area: none registered"),
+    }
+    serial_println!("[ EXCEPTION ] ISS: valid {}, s1ptw {}, cache maintenance {}, access size {} bytes", abort_info.valid, abort_info.s1ptw, abort_info.cache_maintenance, 1 << abort_info.access_size);
+    dump_walk(fault_addr);
+
+    panic!("Fatal: {} {} at {:#x} (PC {:#x})", abort_info.get_fault_type(), if abort_info.write_not_read {"writing"} else {"reading"}, fault_addr, fault_pc);
 }
 
-fn handle_instruction_abort(fault_addr: u64) {
+fn handle_instruction_abort(fault_addr: u64) -> ! {
+    dump_walk(fault_addr);
     panic!("Fatal: instruction abort occured at 0x{:x}", fault_addr);
 }
 
 #[unsafe(no_mangle)]
-pub extern "C" fn interrupt_handler() {
-    let irq_id = mmio_read32(GICC as u64 + 0xc);
-
-    match irq_id {
-        30 => {tick_timer();},
-        33 => {uart_irq_handler();}
-        _ => {
-            dbg!("unknown interrupt");
-            mmio_write32(GICC as u64 + 0x10, irq_id);
-        }
-    }
-    mmio_write32(GICC as u64 + 0x10, irq_id);
+pub extern "C" fn interrupt_handler(frame: &mut TrapFrame) -> *mut TrapFrame {
+    irq::handle_irq();
+    // Handlers may have woken threads or used up the time slice
+    scheduler::preempt(frame)
 }
 
 #[unsafe(no_mangle)]
-pub unsafe extern "C" fn serror_current_el_spx_handler() {
-    let mut esr: usize;
-    let mut elr: usize; 
-    let mut far: usize;
-    let mut gprs: [usize; 31] = [0; 31];
-    let mut sp: usize;
-    let mut sctlr: usize;
-    let mut ttbr0: usize;
-    let mut ttbr1: usize;
-    let mut tcr: usize;
-
-    asm!("mrs {}, ESR_EL1", out(reg) esr);
-    asm!("mrs {}, ELR_EL1", out(reg) elr);
-    asm!("mrs {}, FAR_EL1", out(reg) far);
-    asm!("mov {}, sp", out(reg) sp);
-    asm!("mrs {}, SCTLR_EL1", out(reg) sctlr);
-    asm!("mrs {}, TTBR0_EL1", out(reg) ttbr0);
-    asm!("mrs {}, TTBR1_EL1", out(reg) ttbr1);
-    asm!("mrs {}, TCR_EL1", out(reg) tcr);
-    asm!("mov {}, x0", out(reg) gprs[0]);
-    asm!("mov {}, x1", out(reg) gprs[1]);
-    asm!("mov {}, x2", out(reg) gprs[2]);
-    asm!("mov {}, x3", out(reg) gprs[3]);
-    asm!("mov {}, x4", out(reg) gprs[4]);
-    asm!("mov {}, x5", out(reg) gprs[5]);
-    asm!("mov {}, x6", out(reg) gprs[6]);
-    asm!("mov {}, x7", out(reg) gprs[7]);
-    asm!("mov {}, x8", out(reg) gprs[8]);
-    asm!("mov {}, x9", out(reg) gprs[9]);
-    asm!("mov {}, x10", out(reg) gprs[10]);
-    asm!("mov {}, x11", out(reg) gprs[11]);
-    asm!("mov {}, x12", out(reg) gprs[12]);
-    asm!("mov {}, x13", out(reg) gprs[13]);
-    asm!("mov {}, x14", out(reg) gprs[14]);
-    asm!("mov {}, x15", out(reg) gprs[15]);
-    asm!("mov {}, x16", out(reg) gprs[16]);
-    asm!("mov {}, x17", out(reg) gprs[17]);
-    asm!("mov {}, x18", out(reg) gprs[18]);
-    asm!("mov {}, x19", out(reg) gprs[19]);
-    asm!("mov {}, x20", out(reg) gprs[20]);
-    asm!("mov {}, x21", out(reg) gprs[21]);
-    asm!("mov {}, x22", out(reg) gprs[22]);
-    asm!("mov {}, x23", out(reg) gprs[23]);
-    asm!("mov {}, x24", out(reg) gprs[24]);
-    asm!("mov {}, x25", out(reg) gprs[25]);
-    asm!("mov {}, x26", out(reg) gprs[26]);
-    asm!("mov {}, x27", out(reg) gprs[27]);
-    asm!("mov {}, x28", out(reg) gprs[28]);
-    asm!("mov {}, x29", out(reg) gprs[29]);
-    asm!("mov {}, x30", out(reg) gprs[30]);
+pub extern "C" fn serror_current_el_spx_handler(frame: &mut TrapFrame) {
+    let sctlr: usize;
+    let ttbr0: usize;
+    let ttbr1: usize;
+    let tcr: usize;
+
+    unsafe {
+        asm!("mrs {}, SCTLR_EL1", out(reg) sctlr);
+        asm!("mrs {}, TTBR0_EL1", out(reg) ttbr0);
+        asm!("mrs {}, TTBR1_EL1", out(reg) ttbr1);
+        asm!("mrs {}, TCR_EL1", out(reg) tcr);
+    }
+    // The frame sits right below the interrupted stack pointer
+    let sp = frame as *const TrapFrame as usize + size_of::<TrapFrame>();
 
+    backtrace::set_exception_frame(frame.elr, frame.x[29]);
     serial_println!("[ EXCEPTION ] FATAL: SYSTEM ERROR OCCURED");
     serial_println!("[ EXCEPTION ] REGISTER DUMP:");
     serial_println!("[ EXCEPTION ]");
     serial_println!("[ EXCEPTION ] -- SYSTEM REGISTERS --");
-    serial_println!("[ EXCEPTION ] ESR: 0x{:X}, ELR: 0x{:X}, FAR: 0x{:X}", esr, elr, far);
+    serial_println!("[ EXCEPTION ] ESR: 0x{:X}, ELR: 0x{:X} <{}>, FAR: 0x{:X}", frame.esr, frame.elr, Symbol(frame.elr), frame.far);
+    serial_println!("[ EXCEPTION ] SPSR: 0x{:X}", frame.spsr);
     serial_println!("[ EXCEPTION ] SCTLR: 0x{:X}", sctlr);
     serial_println!("[ EXCEPTION ] TTBR0: 0x{:X}, TTBR1: 0x{:X}", ttbr0, ttbr1);
     serial_println!("[ EXCEPTION ] TCR: 0x{:X}", tcr);
-    serial_println!("[ EXCEPTION ] SP: 0x{:X}", sp);
+    serial_println!("[ EXCEPTION ] SP: 0x{:X}, SP_EL0: 0x{:X}", sp, frame.sp_el0);
     serial_println!("[ EXCEPTION ]");
     serial_println!("[ EXCEPTION ] -- GENERAL PURPOSE REGISTERS --");
-    for i in 0..31 {
-        serial_println!("[ EXCEPTION ] X{}: 0x{:X}", i, gprs[i]);
+    for (i, x) in frame.x.iter().enumerate() {
+        serial_println!("[ EXCEPTION ] X{}: 0x{:X}", i, x);
     }
 
     panic!("Fatal: System Error Occured.");
 
 }
 
-pub mod irq;
\ No newline at end of file
+pub mod irq;
+pub mod gicv2;
+pub mod gicv3;
\ No newline at end of file
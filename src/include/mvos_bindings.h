@@ -9,26 +9,37 @@
 
 #define BROWN 11162880
 
+/**
+ * Longest command line kept, longer ones are cut short
+ */
+#define CMDLINE_SIZE 512
+
 #define CYAN 43690
 
 #define DBG_YELLOW 14402339
 
 #define D_GRAY 5592405
 
+/**
+ * Size of the direct map (128TiB)
+ */
+#define DIRECT_MAP_SIZE 140737488355328
+
 #define ERROR_RED 14483456
 
 #define FAIL_RED 11141120
 
 #define GENERIC_WHITE 16777215
 
-#define GICC 134283264
-
-#define GICD 134217728
-
 #define GREEN 43520
 
 #define INFO_GREEN 4308232
 
+/**
+ * Start of the upper half, translated through the kernel's TTBR1 tables
+ */
+#define KERNEL_SPACE_START 18446462598732840960
+
 #define L_BLUE 5592575
 
 #define L_CYAN 5636095
@@ -43,8 +54,60 @@
 
 #define MAGENTA 11141290
 
+/**
+ * MAIR attribute indices
+ */
+#define MAIR_IDX_DEVICE 0
+
+#define MAIR_IDX_NORMAL 1
+
+/**
+ * Most CPUs brought up, the boot CPU included
+ */
+#define MAX_CPUS 16
+
+/**
+ * Interrupt IDs the GIC can deliver (1020 and up are special)
+ */
+#define MAX_IRQS 1020
+
+/**
+ * Most RAM banks kept in the memory map
+ */
+#define MAX_MEMORY_REGIONS 16
+
+/**
+ * Device registers, EL1 only, never executable
+ */
+#define PAGE_DEVICE 27021597764222976
+
+/**
+ * Kernel data: normal memory, EL1 read/write, never executable
+ */
+#define PAGE_KERNEL_DATA 27021597764223748
+
+/**
+ * Kernel text: normal memory, EL1 read/write/execute
+ */
+#define PAGE_KERNEL_RWX 18014398509482756
+
 #define PANIC_RED 16711680
 
+/**
+ * Start of the direct map of physical memory
+ */
+#define PHYS_OFFSET 18446462598732840960
+
+/**
+ * Priority for device interrupts without special needs
+ */
+#define PRIORITY_DEFAULT 160
+
+/**
+ * Priority of the timer tick (lower is more urgent)
+ */
+#define PRIORITY_TIMER 128
+
 #define RED 11141120
 
 #define SCALE 1
@@ -53,40 +116,161 @@
 
 #define SCREENWIDTH 1280
 
+/**
+ * Read from the IAR when there is nothing (left) to acknowledge
+ */
+#define SPURIOUS_IRQ 1023
+
 #define SUCCESS_GREEN 65280
 
+/**
+ * Start of the window kernel thread stacks are mapped in
+ */
+#define THREAD_STACKS_START 18446603340516163584
+
+/**
+ * Non-secure EL1 physical timer (PPI 14)
+ */
+#define TIMER_IRQ 30
+
+/**
+ * Receive interrupt of the PL011 (SPI 1 on QEMU virt)
+ */
+#define UART_IRQ 33
+
+/**
+ * End of the lower half: user address spaces live below this
+ */
+#define USER_SPACE_END 281474976710656
+
 #define WARNING_ORANGE 15900431
 
 #define WHITE 16777215
 
 #define YELLOW 16777045
 
-typedef struct InterruptFrame {
-    uint64_t x0;
-    uint64_t x1;
-    uint64_t x2;
-    uint64_t x3;
-    uint64_t x4;
-    uint64_t x5;
-    uint64_t x6;
-    uint64_t x7;
-    uint64_t x8;
-    uint64_t x9;
-    uint64_t x10;
-    uint64_t x11;
-    uint64_t x12;
-    uint64_t x13;
-    uint64_t x14;
-    uint64_t x15;
-    uint64_t x16;
-    uint64_t x17;
-    uint64_t x18;
-    uint64_t x29;
-    uint64_t x30;
+/**
+ * How much a record matters, least first
+ */
+enum LogLevel {
+    LOG_LEVEL_TRACE = 0,
+    LOG_LEVEL_DEBUG = 1,
+    LOG_LEVEL_INFO = 2,
+    LOG_LEVEL_WARN = 3,
+    LOG_LEVEL_ERROR = 4,
+};
+typedef uint8_t LogLevel;
+
+/**
+ * A contiguous (address, size) region.
+ */
+typedef struct DtRegion {
+    uint64_t address;
+    uint64_t size;
+} DtRegion;
+
+typedef struct BootInfo {
+    /**
+     * Device tree blob (direct map address), null if the loader passed none
+     */
+    const uint8_t *dtb;
+    /**
+     * Exception level the loader entered the kernel at
+     */
+    uint64_t boot_el;
+    /**
+     * Physical address the loader put the kernel at. The kernel runs at its
+     * link address, it moved itself there if the two differ.
+     */
+    uint64_t load_addr;
+    struct DtRegion memory[MAX_MEMORY_REGIONS];
+    size_t memory_regions;
+    uint8_t cmdline[CMDLINE_SIZE];
+    size_t cmdline_len;
+} BootInfo;
+
+typedef struct PlatformInfo {
+    /**
+     * PL011 UART registers
+     */
+    uint64_t uart_base;
+    /**
+     * GIC distributor registers
+     */
+    uint64_t gicd_base;
+    uint64_t gicd_size;
+    /**
+     * GIC CPU interface registers (GICv2 only)
+     */
+    uint64_t gicc_base;
+    uint64_t gicc_size;
+    /**
+     * GIC redistributors, one frame per CPU (GICv3 only)
+     */
+    uint64_t gicr_base;
+    uint64_t gicr_size;
+    /**
+     * GIC architecture version, 2 or 3
+     */
+    uint32_t gic_version;
+    /**
+     * PCIe ECAM configuration space
+     */
+    uint64_t pci_ecam_base;
+    uint64_t pci_ecam_size;
+    /**
+     * QEMU fw_cfg MMIO interface
+     */
+    uint64_t fw_cfg_base;
+    /**
+     * PL031 real-time clock
+     */
+    uint64_t rtc_base;
+    /**
+     * First RAM bank
+     */
+    uint64_t mem_base;
+    uint64_t mem_size;
+} PlatformInfo;
+
+/**
+ * What `_secondary_start` needs before it can run Rust code. Read with the
+ * MMU off, so it is cleaned to memory before each `CPU_ON`.
+ */
+typedef struct SecondaryBoot {
+    /**
+     * Kernel page tables for TTBR1
+     */
+    uint64_t ttbr1;
+    uint64_t stack_top;
+} SecondaryBoot;
+
+/**
+ * Register state saved on exception entry by `exception_vectors_as.sx`.
+ * Everything but ESR and FAR is restored from it on return, so handlers can
+ * change the registers, the return address or the saved PSTATE.
+ */
+typedef struct TrapFrame {
+    /**
+     * x0 to x30
+     */
+    uint64_t x[31];
+    uint64_t sp_el0;
+    /**
+     * Return address
+     */
     uint64_t elr;
+    /**
+     * PSTATE to return to
+     */
+    uint64_t spsr;
     uint64_t esr;
     uint64_t far;
-} InterruptFrame;
+} TrapFrame;
+
+extern struct BootInfo BOOT_INFO;
+
+extern struct SecondaryBoot SECONDARY_BOOT;
 
 /**
  *Align the given address upwards to given alignment
@@ -94,19 +278,15 @@ typedef struct InterruptFrame {
 size_t align_up(size_t addr, size_t align);
 
 /**
- * dbg FFI binding for C (no varargs)
+ * Allocate `count` contiguous frames aligned to `align` bytes (at least 4KiB).
+ * Returns 0 if no such run exists.
  */
-void c_dbg(const char *message);
+uint64_t alloc_contiguous(size_t count, size_t align);
 
 /**
- * dgb for integers (bin format, u64)
+ * Allocate one 4KiB physical frame. Returns 0 if memory is exhausted.
  */
-void c_dbg_bin(uint64_t bin);
-
-/**
- * dgb for integers (hex format, u64)
- */
-void c_dgb_hex(uint64_t hex);
+uint64_t alloc_frame(void);
 
 extern int32_t c_init_xhci(void);
 
@@ -121,22 +301,124 @@ extern int32_t c_setup_ramfb(char *fb_addr, uint32_t width, uint32_t height);
 
 void c_sleep(size_t ms);
 
+/**
+ * Write dirty lines in `[addr, addr + size)` back to memory, so a device
+ * reading it sees what the CPU wrote.
+ */
+void clean_dcache_range(size_t addr, size_t size);
+
+/**
+ * Clean then invalidate `[addr, addr + size)`: used before handing a buffer
+ * to a device that will write to it.
+ */
+void clean_invalidate(size_t addr, size_t size);
+
 extern void display_bootscreen(char *fb_addr);
 
 uint64_t find_pci_device(uint32_t vendor_id, uint32_t device_id);
 
-void interrupt_handler(void);
+/**
+ * Called by the exception vectors after a switch, once the CPU runs on the
+ * new thread's stack: the previous thread may now be freed or resumed.
+ */
+void finish_switch(void);
+
+struct TrapFrame *interrupt_handler(struct TrapFrame *frame);
+
+/**
+ * Return `count` frames obtained from `alloc_contiguous`.
+ */
+void free_contiguous(uint64_t addr, size_t count);
+
+/**
+ * Return a frame obtained from `alloc_frame`.
+ */
+void free_frame(uint64_t addr);
 
-void kernel_main(uint64_t _x0, const uint8_t *_dtb_ptr);
+/**
+ * Dump the heap counters (and, with `heap_debug`, the live allocations) to the
+ * UART and, once a GPU is up, to the console.
+ */
+void heap_report(void);
 
+/**
+ * Discard cached copies of `[addr, addr + size)`, so the CPU sees what a
+ * device wrote. Lines only partly inside the range are cleaned first, to
+ * keep whatever shares them.
+ */
+void invalidate_dcache_range(size_t addr, size_t size);
+
+void kernel_main(struct BootInfo *boot_info);
+
+/**
+ * # Safety
+ * `ptr` must come from `kmalloc` with the same `size`, and not be freed
+ * yet.
+ */
 void kfree(uint8_t *ptr, size_t size);
 
+/**
+ * # Safety
+ * `ptr` must come from `kmalloc_aligned` with the same `size` and
+ * `align`, and not be freed yet.
+ */
 void kfree_aligned(uint8_t *ptr, size_t size, size_t align);
 
+/**
+ * `kfree_aligned` recording `tag` in `heap_debug` builds
+ *
+ * # Safety
+ * As for `kfree_aligned`, and `tag` must be null or a NUL terminated
+ * string that lives forever.
+ */
+void kfree_aligned_tagged(uint8_t *ptr, size_t size, size_t align, const char *tag);
+
+/**
+ * `kfree` recording `tag` in `heap_debug` builds
+ *
+ * # Safety
+ * As for `kfree`, and `tag` must be null or a NUL terminated string that
+ * lives forever.
+ */
+void kfree_tagged(uint8_t *ptr, size_t size, const char *tag);
+
+/**
+ * `klog!` for C, `msg` is logged as is
+ *
+ * # Safety
+ * `tag` and `msg` must be NUL-terminated strings.
+ */
+void klog(LogLevel level, const char *tag, const char *msg);
+
+/**
+ * `klog` with `value` appended in hex
+ *
+ * # Safety
+ * `tag` and `msg` must be NUL-terminated strings.
+ */
+void klog_hex(LogLevel level, const char *tag, const char *msg, uint64_t value);
+
 uint8_t *kmalloc(size_t size);
 
 uint8_t *kmalloc_aligned(size_t size, size_t align);
 
+/**
+ * `kmalloc_aligned` recording `tag` in `heap_debug` builds
+ *
+ * # Safety
+ * `tag` must be null or a NUL terminated string that lives forever.
+ */
+uint8_t *kmalloc_aligned_tagged(size_t size, size_t align, const char *tag);
+
+/**
+ * `kmalloc` recording `tag` (a static string, e.g. the call site) in
+ * `heap_debug` builds
+ *
+ * # Safety
+ * `tag` must be null or a NUL terminated string that lives forever.
+ */
+uint8_t *kmalloc_tagged(size_t size, const char *tag);
+
 uint32_t mmio_read32(uint64_t addr);
 
 uint64_t mmio_read64(uint64_t addr);
@@ -145,7 +427,21 @@ uint8_t mmio_read8(uint8_t addr);
 
 void mmio_write32(uint64_t reg, uint32_t data);
 
-extern void mmu_init(void);
+/**
+ * Map `[va, va + size)` to `pa` in the kernel address space with `flags`
+ * (`PAGE_*`). Returns false on failure.
+ */
+bool paging_map(uint64_t va, uint64_t pa, uint64_t size, uint64_t flags);
+
+/**
+ * Physical address `va` maps to, or 0 if it is not mapped.
+ */
+uint64_t paging_translate(uint64_t va);
+
+/**
+ * Unmap `[va, va + size)` from the kernel address space.
+ */
+bool paging_unmap(uint64_t va, uint64_t size);
 
 extern bool pci_enable_device_c(uint64_t base);
 
@@ -158,6 +454,16 @@ uint64_t pci_setup_bar(uint64_t pci_addr,
                        uint64_t *mmio_start,
                        uint64_t *mmio_size);
 
+/**
+ * Direct map address of physical address `pa`.
+ */
+uint64_t phys_to_virt(uint64_t pa);
+
+/**
+ * Platform layout for C sources
+ */
+const struct PlatformInfo *platform_info(void);
+
 extern void ramfb_clear(uint8_t color, char *fb_addr);
 
 extern void ramfb_draw_letter(size_t utf8_offset,
@@ -184,10 +490,33 @@ extern void ramfb_matrix(char *fb_addr);
 
 extern void ramfb_set_pixel(uint32_t x, uint32_t y, uint8_t r, uint8_t g, uint8_t b, char *fb);
 
-void serror_current_el_spx_handler(void);
+/**
+ * Rust entry point of secondary CPU `id`, called by `_secondary_start`
+ */
+void secondary_main(size_t id);
+
+void serror_current_el_spx_handler(struct TrapFrame *frame);
 
-void sync_current_el_spx_handler(struct InterruptFrame *frame);
+struct TrapFrame *sync_current_el_spx_handler(struct TrapFrame *frame);
 
 void verify_MMU(void);
 
+/**
+ * Physical address behind kernel virtual address `va`, or 0 if it is not
+ * mapped. Direct map addresses are converted directly, anything else (the
+ * heap) is looked up in the kernel page tables.
+ */
+uint64_t virt_to_phys(uint64_t va);
+
 extern int32_t virtio_generic_setup_c(uint64_t virtio_base, uint16_t device_id);
+
+/* heap_debug: tag every C heap call with its call site */
+#if defined(MVOS_HEAP_DEBUG)
+#define MVOS_STRINGIFY_(x) #x
+#define MVOS_STRINGIFY(x) MVOS_STRINGIFY_(x)
+#define MVOS_CALL_SITE __FILE__ ":" MVOS_STRINGIFY(__LINE__)
+#define kmalloc(size) kmalloc_tagged((size), MVOS_CALL_SITE)
+#define kmalloc_aligned(size, align) kmalloc_aligned_tagged((size), (align), MVOS_CALL_SITE)
+#define kfree(ptr, size) kfree_tagged((ptr), (size), MVOS_CALL_SITE)
+#define kfree_aligned(ptr, size, align) kfree_aligned_tagged((ptr), (size), (align), MVOS_CALL_SITE)
+#endif
@@ -0,0 +1,101 @@
+//! Inter-processor interrupts.
+//!
+//! IPIs are GIC software generated interrupts, one SGI number per
+//! [`IpiKind`]. Besides the scheduler's reschedule IPI they carry cross-CPU
+//! function calls ([`smp_call_function`]), which TLB shootdown is built on.
+
+use core::{arch::asm, sync::atomic::{AtomicU64, AtomicUsize, Ordering}};
+
+use spin::Mutex;
+
+use crate::{exceptions::irq::{self, PRIORITY_DEFAULT, Trigger}, klog, log::Level, smp, thread::scheduler};
+
+/// What an IPI asks of its target, the value is the SGI number
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u32)]
+pub enum IpiKind {
+    /// Pick the next thread at the end of the interrupt
+    Reschedule = 0,
+    /// Run the function passed to `smp_call_function`
+    CallFunction = 1,
+}
+
+/// Held for the duration of a cross-CPU call, one at a time
+static CALL_LOCK: Mutex<()> = Mutex::new(());
+/// Function of the call in flight, as a `fn()` address
+static CALL_FN: AtomicUsize = AtomicUsize::new(0);
+/// Bit `n` is set until CPU `n` has run the call in flight
+static PENDING: AtomicU64 = AtomicU64::new(0);
+
+/// Register the IPI handlers. SGIs are banked per CPU, this enables them on
+/// the boot CPU; secondaries call `init_cpu`.
+pub fn init() {
+    for kind in [IpiKind::Reschedule, IpiKind::CallFunction] {
+        if let Err(e) = irq::register_handler(kind as u32, handle_ipi, PRIORITY_DEFAULT, Trigger::Edge) {
+            klog!(Level::Error, "IPI", "{}", e);
+        }
+    }
+}
+
+/// Enable the IPIs on a secondary CPU
+pub fn init_cpu() {
+    for kind in [IpiKind::Reschedule, IpiKind::CallFunction] {
+        if let Err(e) = irq::enable_percpu(kind as u32, PRIORITY_DEFAULT, Trigger::Edge) {
+            klog!(Level::Error, "IPI", "{}", e);
+        }
+    }
+}
+
+/// Send IPI `kind` to CPU `cpu`. CPUs that are not online are skipped.
+pub fn send_ipi(cpu: usize, kind: IpiKind) {
+    if smp::is_online(cpu) {
+        irq::send_sgi(kind as u32, cpu);
+    }
+}
+
+fn handle_ipi(irq: u32) {
+    match irq {
+        0 => scheduler::resched(),
+        1 => {
+            let f: fn() = unsafe { core::mem::transmute(CALL_FN.load(Ordering::Acquire)) };
+            f();
+            PENDING.fetch_and(!(1 << smp::cpu_id()), Ordering::Release);
+        }
+        _ => {}
+    }
+}
+
+/// Run `f` on every other online CPU, in interrupt context, and wait until
+/// all of them have. Must not be called with IRQs masked: two CPUs calling
+/// at once would wait for each other.
+pub fn smp_call_function(f: fn()) {
+    let _guard = CALL_LOCK.lock();
+    let me = smp::cpu_id();
+    let targets = (0..smp::MAX_CPUS).filter(|&cpu| cpu != me && smp::is_online(cpu))
+        .fold(0u64, |mask, cpu| mask | 1 << cpu);
+    if targets == 0 {
+        return;
+    }
+
+    CALL_FN.store(f as usize, Ordering::Relaxed);
+    PENDING.store(targets, Ordering::Release);
+    for cpu in (0..smp::MAX_CPUS).filter(|&cpu| targets & (1 << cpu) != 0) {
+        send_ipi(cpu, IpiKind::CallFunction);
+    }
+    while PENDING.load(Ordering::Acquire) != 0 {
+        core::hint::spin_loop();
+    }
+}
+
+/// Drop the calling CPU's TLB entries
+fn flush_local_tlb() {
+    unsafe { asm!("dsb ishst", "tlbi vmalle1", "dsb nsh", "isb"); }
+}
+
+/// Flush the TLBs of all CPUs. The page table code in `paging` already uses
+/// the inner shareable (broadcast) TLBI forms, which reach every CPU; this is
+/// for mappings changed behind its back, or flushed with local TLBIs only.
+pub fn tlb_shootdown() {
+    flush_local_tlb();
+    smp_call_function(flush_local_tlb);
+}
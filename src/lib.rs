@@ -5,19 +5,18 @@
 #![feature(asm_sym)]
 #![feature(ascii_char)]
 
-use core::{arch::asm, ffi::{c_char, CStr}, ptr::null_mut};
+use core::ffi::{c_char, CStr};
 
 extern crate alloc;
 
 use drivers::uart::UartWriter;
 use alloc::{boxed::Box, vec::Vec};
 
-use crate::{bootscreen::print_bootscreen, drivers::{graphics::{self, virtio::VirtioDriver}, uart::uart_enable_rxim}, exceptions::{irq::{enable_timer, gic_init}, set_exception_vectors}, memory::allocator::{alloc_ffi::kmalloc_aligned, init_heap}, mvulkan::{MVulkanGPUDriver, color::{DefaultColorScheme, MVulkanColorScheme}, console}, random::random_bible_line, trinkets::templeos_color_palette::TempleOSColorScheme};
+use crate::{boot_info::BootInfo, bootscreen::print_bootscreen, drivers::{graphics::{GpuKind, Resolution, ramfb::RamFBDriver, virtio::VirtioDriver}, uart::uart_enable_rxim}, exceptions::{irq::{enable_timer, gic_init}, set_exception_vectors}, log::Level, memory::allocator::init_heap, mvulkan::{MVulkanGPUDriver, color::{DefaultColorScheme, MVulkanColorScheme}}, time::Instant, trinkets::templeos_color_palette::TempleOSColorScheme};
 
 // C functions
 unsafe extern "C" {
     fn pci_enable_device_c(base: u64) -> bool;
-    fn mmu_init();
     fn ramfb_gradient(fb_addr: *mut c_char);
     fn ramfb_matrix(fb_addr: *mut c_char);
 }
@@ -33,66 +32,159 @@ static mut GPU_DEVICE: Option<*mut dyn MVulkanGPUDriver> = None;
 
 const BIBLE: &str = include_str!("../Bible.TXT");
 
-/// Global absolute system timer (seconds)
-static mut TIMER: usize = 0;
-
 static mut THEME: &dyn MVulkanColorScheme = &DefaultColorScheme;
 
 #[unsafe(no_mangle)]
-pub extern "C" fn kernel_main(_x0: u64, _dtb_ptr: *const u8) -> ! {
+pub extern "C" fn kernel_main(boot_info: &'static mut BootInfo) -> ! {
+    time::init();
+    smp::init();
     let mut error_count: u32 = 0;
     print_bootscreen();
-    serial_println!("\x1B[1;32m[  ☦️INFO   ] Hello World!\x1B[0m");
-    serial_println!("\x1B[1;32m[  ☦️INFO   ] MVOS aarch64 version 0.0.4\x1B[0m");
+    klog!(Level::Info, "SYSTEM", "Hello World!");
+    klog!(Level::Info, "SYSTEM", "MVOS aarch64 version 0.0.4");
+    klog!(Level::Info, "SYSTEM", "Loaded at {:#x}, entered at EL{}, running at EL{}", boot_info.load_addr, boot_info.boot_el, exceptions::current_el());
 
     
-    serial_println!("[ ☦️MEMORY  ] Initializing heap...");
-    init_heap();
+    // Physical memory first: the heap is built from frames mapped by the MMU,
+    // so RAM has to be found without it.
+    let stage = Instant::now();
+    // The boot code passes the loader's DTB pointer as it is, null if none
+    let dtb = unsafe { drivers::dtb_parser::DeviceTreeParser::new(boot_info.dtb) };
+    if let Ok(dtb) = &dtb {
+        boot_info.load_dtb(dtb);
+    }
+    let boot_info: &'static BootInfo = boot_info;
+    if !boot_info.cmdline().is_empty() {
+        klog!(Level::Info, "SYSTEM", "Command line: {}", boot_info.cmdline());
+    }
+    cmdline::init(boot_info.cmdline());
+    log::init();
+    if let Some(action) = cmdline::get_as("mvos.panic") {
+        power::set_panic_action(action);
+    }
+    if let Some(theme) = theme_from_cmdline() {
+        unsafe { THEME = theme; }
+    }
+    klog!(Level::Info, "MEMORY", "Initializing frame allocator...");
+    memory::frame_allocator::init(boot_info, dtb.as_ref().ok());
     
-    serial_println!("[ ☦️SYSTEM  ] Installing exception handlers... ");
+    klog!(Level::Info, "SYSTEM", "Installing exception handlers...");
     unsafe {set_exception_vectors();}
     
-    serial_println!("[ ☦️MEMORY  ] Initializing MMU...");
-    unsafe { mmu_init(); }
+    klog!(Level::Info, "MEMORY", "Initializing MMU...");
+    if let Err(e) = memory::paging::init() {
+        panic!("{}", e);
+    }
+    if let Err(e) = memory::vma::init() {
+        klog!(Level::Error, "MEMORY", "{}", e);
+    }
+
+    klog!(Level::Info, "MEMORY", "Initializing heap...");
+    init_heap();
+    klog!(Level::Info, "TIME", "Memory set up in {:?}.", stage.elapsed());
+
+    let stage = Instant::now();
+    klog!(Level::Info, "SYSTEM", "Parsing device tree...");
+    match dtb.and_then(drivers::dtb_parser::init) {
+        Ok(tree) => {
+            drivers::platform::probe(tree);
+            drivers::psci::probe(tree);
+            if let Err(e) = memory::paging::map_platform() { klog!(Level::Error, "MEMORY", "{}", e); }
+            klog!(Level::Info, "SYSTEM", "Found {} device tree nodes.", tree.nodes().count());
+        },
+        Err(e) => {
+            klog!(Level::Error, "DTB", "{}", e);
+            klog!(Level::Warn, "SYSTEM", "No usable device tree, using QEMU virt defaults.");
+        },
+    }
+    drivers::platform::debug_platform();
+    if let Err(e) = drivers::rtc::init() {
+        klog!(Level::Error, "RTC", "{}", e);
+    }
+    klog!(Level::Info, "TIME", "Device tree parsed in {:?}.", stage.elapsed());
+
+    klog!(Level::Info, "SYSTEM", "Starting scheduler...");
+    if let Err(e) = thread::init() {
+        panic!("{}", e);
+    }
     
     gic_init();
-    serial_println!("[ ☦️SYSTEM  ] \x1b[1;32mFinished GIC init.\x1b[0m");
+    klog!(Level::Info, "SYSTEM", "Finished GIC init.");
     enable_timer();
+    ipi::init();
     unsafe { uart_enable_rxim(); }
-    
-    let mut RAMFB_DEVICE = drivers::graphics::ramfb::RamFBDriver::new();
-    
-    unsafe {
-        GPU_DEVICE = Some(&mut RAMFB_DEVICE as *mut dyn MVulkanGPUDriver);
+
+    klog!(Level::Info, "SYSTEM", "Starting secondary CPUs...");
+    if let Some(tree) = drivers::dtb_parser::device_tree() {
+        smp::start_secondaries(tree);
     }
+    klog!(Level::Info, "TIME", "Interrupts and scheduler up at {:?}.", time::uptime());
     
-    serial_println!("[  DRIVERS  ] Enabling Ramfb device...");
-    
-    match RAMFB_DEVICE.setup() {
-        Ok(()) => {},
-        Err(e) => { error_count += 1; serial_println!("[  DRIVERS  ]\x1b[0;31m RamFB {}\x1b[0m", e) } 
-    };
-    
-    match RAMFB_DEVICE.bootscreen() {
-        Ok(()) => {},
-        Err(e) => { error_count += 1; serial_println!("[  DRIVERS  ]\x1b[0;31m RamFB {}\x1b[0m", e) } 
-    };
+    if let Some(fb) = cmdline::get_as::<Resolution>("fb") && (fb.width, fb.height) != (SCREENWIDTH, SCREENHEIGHT) {
+        klog!(Level::Warn, "DRIVERS", "fb={}x{} not supported, the framebuffer is {}x{}", fb.width, fb.height, SCREENWIDTH, SCREENHEIGHT);
+    }
+
+    // gpu=virtio: the VirtIO GPU, RamFB if it cannot be set up
+    let mut virtio_gpu_device: Option<VirtioDriver> = None;
+    if cmdline::get_as("gpu") == Some(GpuKind::Virtio) {
+        match VirtioDriver::new() {
+            Ok(mut d) => match d.setup() {
+                Ok(()) => virtio_gpu_device = Some(d),
+                Err(e) => { error_count += 1; klog!(Level::Error, "DRIVERS", "VirtIO GPU Error: {}", e) },
+            },
+            Err(e) => { error_count += e as u32; klog!(Level::Error, "DRIVERS", "VirtIO GPU device could not be set up (device not present)") },
+        }
+    }
+
+    // The drivers live on the heap: kernel_main's stack is freed once it exits
+    if let Some(d) = virtio_gpu_device {
+        let d: &'static mut VirtioDriver = Box::leak(Box::new(d));
+        unsafe { GPU_DEVICE = Some(d as *mut dyn MVulkanGPUDriver); }
+    } else {
+        let ramfb: &'static mut RamFBDriver = Box::leak(Box::new(RamFBDriver::new()));
+        unsafe {
+            GPU_DEVICE = Some(ramfb as *mut dyn MVulkanGPUDriver);
+        }
+        
+        klog!(Level::Info, "DRIVERS", "Enabling Ramfb device...");
+        
+        match ramfb.setup() {
+            Ok(()) => {},
+            Err(e) => { error_count += 1; klog!(Level::Error, "DRIVERS", "RamFB {}", e) } 
+        };
+        
+        match ramfb.bootscreen() {
+            Ok(()) => {},
+            Err(e) => { error_count += 1; klog!(Level::Error, "DRIVERS", "RamFB {}", e) } 
+        };
+    }
+
+    // Warnings and errors show on screen too
+    if let Err(e) = log::register_sink(&log::ConsoleSink, Level::Warn) {
+        klog!(Level::Error, "LOG", "{}", e);
+    }
 
     let mut theme = unsafe { THEME };
     
     console_println!("[   INFO   ] Hello World!", ; color: theme.info());
     console_println!("[   INFO   ] MVOS aarch64 version 0.0.4", ; color: theme.info());
     console_println!("[   INFO   ] MVulkan version 0.0.3", ; color: theme.info());
+    if let Some(now) = drivers::rtc::now_utc() {
+        console_println!("[   INFO   ] {}", now ; color: theme.info());
+    }
 
     console_println!("Γεια σου Κοσμε!", ; r: 255, g: 255, b: 255); // hell
 
-    unsafe { THEME = &TempleOSColorScheme; theme = THEME; }
+    // Without mvos.theme the boot shows off the switch to TempleOS colors
+    if !cmdline::is_set("mvos.theme") {
+        unsafe { THEME = &TempleOSColorScheme; }
+    }
+    theme = unsafe { THEME };
 
     console_println!("[  SYSTEM  ] Activated MMU";color: theme.success());
     console_println!("[  SYSTEM  ] Activated GIC";color: theme.success());
     console_println!("[  SYSTEM  ] Activated RamFB device"; color: theme.success());
     console_println!("[{:^10}] TEST", "SYSTEM"; color: theme.debug());
-    serial_println_prefixed!("SYS" => "prefix test");
 
     if let Some(geometry_gpu) = unsafe { (*GPU_DEVICE.unwrap()).as_geometry_mut() } {
         // geometry_gpu.draw_circle(1000, 350, 51, 50, 200, 100, false);
@@ -108,71 +200,90 @@ pub extern "C" fn kernel_main(_x0: u64, _dtb_ptr: *const u8) -> ! {
         text_gpu.draw_textbox("Terry", 401, 401, 4, trinkets::templeos_color_palette::YELLOW);
     }
 
-    //trinkets::trigonakalanta();
-
     //unsafe { drivers::xhci::c::c_init_xhci() };
-    
-    // let mut VIRTIO_GPU_DEVICE: Option<VirtioDriver> = match graphics::virtio::VirtioDriver::new() {
-    //     Ok(d) => Some(d),
-    //     Err(e) => {error_count += e as u32; None},
-    // };
-
-    // if let Some(mut d) = VIRTIO_GPU_DEVICE {
-    //     match d.setup() {
-    //         Ok(()) => {},
-    //         Err(e) => serial_println!("[  DRIVERS  ]\x1b[0;31m VirtIO GPU Error: {}\x1b[0m", e),
-    //     };
-    // } else {
-    //     serial_println!("[  DRIVERS  ]\x1b[0;31m VirtIO GPU device could not be set up (device not present)\x1b[0m");
-    // }
 
     if error_count == 0 { 
-        serial_println!("[ ☦️SYSTEM  ]\x1b[0;32m All processes succeded.\x1b[0m");
-        unsafe { let timer = TIMER;  console_println!("[  SYSTEM  ] All processes succeded in {}ms.", timer ; color: theme.success()); }
+        klog!(Level::Info, "SYSTEM", "All processes succeded.");
+        let uptime = time::uptime().as_millis();
+        console_println!("[  SYSTEM  ] All processes succeded in {}ms.", uptime ; color: theme.success());
     } else {
-        serial_println!("[ ☦️SYSTEM  ]\x1b[0;31m All processes done ({} failed).\x1b[0m", error_count);
-        unsafe { let timer = TIMER;  console_println!("[  SYSTEM  ] All processes done in {}ms ({} failed).", timer, error_count ; color: theme.fail()); }
+        klog!(Level::Error, "SYSTEM", "All processes done ({} failed).", error_count);
+        let uptime = time::uptime().as_millis();
+        console_println!("[  SYSTEM  ] All processes done in {}ms ({} failed).", uptime, error_count ; color: theme.fail());
     }
 
-    unsafe { 
-        loop {}
+    #[cfg(feature = "heap_debug")]
+    memory::allocator::stats::heap_report();
+
+    // The long running jobs get threads of their own, kernel_main is done
+    let jobs: [(&'static str, thread::Entry); 3] = [
+        ("uart_echo", |_| drivers::uart::uart_echo()),
+        ("trigonakalanta", |_| trinkets::trigonakalanta()),
+        ("print_bible", |_| print_bible()),
+    ];
+    for (name, job) in jobs {
+        if let Err(e) = thread::spawn(name, job, 0) {
+            klog!(Level::Error, "THREAD", "{}", e);
+        }
     }
+    thread::exit();
 }
 
 #[panic_handler]
 fn panic(info: &core::panic::PanicInfo) -> ! {
     serial_println!("\x1B[1;31m[   PANIC   ] SYSTEM PANICKED: {:?}\x1B[0m", info);
+    if unsafe { GPU_DEVICE }.is_some() {
+        console_println!("[   PANIC   ] SYSTEM PANICKED: {}", info.message() ; color: unsafe { THEME }.panic_red());
+    }
+    backtrace::print_panic_backtrace(backtrace::current_fp());
     // unsafe {
     //     asm!("mov x0, #0x09000000");
     //     asm!("mov w1, #0x41");
     //     asm!("strb w1, [x0]"); 
     // }
-    loop{}
+    power::on_panic()
 }
 
 #[unsafe(no_mangle)]
 pub unsafe extern "C" fn c_panic(msg: *const c_char) { panic!("Panic caused in C source: {}.", CStr::from_ptr(msg).to_str().unwrap()); }
 
+/// Color scheme named by `mvos.theme` on the command line
+fn theme_from_cmdline() -> Option<&'static dyn MVulkanColorScheme> {
+    match cmdline::get("mvos.theme")? {
+        "default" => Some(&DefaultColorScheme),
+        "templeos" => Some(&TempleOSColorScheme),
+        other => {
+            klog!(Level::Warn, "CMDLINE", "Unknown theme {}", other);
+            None
+        }
+    }
+}
+
 /// Print the whole Bible text to bless the system
 pub fn print_bible() {
     let lines: Vec<&str> = BIBLE.lines().collect();
     for line in lines {
         console_println!("{}", line ; color: 0xffaa55);
-        for _ in (0..2_usize.pow(24)) {
-            unsafe {
-                asm!("nop");
-            }
-        }
+        thread::sleep(50);
     }
 }
 
 // pub mod framebuffer;
+pub mod backtrace;
 pub mod drivers;
 pub mod exceptions;
+pub mod ipi;
+pub mod log;
 pub mod memory;
 pub mod bindings;
+pub mod boot_info;
 pub mod bootscreen;
+pub mod cmdline;
 pub mod mvulkan;
+pub mod power;
 pub mod random;
+pub mod smp;
 pub mod thread;
+pub mod time;
+pub mod timer;
 pub mod trinkets;
\ No newline at end of file
@@ -0,0 +1,351 @@
+//! Kernel log.
+//!
+//! Records carry a [`Level`], a short tag naming the subsystem (`"IRQ"`,
+//! `"MEMORY"`, ...) and an uptime timestamp, and are handed to every
+//! registered [`Sink`] that wants their level: the UART and an in-memory
+//! ring buffer from the start, the console once a GPU is up. Rust code logs
+//! with [`klog!`](crate::klog), C with `klog(level, tag, msg)` (or
+//! `klog_hex(level, tag, msg, value)` to append a number, C has no `printf`).
+//!
+//! A record is kept if its level is at least that of its tag, or of the log
+//! as a whole if the tag has none. Both come from the command line:
+//!
+//! ```text
+//! loglevel=trace|debug|info|warn|error    default debug
+//! log.<TAG>=<level>                       for one tag, e.g. log.IRQ=trace
+//! ```
+//!
+//! Nothing here allocates, and the locks are taken with IRQs masked, so
+//! logging works before the heap exists and from interrupt handlers. A sink
+//! must not log itself. Fault and panic reports (exception and register
+//! dumps, backtraces, `paging::dump_walk`) still write to the UART directly:
+//! they have to get out whatever lock the faulting code held.
+
+use core::{ffi::{c_char, CStr}, fmt::{self, Write}, str::FromStr, sync::atomic::{AtomicU8, Ordering}, time::Duration};
+
+use spin::Mutex;
+
+use crate::{GPU_DEVICE, THEME, cmdline, drivers::uart::UartWriter, exceptions::irq::without_interrupts, mvulkan::{color::MVulkanColorScheme, console::ConsoleWriter}, time};
+
+/// Most sinks registered at once
+const MAX_SINKS: usize = 4;
+/// Most tags with a level of their own
+const MAX_FILTERS: usize = 16;
+/// Longest tag kept, longer ones are cut short
+const TAG_SIZE: usize = 16;
+/// Bytes of log text the ring buffer keeps
+const RING_SIZE: usize = 16 * 1024;
+
+/// How much a record matters, least first
+///
+/// cbindgen:rename-all=QualifiedScreamingSnakeCase
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[repr(u8)]
+pub enum Level {
+    Trace = 0,
+    Debug = 1,
+    Info = 2,
+    Warn = 3,
+    Error = 4,
+}
+
+impl Level {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Level::Trace => "TRACE",
+            Level::Debug => "DEBUG",
+            Level::Info => "INFO",
+            Level::Warn => "WARN",
+            Level::Error => "ERROR",
+        }
+    }
+
+    /// Color of the level in `theme`
+    pub fn color(self, theme: &dyn MVulkanColorScheme) -> u32 {
+        match self {
+            Level::Trace => theme.white(),
+            Level::Debug => theme.debug(),
+            Level::Info => theme.info(),
+            Level::Warn => theme.warning(),
+            Level::Error => theme.error(),
+        }
+    }
+
+    fn from_u8(level: u8) -> Self {
+        match level {
+            0 => Level::Trace,
+            1 => Level::Debug,
+            2 => Level::Info,
+            3 => Level::Warn,
+            _ => Level::Error,
+        }
+    }
+}
+
+impl FromStr for Level {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "trace" => Ok(Level::Trace),
+            "debug" => Ok(Level::Debug),
+            "info" => Ok(Level::Info),
+            "warn" => Ok(Level::Warn),
+            "error" => Ok(Level::Error),
+            _ => Err(()),
+        }
+    }
+}
+
+/// One log line, as handed to the sinks
+pub struct Record<'a> {
+    pub level: Level,
+    /// Upper case, at most `TAG_SIZE` bytes
+    pub tag: &'a str,
+    /// Uptime when the record was made
+    pub time: Duration,
+    pub args: fmt::Arguments<'a>,
+}
+
+/// Somewhere records end up
+pub trait Sink: Sync {
+    fn write(&self, record: &Record);
+}
+
+/// Level of tags without one of their own
+static LEVEL: AtomicU8 = AtomicU8::new(Level::Debug as u8);
+
+struct Filters {
+    entries: [(&'static str, Level); MAX_FILTERS],
+    len: usize,
+}
+
+static FILTERS: Mutex<Filters> = Mutex::new(Filters { entries: [("", Level::Trace); MAX_FILTERS], len: 0 });
+
+/// A sink and the least level it takes
+type SinkSlot = Option<(&'static dyn Sink, Level)>;
+
+static SINKS: Mutex<[SinkSlot; MAX_SINKS]> = Mutex::new([
+    Some((&UartSink, Level::Trace)),
+    Some((&RING, Level::Trace)),
+    None,
+    None,
+]);
+
+/// Take the levels from the command line. Called right after `cmdline::init`.
+pub fn init() {
+    if let Some(level) = cmdline::get_as("loglevel") {
+        set_level(level);
+    }
+    cmdline::with_prefix("log.", |tag, value| match value.parse() {
+        Ok(level) => set_tag_level(tag, level),
+        Err(()) => crate::klog!(Level::Warn, "CMDLINE", "Ignoring log.{}={}: bad level", tag, value),
+    });
+}
+
+/// Set the level of tags without one of their own
+pub fn set_level(level: Level) {
+    LEVEL.store(level as u8, Ordering::Relaxed);
+}
+
+/// Set the level of records tagged `tag` (in any case)
+pub fn set_tag_level(tag: &'static str, level: Level) {
+    without_interrupts(|| {
+        let mut filters = FILTERS.lock();
+        let len = filters.len;
+        if let Some(entry) = filters.entries[..len].iter_mut().find(|(t, _)| t.eq_ignore_ascii_case(tag)) {
+            entry.1 = level;
+        } else if len < MAX_FILTERS {
+            filters.entries[len] = (tag, level);
+            filters.len += 1;
+        }
+    });
+}
+
+/// Whether a record of `level` tagged `tag` would be kept
+pub fn enabled(level: Level, tag: &str) -> bool {
+    let min = without_interrupts(|| {
+        let filters = FILTERS.lock();
+        filters.entries[..filters.len].iter().find(|(t, _)| t.eq_ignore_ascii_case(tag)).map(|(_, l)| *l)
+    });
+    level >= min.unwrap_or(Level::from_u8(LEVEL.load(Ordering::Relaxed)))
+}
+
+/// Hand records of `level` and up to `sink` as well. Fails if all slots are
+/// taken.
+pub fn register_sink(sink: &'static dyn Sink, level: Level) -> Result<(), &'static str> {
+    without_interrupts(|| {
+        let mut sinks = SINKS.lock();
+        let slot = sinks.iter_mut().find(|s| s.is_none()).ok_or("[    LOG    ] \x1b[1;31mNo free sink slot\x1b[0m")?;
+        *slot = Some((sink, level));
+        Ok(())
+    })
+}
+
+/// Log `args` at `level` under `tag`. Use [`klog!`](crate::klog) instead.
+pub fn log(level: Level, tag: &str, args: fmt::Arguments) {
+    if !enabled(level, tag) {
+        return;
+    }
+
+    let mut tag_buf = [0u8; TAG_SIZE];
+    let mut len = tag.len().min(TAG_SIZE);
+    while !tag.is_char_boundary(len) {
+        len -= 1;
+    }
+    tag_buf[..len].copy_from_slice(&tag.as_bytes()[..len]);
+    tag_buf.make_ascii_uppercase();
+
+    let record = Record {
+        level,
+        tag: core::str::from_utf8(&tag_buf[..len]).unwrap_or(""),
+        time: time::uptime(),
+        args,
+    };
+    // Copied out, so a sink may register another one
+    let sinks = without_interrupts(|| *SINKS.lock());
+    for (sink, min) in sinks.into_iter().flatten() {
+        if level >= min {
+            sink.write(&record);
+        }
+    }
+}
+
+/// Log a line at a [`Level`] under a tag: `klog!(Level::Info, "SYS", "{} CPUs", n)`
+#[macro_export]
+macro_rules! klog {
+    ($level:expr, $tag:expr, $($arg:tt)+) => {
+        $crate::log::log($level, $tag, format_args!($($arg)+))
+    };
+}
+
+/// `klog!` for C, `msg` is logged as is
+///
+/// # Safety
+/// `tag` and `msg` must be NUL-terminated strings.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn klog(level: Level, tag: *const c_char, msg: *const c_char) {
+    if let Some((tag, msg)) = unsafe { from_c(tag, msg) } {
+        log(level, tag, format_args!("{}", msg));
+    }
+}
+
+/// `klog` with `value` appended in hex
+///
+/// # Safety
+/// `tag` and `msg` must be NUL-terminated strings.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn klog_hex(level: Level, tag: *const c_char, msg: *const c_char, value: u64) {
+    if let Some((tag, msg)) = unsafe { from_c(tag, msg) } {
+        log(level, tag, format_args!("{} {:#x}", msg, value));
+    }
+}
+
+/// Tag and message of a C call, logging an error instead if they are not UTF-8
+unsafe fn from_c<'a>(tag: *const c_char, msg: *const c_char) -> Option<(&'a str, &'a str)> {
+    let (tag, msg) = unsafe { (CStr::from_ptr(tag).to_str(), CStr::from_ptr(msg).to_str()) };
+    match (tag, msg) {
+        (Ok(tag), Ok(msg)) => Some((tag, msg)),
+        _ => {
+            log(Level::Error, "LOG", format_args!("Invalid UTF-8 string passed from C"));
+            None
+        }
+    }
+}
+
+/// Color of `level` in the active theme as an ANSI escape
+struct Ansi(Level);
+
+impl fmt::Display for Ansi {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let color = self.0.color(unsafe { THEME });
+        write!(f, "\x1b[38;2;{};{};{}m", color >> 16 & 0xff, color >> 8 & 0xff, color & 0xff)
+    }
+}
+
+/// Serial port, with colors
+pub struct UartSink;
+
+/// Keeps the lines of different CPUs apart
+static UART_LOCK: Mutex<()> = Mutex::new(());
+
+impl Sink for UartSink {
+    fn write(&self, record: &Record) {
+        without_interrupts(|| {
+            let _guard = UART_LOCK.lock();
+            let t = record.time;
+            writeln!(UartWriter, "+{}.{:06} [{:^11}] {}{}\x1b[0m", t.as_secs(), t.subsec_micros(), record.tag, Ansi(record.level), record.args).ok();
+        });
+    }
+}
+
+/// MVulkan console. Does nothing until a GPU is set up.
+pub struct ConsoleSink;
+
+impl Sink for ConsoleSink {
+    fn write(&self, record: &Record) {
+        if unsafe { GPU_DEVICE }.is_none() {
+            return;
+        }
+        let mut console = ConsoleWriter { color: record.level.color(unsafe { THEME }) };
+        writeln!(console, "[{:^10}] {}", record.tag, record.args).ok();
+    }
+}
+
+/// The last `RING_SIZE` bytes logged, as plain text
+pub struct RingBuffer {
+    ring: Mutex<Ring>,
+}
+
+struct Ring {
+    buf: [u8; RING_SIZE],
+    /// Bytes ever written, the next one goes at `written % RING_SIZE`
+    written: usize,
+}
+
+impl Write for Ring {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for &byte in s.as_bytes() {
+            self.buf[self.written % RING_SIZE] = byte;
+            self.written += 1;
+        }
+        Ok(())
+    }
+}
+
+pub static RING: RingBuffer = RingBuffer { ring: Mutex::new(Ring { buf: [0; RING_SIZE], written: 0 }) };
+
+impl Sink for RingBuffer {
+    fn write(&self, record: &Record) {
+        without_interrupts(|| {
+            let t = record.time;
+            writeln!(self.ring.lock(), "+{}.{:06} {:<5} [{:^11}] {}", t.as_secs(), t.subsec_micros(), record.level.as_str(), record.tag, record.args).ok();
+        });
+    }
+}
+
+impl RingBuffer {
+    /// Call `f` with the kept text, oldest first, in one or two pieces. Once
+    /// the buffer has wrapped the partly overwritten oldest line is left out.
+    /// `f` runs with IRQs masked and must not log.
+    pub fn read(&self, mut f: impl FnMut(&[u8])) {
+        without_interrupts(|| {
+            let ring = self.ring.lock();
+            if ring.written <= RING_SIZE {
+                f(&ring.buf[..ring.written]);
+                return;
+            }
+            let head = ring.written % RING_SIZE;
+            let (newer, older) = ring.buf.split_at(head);
+            match older.iter().position(|&b| b == b'\n') {
+                Some(nl) => f(&older[nl + 1..]),
+                None => {
+                    let nl = newer.iter().position(|&b| b == b'\n').map_or(newer.len(), |nl| nl + 1);
+                    f(&newer[nl..]);
+                    return;
+                }
+            }
+            f(newer);
+        });
+    }
+}
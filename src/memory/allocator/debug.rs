@@ -0,0 +1,97 @@
+//! Live allocation table for the `heap_debug` feature.
+//!
+//! Every successful allocation is recorded with its size and tag, and removed
+//! again when it is freed. Frees of unknown pointers and size mismatches are
+//! reported on the UART together with the tag of the `kfree` call.
+
+use core::ptr::null;
+
+use crate::{memory::allocator::stats::{AllocTag, tag_str}, klog, log::Level};
+
+/// Number of allocations that can be tracked at once
+const MAX_TRACKED: usize = 4096;
+/// Number of distinct tags `live_by_tag` can summarize
+const MAX_SITES: usize = 64;
+
+#[derive(Clone, Copy)]
+struct LiveAlloc {
+    ptr: usize,
+    size: usize,
+    tag: AllocTag,
+}
+
+/// Live allocations sharing one tag
+#[derive(Clone, Copy)]
+pub struct AllocSite {
+    pub tag: AllocTag,
+    pub count: usize,
+    pub bytes: usize,
+}
+
+struct AllocTable {
+    entries: [Option<LiveAlloc>; MAX_TRACKED],
+    /// Allocations that did not fit in the table
+    dropped: usize,
+}
+
+unsafe impl Send for AllocTable {}
+
+static TABLE: spin::Mutex<AllocTable> = spin::Mutex::new(AllocTable { entries: [None; MAX_TRACKED], dropped: 0 });
+
+pub fn record(ptr: *mut u8, size: usize, tag: AllocTag) {
+    let mut table = TABLE.lock();
+    match table.entries.iter_mut().find(|e| e.is_none()) {
+        Some(slot) => *slot = Some(LiveAlloc { ptr: ptr as usize, size, tag }),
+        None => table.dropped += 1,
+    }
+}
+
+/// Drop `ptr` from the table, reporting a size mismatch or an untracked free
+///
+/// # Safety
+/// `tag` must be null or a NUL terminated string that lives forever.
+pub unsafe fn forget(ptr: *mut u8, size: usize, tag: AllocTag) {
+    let mut table = TABLE.lock();
+    let slot = table.entries.iter_mut().find(|e| e.is_some_and(|a| a.ptr == ptr as usize));
+    match slot.and_then(|slot| slot.take()) {
+        Some(live) => {
+            if live.size != size {
+                klog!(Level::Warn, "MEMORY", "Free of {:p} from {} with size {}, allocated with size {} by {}.", ptr, unsafe { tag_str(tag) }, size, live.size, unsafe { tag_str(live.tag) });
+            }
+        }
+        None if table.dropped > 0 => table.dropped -= 1,
+        None => klog!(Level::Error, "MEMORY", "Free of untracked pointer {:p} ({} bytes) from {}.", ptr, size, unsafe { tag_str(tag) }),
+    }
+}
+
+/// Live allocations grouped by tag. Tags beyond `MAX_SITES` are merged into a
+/// single entry with a null tag.
+pub fn live_by_tag() -> [Option<AllocSite>; MAX_SITES] {
+    let mut sites: [Option<AllocSite>; MAX_SITES] = [None; MAX_SITES];
+    let table = TABLE.lock();
+
+    for live in table.entries.iter().flatten() {
+        let tag = if sites.iter().flatten().any(|s| s.tag == live.tag) || sites[MAX_SITES - 2].is_none() {
+            live.tag
+        } else {
+            null()
+        };
+
+        match sites.iter_mut().flatten().find(|s| s.tag == tag) {
+            Some(site) => {
+                site.count += 1;
+                site.bytes += live.size;
+            }
+            None => {
+                let slot = sites.iter_mut().find(|s| s.is_none()).unwrap();
+                *slot = Some(AllocSite { tag, count: 1, bytes: live.size });
+            }
+        }
+    }
+
+    if table.dropped > 0 {
+        klog!(Level::Warn, "MEMORY", "{} allocations were not tracked (table full).", table.dropped);
+    }
+
+    sites
+}
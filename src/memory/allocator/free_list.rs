@@ -1,99 +1,396 @@
 //! MVOS Free list memory allocator implementation
+//!
+//! Free blocks are kept in a singly linked list sorted by address, with the
+//! list nodes living inside the free memory itself. Allocation carves the
+//! first (or best) fitting block, splitting off whatever is left over;
+//! freeing reinserts the block and coalesces it with its neighbours.
+//!
+//! Every allocation is preceded by an `AllocHeader` recording the block it was
+//! carved from, guarded by a canary so corruption and double frees are caught
+//! on `dealloc`.
 
-use core::{alloc::GlobalAlloc, ptr::addr_of_mut};
+use core::{alloc::{GlobalAlloc, Layout}, mem, ptr::null_mut};
 
-use crate::memory::allocator::align_up;
+use super::{Locked, align_up};
 
-unsafe extern "C" {
-    pub unsafe static heap_bottom: u8;
-}
+/// Marks a live allocation header.
+const CANARY_LIVE: usize = 0x4d56_4f53_a110_ca7e;
+/// Written over the canary once the allocation has been freed.
+const CANARY_FREED: usize = 0x4d56_4f53_dead_beef;
+
+/// Every block start and size is a multiple of this.
+const BLOCK_ALIGN: usize = mem::align_of::<FreeBlock>();
+/// Smallest leftover worth splitting into its own free block.
+const MIN_BLOCK: usize = mem::size_of::<FreeBlock>();
+const HEADER_SIZE: usize = mem::size_of::<AllocHeader>();
 
 #[repr(C)]
 pub struct FreeBlock {
-    addr: *mut u8,
     size: usize,
-    next: Option<*mut FreeBlock>,
+    next: *mut FreeBlock,
 }
 
-unsafe impl Send for FreeBlock {}
-unsafe impl Sync for FreeBlock {}
-
-impl FreeBlock {
-    /// Default head of list starting at a dummy address with 4KiB size.
-    ///
-    /// The list will be initialized properly at runtime.
-    pub const unsafe fn new_list() -> Self {
-        Self {
-            addr: 0x41000000 as *mut u8,
-            size: 4096,
-            next: None,
-        }
-    }
+#[repr(C)]
+struct AllocHeader {
+    /// Start of the block the allocation was carved from
+    block_start: usize,
+    /// Size of that block (alignment padding included)
+    block_size: usize,
+    canary: usize,
+}
 
-    /// New instance of block from provided size and address to push onto the list.
-    pub fn new(addr: *mut u8, size: usize) -> Self {
-        Self {
-            addr, size, next: None,
-        }
-    }
+/// Block selection strategy.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FitStrategy {
+    /// Take the lowest-addressed block that fits (fast)
+    FirstFit,
+    /// Take the block that leaves the smallest remainder (less fragmentation)
+    BestFit,
 }
 
-#[repr(C)]
 pub struct FreeListAllocator {
     head: *mut FreeBlock,
+    strategy: FitStrategy,
+    /// Total bytes handed to the allocator
+    size: usize,
+    /// Bytes currently on the free list
+    free: usize,
+}
+
+unsafe impl Send for FreeListAllocator {}
+unsafe impl Sync for FreeListAllocator {}
+
+impl Default for FreeListAllocator {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl FreeListAllocator {
+    /// An empty first-fit allocator. Call `init` before the first allocation.
     pub const fn new() -> Self {
-        unsafe {
-            let mut head = FreeBlock::new_list();
-            Self {
-                head: &raw mut head,
+        Self::with_strategy(FitStrategy::FirstFit)
+    }
+
+    pub const fn with_strategy(strategy: FitStrategy) -> Self {
+        Self { head: null_mut(), strategy, size: 0, free: 0 }
+    }
+
+    /// Hand the region `[heap_bottom, heap_bottom + heap_size)` to the allocator.
+    ///
+    /// Same signature as `linked_list_allocator::Heap::init` so either can back
+    /// the global allocator.
+    ///
+    /// # Safety
+    /// The region must be valid for reads and writes, unused by anything else
+    /// and outlive the allocator.
+    pub unsafe fn init(&mut self, heap_bottom: *mut u8, heap_size: usize) {
+        unsafe { self.add_region(heap_bottom as usize, heap_size); }
+    }
+
+    /// Add another (non-overlapping) region of free memory.
+    ///
+    /// # Safety
+    /// Same as `init`, and the region must not overlap memory the allocator
+    /// already manages.
+    pub unsafe fn add_region(&mut self, start: usize, size: usize) {
+        let aligned = align_up(start, BLOCK_ALIGN);
+        let end = (start + size) & !(BLOCK_ALIGN - 1);
+        if end <= aligned || end - aligned < MIN_BLOCK { return; }
+
+        self.size += end - aligned;
+        unsafe { self.insert_free(aligned, end - aligned); }
+    }
+
+    /// Total bytes managed.
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Bytes currently on the free list.
+    pub fn free(&self) -> usize {
+        self.free
+    }
+
+    /// Bytes currently allocated (headers and padding included).
+    pub fn used(&self) -> usize {
+        self.size - self.free
+    }
+
+    /// Size of the largest block on the free list.
+    pub fn largest_free_block(&self) -> usize {
+        let mut largest = 0;
+        let mut current = self.head;
+        while !current.is_null() {
+            unsafe {
+                largest = largest.max((*current).size);
+                current = (*current).next;
             }
         }
+        largest
     }
-}
 
-unsafe impl GlobalAlloc for FreeListAllocator {
-    unsafe fn alloc(&self, layout: core::alloc::Layout) -> *mut u8 {
-        let size = layout.size();
-        let align = layout.align();
-        let rounded = align_up(size, align);
+    /// Where an allocation of `size`/`align` would sit inside `block`,
+    /// as (payload address, bytes taken from the block start).
+    fn fit(block: *mut FreeBlock, size: usize, align: usize) -> Option<(usize, usize)> {
+        let start = block as usize;
+        let payload = align_up(start + HEADER_SIZE, align);
+        let end = align_up(payload + size, BLOCK_ALIGN);
+        let needed = end - start;
+        if needed <= unsafe { (*block).size } { Some((payload, needed)) } else { None }
+    }
 
-        // Traverse the free list and pop the final block.
+    pub fn allocate(&mut self, layout: Layout) -> *mut u8 {
+        let align = layout.align().max(BLOCK_ALIGN);
+        let size = layout.size().max(1);
 
-        let mut current_block = self.head;
-        let mut prev_block: *mut FreeBlock = 0 as *mut FreeBlock;
+        unsafe {
+            // (previous node, chosen node, payload, bytes needed)
+            let mut chosen: Option<(*mut FreeBlock, *mut FreeBlock, usize, usize)> = None;
 
-        while (*current_block).next.is_some() {
-            if (*(*current_block).next.unwrap()).size >= rounded {
-                prev_block = current_block;
-                current_block = (*current_block).next.unwrap();
+            let mut prev: *mut FreeBlock = null_mut();
+            let mut current = self.head;
+            while !current.is_null() {
+                if let Some((payload, needed)) = Self::fit(current, size, align) {
+                    let better = match chosen {
+                        None => true,
+                        Some((_, best, _, best_needed)) => {
+                            (*current).size - needed < (*best).size - best_needed
+                        }
+                    };
+                    if better {
+                        chosen = Some((prev, current, payload, needed));
+                    }
+                    if self.strategy == FitStrategy::FirstFit || (*current).size == needed {
+                        break;
+                    }
+                }
+                prev = current;
+                current = (*current).next;
+            }
+
+            let Some((prev, block, payload, mut needed)) = chosen else {
+                return null_mut();
+            };
+
+            let block_start = block as usize;
+            let block_size = (*block).size;
+            let next = (*block).next;
+
+            // Split off the tail if it can hold a free block of its own
+            let replacement = if block_size - needed >= MIN_BLOCK {
+                let tail = (block_start + needed) as *mut FreeBlock;
+                tail.write(FreeBlock { size: block_size - needed, next });
+                tail
+            } else {
+                needed = block_size;
+                next
+            };
+
+            if prev.is_null() {
+                self.head = replacement;
             } else {
-                (*prev_block).next = (*current_block).next;
-                return (*current_block).addr;
+                (*prev).next = replacement;
             }
+
+            let header = (payload - HEADER_SIZE) as *mut AllocHeader;
+            header.write(AllocHeader { block_start, block_size: needed, canary: CANARY_LIVE });
+
+            self.free -= needed;
+            payload as *mut u8
         }
+    }
+
+    /// Return an allocation to the free list. Panics on a double free or a
+    /// corrupted header.
+    ///
+    /// # Safety
+    /// `ptr` must have been returned by `allocate` on this allocator.
+    pub unsafe fn deallocate(&mut self, ptr: *mut u8) {
+        unsafe {
+            let header = (ptr as usize - HEADER_SIZE) as *mut AllocHeader;
+            match (*header).canary {
+                CANARY_LIVE => {}
+                CANARY_FREED => panic!("FreeListAllocator: double free of {:p}", ptr),
+                canary => panic!("FreeListAllocator: heap corruption at {:p} (canary {:#x})", ptr, canary),
+            }
 
-        // Return null pointer to indicate allocation failure
+            let AllocHeader { block_start, block_size, .. } = header.read();
+            (*header).canary = CANARY_FREED;
 
-        0 as *mut u8
+            self.insert_free(block_start, block_size);
+        }
     }
 
-    unsafe fn dealloc(&self, ptr: *mut u8, layout: core::alloc::Layout) {
-        let size = align_up(layout.size(), layout.align());
+    /// Insert `[start, start + size)` into the address-sorted list and merge it
+    /// with adjacent free blocks.
+    unsafe fn insert_free(&mut self, start: usize, size: usize) {
+        unsafe {
+            let mut prev: *mut FreeBlock = null_mut();
+            let mut next = self.head;
+            while !next.is_null() && (next as usize) < start {
+                prev = next;
+                next = (*next).next;
+            }
+
+            if (!next.is_null() && start + size > next as usize)
+                || (!prev.is_null() && prev as usize + (*prev).size > start) {
+                panic!("FreeListAllocator: freed block {:#x}..{:#x} overlaps the free list", start, start + size);
+            }
+
+            self.free += size;
 
-        let mut block = FreeBlock::new(ptr, size);
+            let block = start as *mut FreeBlock;
+            block.write(FreeBlock { size, next });
 
-        // Traverse the list and push new block. 
-        let mut current_block = self.head;
-        while (*current_block).next.is_some() {
-            current_block = (*current_block).next.unwrap();
+            // Coalesce with the following block
+            if !next.is_null() && start + size == next as usize {
+                (*block).size += (*next).size;
+                (*block).next = (*next).next;
+            }
+
+            // Coalesce with the preceding block
+            if !prev.is_null() && prev as usize + (*prev).size == start {
+                (*prev).size += (*block).size;
+                (*prev).next = (*block).next;
+            } else if prev.is_null() {
+                self.head = block;
+            } else {
+                (*prev).next = block;
+            }
         }
+    }
+}
 
-        (*current_block).next = Some(addr_of_mut!(block));
+unsafe impl GlobalAlloc for Locked<FreeListAllocator> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        self.lock().allocate(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, _layout: Layout) {
+        if ptr.is_null() { return; }
+        unsafe { self.lock().deallocate(ptr); }
     }
 }
 
-unsafe impl Send for FreeListAllocator {}
-unsafe impl Sync for FreeListAllocator {}
\ No newline at end of file
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ARENA_SIZE: usize = 4096;
+
+    #[repr(C, align(4096))]
+    struct Arena([u8; ARENA_SIZE]);
+
+    /// Allocator over a fresh arena. The arena is returned to keep it alive.
+    fn heap(strategy: FitStrategy) -> (Box<Arena>, FreeListAllocator) {
+        let mut arena = Box::new(Arena([0; ARENA_SIZE]));
+        let mut heap = FreeListAllocator::with_strategy(strategy);
+        unsafe { heap.init(arena.0.as_mut_ptr(), ARENA_SIZE); }
+        (arena, heap)
+    }
+
+    fn alloc(heap: &mut FreeListAllocator, size: usize, align: usize) -> *mut u8 {
+        heap.allocate(Layout::from_size_align(size, align).unwrap())
+    }
+
+    fn blocks(heap: &FreeListAllocator) -> usize {
+        let mut count = 0;
+        let mut current = heap.head;
+        while !current.is_null() {
+            count += 1;
+            current = unsafe { (*current).next };
+        }
+        count
+    }
+
+    #[test]
+    fn splits_blocks() {
+        let (_arena, mut heap) = heap(FitStrategy::FirstFit);
+        let a = alloc(&mut heap, 64, 8);
+        assert!(!a.is_null());
+        assert_eq!(blocks(&heap), 1);
+        assert_eq!(heap.used(), HEADER_SIZE + 64);
+        assert_eq!(heap.largest_free_block(), ARENA_SIZE - heap.used());
+
+        let b = alloc(&mut heap, 64, 8);
+        assert_eq!(b as usize, a as usize + 64 + HEADER_SIZE);
+    }
+
+    #[test]
+    fn coalesces_with_both_neighbours() {
+        let (_arena, mut heap) = heap(FitStrategy::FirstFit);
+        let [a, b, c, _d] = [(); 4].map(|_| alloc(&mut heap, 128, 8));
+        unsafe {
+            heap.deallocate(a);
+            heap.deallocate(c);
+            assert_eq!(blocks(&heap), 3);
+            heap.deallocate(b);
+        }
+        // a, b and c are one block again, followed by the tail past d
+        assert_eq!(blocks(&heap), 2);
+        assert_eq!(heap.largest_free_block(), ARENA_SIZE - 4 * (HEADER_SIZE + 128));
+        assert_eq!(alloc(&mut heap, 3 * 128, 8), a);
+    }
+
+    #[test]
+    fn aligns_beyond_header() {
+        let (_arena, mut heap) = heap(FitStrategy::FirstFit);
+        let _ = alloc(&mut heap, 8, 8);
+        let p = alloc(&mut heap, 100, 256);
+        assert!(!p.is_null());
+        assert_eq!(p as usize % 256, 0);
+
+        unsafe { heap.deallocate(p); }
+        let q = alloc(&mut heap, 100, 256);
+        assert_eq!(p, q);
+    }
+
+    /// Free holes of 256 and 64 bytes, the larger one first
+    fn holes(strategy: FitStrategy) -> (Box<Arena>, FreeListAllocator, *mut u8, *mut u8) {
+        let (arena, mut heap) = heap(strategy);
+        let large = alloc(&mut heap, 256, 8);
+        let _ = alloc(&mut heap, 16, 8);
+        let small = alloc(&mut heap, 64, 8);
+        let _ = alloc(&mut heap, 16, 8);
+        unsafe {
+            heap.deallocate(large);
+            heap.deallocate(small);
+        }
+        (arena, heap, large, small)
+    }
+
+    #[test]
+    fn first_fit_takes_lowest_block() {
+        let (_arena, mut heap, large, _) = holes(FitStrategy::FirstFit);
+        assert_eq!(alloc(&mut heap, 48, 8), large);
+    }
+
+    #[test]
+    fn best_fit_takes_smallest_block() {
+        let (_arena, mut heap, _, small) = holes(FitStrategy::BestFit);
+        assert_eq!(alloc(&mut heap, 48, 8), small);
+    }
+
+    #[test]
+    #[should_panic(expected = "double free")]
+    fn catches_double_free() {
+        let (_arena, mut heap) = heap(FitStrategy::FirstFit);
+        let a = alloc(&mut heap, 32, 8);
+        unsafe {
+            heap.deallocate(a);
+            heap.deallocate(a);
+        }
+    }
+
+    #[test]
+    fn out_of_memory_returns_null() {
+        let (_arena, mut heap) = heap(FitStrategy::FirstFit);
+        assert!(alloc(&mut heap, ARENA_SIZE, 8).is_null());
+
+        let all = alloc(&mut heap, ARENA_SIZE - HEADER_SIZE, 8);
+        assert!(!all.is_null());
+        assert_eq!(heap.free(), 0);
+        assert!(alloc(&mut heap, 1, 8).is_null());
+    }
+}
@@ -1,23 +1,76 @@
 //use alloc::alloc::{Layout, GlobalAlloc};
-use core::{
-    ptr::{
-        self, NonNull},
-        mem,
-};
+#[cfg(not(feature = "free_list_allocator"))]
 use linked_list_allocator::LockedHeap;
 
-use crate::memory::allocator::free_list::{FreeBlock, FreeListAllocator};
+#[cfg(feature = "free_list_allocator")]
+use crate::memory::allocator::free_list::FreeListAllocator;
+use crate::{memory::{allocator::stats::Tracked, frame_allocator::{PAGE_SIZE, alloc_frame, free_frame}, paging::{self, PageFlags}}, klog, log::Level};
 
+/// Kernel heap backed by `linked_list_allocator` (default).
+#[cfg(not(feature = "free_list_allocator"))]
 #[global_allocator]
-static ALLOCATOR: LockedHeap = LockedHeap::empty();
+static ALLOCATOR: Tracked<LockedHeap> = Tracked::new(LockedHeap::empty());
 
+/// Kernel heap backed by the MVOS free list allocator
+/// (build with `--features free_list_allocator`).
+#[cfg(feature = "free_list_allocator")]
+#[global_allocator]
+static ALLOCATOR: Tracked<Locked<FreeListAllocator>> = Tracked::new(Locked::new(FreeListAllocator::new()));
+
+/// Start of the kernel heap's virtual window, above the direct map (see `memory::layout`)
+pub const HEAP_START: usize = 0xffff_8000_0000_0000;
+/// Bytes mapped into the window at boot (16MiB)
+pub const HEAP_SIZE: usize = 0x1000000;
+/// Size of the virtual window the heap can grow into (4GiB)
+pub const HEAP_MAX_SIZE: usize = 0x1_0000_0000;
+/// Smallest step the heap grows by (1MiB)
+const HEAP_GROW_STEP: usize = 0x100000;
+
+/// End of the mapped part of the heap window (0 until `init_heap`)
+static HEAP_END: spin::Mutex<usize> = spin::Mutex::new(0);
+
+/// Back `[start, start + size)` of the heap window with fresh frames.
+/// Returns how many bytes were mapped, which is less than `size` if RAM runs out.
+fn map_heap_pages(start: usize, size: usize) -> usize {
+    let mut mapped = 0;
+    while mapped < size {
+        let frame = alloc_frame();
+        if frame == 0 { break; }
+        if paging::map((start + mapped) as u64, frame, PAGE_SIZE, PageFlags::KERNEL_DATA).is_err() {
+            free_frame(frame);
+            break;
+        }
+        mapped += PAGE_SIZE as usize;
+    }
+    mapped
+}
+
+/// Map the first `HEAP_SIZE` bytes of the heap window and hand them to the
+/// allocator. Needs the frame allocator and the MMU to be up.
 pub fn init_heap() {
-    let heap_start = 0x41000000;
-    let heap_end = 0x42000000;
-    let heap_size = heap_end - heap_start;
+    let mut end = HEAP_END.lock();
+    let mapped = map_heap_pages(HEAP_START, HEAP_SIZE);
     unsafe {
-        ALLOCATOR.lock().init(heap_start as *mut u8, heap_size);
+        ALLOCATOR.init(HEAP_START as *mut u8, mapped);
     }
+    *end = HEAP_START + mapped;
+}
+
+/// Map at least `min` more bytes at the top of the heap and give them to the
+/// allocator. Called before an allocation is allowed to fail; returns the
+/// number of bytes added (0 if the window or RAM is exhausted).
+pub fn grow_heap(min: usize) -> usize {
+    let mut end = HEAP_END.lock();
+    if *end == 0 { return 0; }
+
+    let by = align_up(min, HEAP_GROW_STEP).min(HEAP_START + HEAP_MAX_SIZE - *end);
+    let mapped = map_heap_pages(*end, by);
+    if mapped != 0 {
+        unsafe { ALLOCATOR.extend(*end, mapped); }
+        *end += mapped;
+        klog!(Level::Info, "MEMORY", "Heap grown by {} KiB to {} KiB.", mapped >> 10, (*end - HEAP_START) >> 10);
+    }
+    mapped
 }
 
 /// Wrapper for spin::Mutex to permit trait impl
@@ -49,28 +102,52 @@ pub extern "C" fn align_up(addr: usize, align: usize) -> usize {
 }
 
 pub mod alloc_ffi {
-    use core::{alloc::{Layout, GlobalAlloc}, ptr::{null, null_mut}};
+    use core::{alloc::Layout, ffi::c_char, ptr::null_mut};
 
     use crate::memory::allocator::ALLOCATOR;
 
     #[unsafe(no_mangle)]
     pub extern "C" fn kmalloc(size: usize) -> *mut u8 {
-        if size == 0 {
-            return core::ptr::null_mut();
-        }
+        unsafe { kmalloc_aligned_tagged(size, 1, core::ptr::null()) }
+    }
 
-        let layout = match Layout::from_size_align(size, 1) {
-            Ok(layout) => layout,
-            Err(_) => return core::ptr::null_mut(),
-        };
+    #[unsafe(no_mangle)]
+    pub extern "C" fn kmalloc_aligned(size: usize, align: usize) -> *mut u8 {
+        unsafe { kmalloc_aligned_tagged(size, align, core::ptr::null()) }
+    }
 
-        unsafe {
-            ALLOCATOR.alloc(layout)
-        }
+    /// # Safety
+    /// `ptr` must come from `kmalloc` with the same `size`, and not be freed
+    /// yet.
+    #[unsafe(no_mangle)]
+    pub unsafe extern "C" fn kfree(ptr: *mut u8, size: usize) {
+        unsafe { kfree_aligned_tagged(ptr, size, 1, core::ptr::null()); }
     }
 
+    /// # Safety
+    /// `ptr` must come from `kmalloc_aligned` with the same `size` and
+    /// `align`, and not be freed yet.
     #[unsafe(no_mangle)]
-    pub extern "C" fn kmalloc_aligned(size: usize, align: usize) -> *mut u8 {
+    pub unsafe extern "C" fn kfree_aligned(ptr: *mut u8, size: usize, align: usize) {
+        unsafe { kfree_aligned_tagged(ptr, size, align, core::ptr::null()); }
+    }
+
+    /// `kmalloc` recording `tag` (a static string, e.g. the call site) in
+    /// `heap_debug` builds
+    ///
+    /// # Safety
+    /// `tag` must be null or a NUL terminated string that lives forever.
+    #[unsafe(no_mangle)]
+    pub unsafe extern "C" fn kmalloc_tagged(size: usize, tag: *const c_char) -> *mut u8 {
+        unsafe { kmalloc_aligned_tagged(size, 1, tag) }
+    }
+
+    /// `kmalloc_aligned` recording `tag` in `heap_debug` builds
+    ///
+    /// # Safety
+    /// `tag` must be null or a NUL terminated string that lives forever.
+    #[unsafe(no_mangle)]
+    pub unsafe extern "C" fn kmalloc_aligned_tagged(size: usize, align: usize, tag: *const c_char) -> *mut u8 {
         if size == 0 {
             return null_mut();
         }
@@ -81,28 +158,27 @@ pub mod alloc_ffi {
         };
 
         unsafe {
-            ALLOCATOR.alloc(layout)
+            ALLOCATOR.alloc_tagged(layout, tag)
         }
     }
 
+    /// `kfree` recording `tag` in `heap_debug` builds
+    ///
+    /// # Safety
+    /// As for `kfree`, and `tag` must be null or a NUL terminated string that
+    /// lives forever.
     #[unsafe(no_mangle)]
-    pub extern "C" fn kfree(ptr: *mut u8, size: usize) {
-        if ptr.is_null() || size == 0 {
-            return;
-        }
-
-        let layout = match Layout::from_size_align(size, 1) {
-            Ok(layout) => layout,
-            Err(_) => return,
-        };
-
-        unsafe {
-            ALLOCATOR.dealloc(ptr, layout);
-        }
+    pub unsafe extern "C" fn kfree_tagged(ptr: *mut u8, size: usize, tag: *const c_char) {
+        unsafe { kfree_aligned_tagged(ptr, size, 1, tag); }
     }
 
+    /// `kfree_aligned` recording `tag` in `heap_debug` builds
+    ///
+    /// # Safety
+    /// As for `kfree_aligned`, and `tag` must be null or a NUL terminated
+    /// string that lives forever.
     #[unsafe(no_mangle)]
-    pub extern "C" fn kfree_aligned(ptr: *mut u8, size: usize, align: usize) {
+    pub unsafe extern "C" fn kfree_aligned_tagged(ptr: *mut u8, size: usize, align: usize, tag: *const c_char) {
         if ptr.is_null() || size == 0 {
             return;
         }
@@ -113,9 +189,12 @@ pub mod alloc_ffi {
         };
 
         unsafe {
-            ALLOCATOR.dealloc(ptr, layout);
+            ALLOCATOR.dealloc_tagged(ptr, layout, tag);
         }
     }
 }
 
-pub mod free_list;
\ No newline at end of file
+pub mod free_list;
+pub mod stats;
+#[cfg(feature = "heap_debug")]
+pub mod debug;
\ No newline at end of file
@@ -0,0 +1,280 @@
+//! Kernel heap accounting.
+//!
+//! `Tracked` sits between the global allocator and the heap backend and counts
+//! every allocation made through it, whether it comes from `alloc` in Rust or
+//! from `kmalloc` in C. With the `heap_debug` feature each live allocation is
+//! also recorded together with its tag (see [`super::debug`]).
+//!
+//! When the backend runs out of memory, `Tracked` grows the heap window
+//! (see [`super::grow_heap`]) and retries before reporting the failure.
+
+use core::{alloc::{GlobalAlloc, Layout}, ffi::{c_char, CStr}, sync::atomic::{AtomicUsize, Ordering}};
+
+use crate::{GPU_DEVICE, SCALE, SCREENWIDTH, THEME, console_println, memory::allocator::ALLOCATOR, klog, log::Level};
+
+/// Tag attached to an allocation: a NUL terminated string (usually the C call
+/// site) or null when the caller did not supply one.
+pub type AllocTag = *const c_char;
+
+/// Tag used for allocations coming from Rust through the global allocator
+pub const RUST_TAG: AllocTag = c"rust".as_ptr();
+
+/// Room left for allocator headers when growing the heap for one allocation
+const HEADER_ROOM: usize = 64;
+
+/// Heap backends that can report how much memory they have left
+pub trait HeapBackend: GlobalAlloc {
+    /// Hand `[heap_bottom, heap_bottom + heap_size)` to the backend
+    ///
+    /// # Safety
+    /// The region must be mapped, unused by anything else and stay so for
+    /// as long as the backend lives. Called once.
+    unsafe fn init(&self, heap_bottom: *mut u8, heap_size: usize);
+    /// Add `[heap_top, heap_top + by)`, which directly follows the managed memory
+    ///
+    /// # Safety
+    /// Same as `init` for the added region.
+    unsafe fn extend(&self, heap_top: usize, by: usize);
+    /// Total bytes managed by the backend
+    fn size(&self) -> usize;
+    /// Bytes not currently allocated
+    fn free(&self) -> usize;
+    /// Largest single free block, if the backend can tell
+    fn largest_free_block(&self) -> Option<usize>;
+}
+
+#[cfg(not(feature = "free_list_allocator"))]
+impl HeapBackend for linked_list_allocator::LockedHeap {
+    unsafe fn init(&self, heap_bottom: *mut u8, heap_size: usize) {
+        unsafe { self.lock().init(heap_bottom, heap_size); }
+    }
+
+    unsafe fn extend(&self, _heap_top: usize, by: usize) {
+        unsafe { self.lock().extend(by); }
+    }
+
+    fn size(&self) -> usize {
+        self.lock().size()
+    }
+
+    fn free(&self) -> usize {
+        self.lock().free()
+    }
+
+    /// `linked_list_allocator` keeps its holes to itself, so this bisects
+    /// for the largest allocation that succeeds, freeing each probe again.
+    fn largest_free_block(&self) -> Option<usize> {
+        let mut heap = self.lock();
+        // `fits` can be allocated, `fits + 1..=limit` is unknown
+        let (mut fits, mut limit) = (0, heap.free());
+        while fits < limit {
+            let size = fits + (limit - fits).div_ceil(2);
+            let layout = Layout::from_size_align(size, core::mem::align_of::<usize>()).unwrap();
+            match heap.allocate_first_fit(layout) {
+                Ok(ptr) => {
+                    unsafe { heap.deallocate(ptr, layout); }
+                    fits = size;
+                }
+                Err(()) => limit = size - 1,
+            }
+        }
+        Some(fits)
+    }
+}
+
+#[cfg(feature = "free_list_allocator")]
+impl HeapBackend for super::Locked<super::free_list::FreeListAllocator> {
+    unsafe fn init(&self, heap_bottom: *mut u8, heap_size: usize) {
+        unsafe { self.lock().init(heap_bottom, heap_size); }
+    }
+
+    unsafe fn extend(&self, heap_top: usize, by: usize) {
+        unsafe { self.lock().add_region(heap_top, by); }
+    }
+
+    fn size(&self) -> usize {
+        self.lock().size()
+    }
+
+    fn free(&self) -> usize {
+        self.lock().free()
+    }
+
+    fn largest_free_block(&self) -> Option<usize> {
+        Some(self.lock().largest_free_block())
+    }
+}
+
+/// Snapshot of the heap counters
+#[derive(Debug, Clone, Copy)]
+pub struct HeapStats {
+    /// Bytes handed to the heap backend
+    pub heap_size: usize,
+    /// Bytes requested by live allocations
+    pub in_use: usize,
+    /// Highest `in_use` seen since boot
+    pub peak: usize,
+    /// Number of live allocations
+    pub live: usize,
+    /// Number of allocations since boot
+    pub total: usize,
+    /// Number of allocations that returned null
+    pub failed: usize,
+    /// Bytes the backend still has free (headers and padding excluded)
+    pub free: usize,
+    pub largest_free_block: Option<usize>,
+}
+
+/// Global allocator wrapper that keeps the counters in [`HeapStats`]
+pub struct Tracked<A> {
+    inner: A,
+    in_use: AtomicUsize,
+    peak: AtomicUsize,
+    live: AtomicUsize,
+    total: AtomicUsize,
+    failed: AtomicUsize,
+}
+
+impl<A> Tracked<A> {
+    pub const fn new(inner: A) -> Self {
+        Self {
+            inner,
+            in_use: AtomicUsize::new(0),
+            peak: AtomicUsize::new(0),
+            live: AtomicUsize::new(0),
+            total: AtomicUsize::new(0),
+            failed: AtomicUsize::new(0),
+        }
+    }
+}
+
+impl<A: HeapBackend> Tracked<A> {
+    /// # Safety
+    /// See [`HeapBackend::init`].
+    pub unsafe fn init(&self, heap_bottom: *mut u8, heap_size: usize) {
+        unsafe { self.inner.init(heap_bottom, heap_size); }
+    }
+
+    /// # Safety
+    /// See [`HeapBackend::extend`].
+    pub unsafe fn extend(&self, heap_top: usize, by: usize) {
+        unsafe { self.inner.extend(heap_top, by); }
+    }
+
+    pub fn stats(&self) -> HeapStats {
+        HeapStats {
+            heap_size: self.inner.size(),
+            in_use: self.in_use.load(Ordering::Relaxed),
+            peak: self.peak.load(Ordering::Relaxed),
+            live: self.live.load(Ordering::Relaxed),
+            total: self.total.load(Ordering::Relaxed),
+            failed: self.failed.load(Ordering::Relaxed),
+            free: self.inner.free(),
+            largest_free_block: self.inner.largest_free_block(),
+        }
+    }
+
+    /// Allocate through the backend, growing the heap if it is full, and
+    /// count the allocation under `tag`
+    ///
+    /// # Safety
+    /// Same as `GlobalAlloc::alloc`; `tag` must be null or a NUL terminated
+    /// string that outlives the allocation.
+    pub unsafe fn alloc_tagged(&self, layout: Layout, tag: AllocTag) -> *mut u8 {
+        let mut ptr = unsafe { self.inner.alloc(layout) };
+
+        // Map more of the heap window and try again before giving up. The
+        // extra alignment and header room covers the backend's bookkeeping.
+        if ptr.is_null() && super::grow_heap(layout.size() + layout.align() + HEADER_ROOM) != 0 {
+            ptr = unsafe { self.inner.alloc(layout) };
+        }
+
+        if ptr.is_null() {
+            self.failed.fetch_add(1, Ordering::Relaxed);
+            klog!(Level::Error, "MEMORY", "Heap allocation of {} bytes (align {}) from {} failed: {} bytes free.", layout.size(), layout.align(), unsafe { tag_str(tag) }, self.inner.free());
+            return ptr;
+        }
+
+        let in_use = self.in_use.fetch_add(layout.size(), Ordering::Relaxed) + layout.size();
+        self.peak.fetch_max(in_use, Ordering::Relaxed);
+        self.live.fetch_add(1, Ordering::Relaxed);
+        self.total.fetch_add(1, Ordering::Relaxed);
+
+        #[cfg(feature = "heap_debug")]
+        super::debug::record(ptr, layout.size(), tag);
+
+        ptr
+    }
+
+    /// Free through the backend and drop the allocation from the counters
+    ///
+    /// # Safety
+    /// Same as `GlobalAlloc::dealloc`; `tag` must be null or a NUL terminated
+    /// string.
+    pub unsafe fn dealloc_tagged(&self, ptr: *mut u8, layout: Layout, tag: AllocTag) {
+        #[cfg(feature = "heap_debug")]
+        unsafe { super::debug::forget(ptr, layout.size(), tag); }
+        #[cfg(not(feature = "heap_debug"))]
+        let _ = tag;
+
+        self.in_use.fetch_sub(layout.size(), Ordering::Relaxed);
+        self.live.fetch_sub(1, Ordering::Relaxed);
+        unsafe { self.inner.dealloc(ptr, layout); }
+    }
+}
+
+unsafe impl<A: HeapBackend> GlobalAlloc for Tracked<A> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        unsafe { self.alloc_tagged(layout, RUST_TAG) }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        unsafe { self.dealloc_tagged(ptr, layout, RUST_TAG) }
+    }
+}
+
+/// Printable form of an allocation tag
+///
+/// # Safety
+/// `tag` must be null or a NUL terminated string that lives forever, as the
+/// tags passed to `kmalloc_tagged` and friends do.
+pub unsafe fn tag_str(tag: AllocTag) -> &'static str {
+    if tag.is_null() {
+        return "<untagged>";
+    }
+    unsafe { CStr::from_ptr(tag).to_str().unwrap_or("<invalid tag>") }
+}
+
+/// Current heap counters
+pub fn heap_stats() -> HeapStats {
+    ALLOCATOR.stats()
+}
+
+/// Dump the heap counters (and, with `heap_debug`, the live allocations) to the
+/// UART and, once a GPU is up, to the console.
+#[unsafe(no_mangle)]
+pub extern "C" fn heap_report() {
+    let stats = heap_stats();
+    let largest = match stats.largest_free_block {
+        Some(bytes) => alloc::format!("{} bytes", bytes),
+        None => alloc::string::String::from("n/a"),
+    };
+    let console = unsafe { GPU_DEVICE }.is_some();
+    let theme = unsafe { THEME };
+
+    klog!(Level::Info, "MEMORY", "Heap: {} / {} bytes in use (peak {}), {} bytes free, largest free block {}", stats.in_use, stats.heap_size, stats.peak, stats.free, largest);
+    klog!(Level::Info, "MEMORY", "Heap: {} live allocations, {} since boot, {} failed", stats.live, stats.total, stats.failed);
+    if console {
+        console_println!("[  MEMORY  ] Heap: {} / {} bytes in use (peak {})", stats.in_use, stats.heap_size, stats.peak ; color: theme.debug());
+        console_println!("[  MEMORY  ] Heap: {} live, {} total, {} failed, largest free {}", stats.live, stats.total, stats.failed, largest ; color: theme.debug());
+    }
+
+    #[cfg(feature = "heap_debug")]
+    for site in super::debug::live_by_tag().iter().flatten() {
+        let tag = unsafe { tag_str(site.tag) };
+        klog!(Level::Info, "MEMORY", "  {:>6} x {:>8} bytes  {}", site.count, site.bytes, tag);
+        if console {
+            console_println!("[  MEMORY  ]   {:>6} x {:>8} bytes  {}", site.count, site.bytes, tag ; color: theme.debug());
+        }
+    }
+}
@@ -0,0 +1,66 @@
+//! Data and instruction cache maintenance.
+//!
+//! RAM is mapped Write-Back cacheable, so memory shared with a device that
+//! does not snoop the caches has to be cleaned before the device reads it and
+//! invalidated before the CPU reads what the device wrote.
+
+use core::arch::asm;
+
+/// Smallest data cache line in the system (CTR_EL0.DminLine)
+fn dcache_line_size() -> usize {
+    let ctr: u64;
+    unsafe { asm!("mrs {}, ctr_el0", out(reg) ctr); }
+    4 << ((ctr >> 16) & 0xf)
+}
+
+/// Line-aligned bounds of `[addr, addr + size)`
+fn line_range(addr: usize, size: usize) -> (usize, usize, usize) {
+    let line = dcache_line_size();
+    (addr & !(line - 1), (addr + size).next_multiple_of(line), line)
+}
+
+/// Write dirty lines in `[addr, addr + size)` back to memory, so a device
+/// reading it sees what the CPU wrote.
+#[unsafe(no_mangle)]
+pub extern "C" fn clean_dcache_range(addr: usize, size: usize) {
+    let (start, end, line) = line_range(addr, size);
+    for va in (start..end).step_by(line) {
+        unsafe { asm!("dc cvac, {}", in(reg) va); }
+    }
+    unsafe { asm!("dsb sy"); }
+}
+
+/// Discard cached copies of `[addr, addr + size)`, so the CPU sees what a
+/// device wrote. Lines only partly inside the range are cleaned first, to
+/// keep whatever shares them.
+#[unsafe(no_mangle)]
+pub extern "C" fn invalidate_dcache_range(addr: usize, size: usize) {
+    let (start, end, line) = line_range(addr, size);
+    for va in (start..end).step_by(line) {
+        let partial = va < addr || va + line > addr + size;
+        unsafe {
+            if partial {
+                asm!("dc civac, {}", in(reg) va);
+            } else {
+                asm!("dc ivac, {}", in(reg) va);
+            }
+        }
+    }
+    unsafe { asm!("dsb sy"); }
+}
+
+/// Clean then invalidate `[addr, addr + size)`: used before handing a buffer
+/// to a device that will write to it.
+#[unsafe(no_mangle)]
+pub extern "C" fn clean_invalidate(addr: usize, size: usize) {
+    let (start, end, line) = line_range(addr, size);
+    for va in (start..end).step_by(line) {
+        unsafe { asm!("dc civac, {}", in(reg) va); }
+    }
+    unsafe { asm!("dsb sy"); }
+}
+
+/// Invalidate the whole instruction cache (after writing code).
+pub fn invalidate_icache() {
+    unsafe { asm!("ic iallu", "dsb nsh", "isb"); }
+}
@@ -0,0 +1,247 @@
+//! Physical page frame allocator.
+//!
+//! One bit per 4KiB frame of RAM (1 = in use). RAM banks come from the device
+//! tree `/memory` nodes; the kernel image, boot stack, DTB blob and any firmware
+//! reservations are marked used before the first allocation.
+//!
+//! This runs before the heap exists (the heap itself is built from these
+//! frames), so nothing in here may allocate.
+//!
+//! Frames are handed out as physical addresses, to Rust and to C alike.
+
+use crate::{boot_info::BootInfo, drivers::{dtb_parser::{DeviceTreeParser, DtRegion, MemoryKind}, platform::{self, platform}}, memory::{allocator::Locked, layout::virt_to_phys}, klog, log::Level};
+
+pub const PAGE_SIZE: u64 = 4096;
+
+/// Largest amount of RAM the bitmap can describe (8GiB).
+const MAX_FRAMES: usize = (8 << 30) / PAGE_SIZE as usize;
+const BITMAP_WORDS: usize = MAX_FRAMES / 64;
+
+unsafe extern "C" {
+    static kernel_start: u8;
+    static kernel_end: u8;
+    static stack_guard: u8;
+    static stack_top: u8;
+}
+
+static FRAME_ALLOCATOR: Locked<BitmapFrameAllocator> = Locked::new(BitmapFrameAllocator::new());
+
+pub struct BitmapFrameAllocator {
+    /// Physical address of frame 0
+    base: u64,
+    /// Number of frames covered by the bitmap
+    frames: usize,
+    /// Number of frames currently free
+    free: usize,
+    /// Where the next single-frame search starts
+    next: usize,
+    bitmap: [u64; BITMAP_WORDS],
+}
+
+impl Default for BitmapFrameAllocator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl BitmapFrameAllocator {
+    pub const fn new() -> Self {
+        Self { base: 0, frames: 0, free: 0, next: 0, bitmap: [0; BITMAP_WORDS] }
+    }
+
+    fn is_used(&self, frame: usize) -> bool {
+        self.bitmap[frame / 64] & (1 << (frame % 64)) != 0
+    }
+
+    fn set_used(&mut self, frame: usize) {
+        if !self.is_used(frame) {
+            self.bitmap[frame / 64] |= 1 << (frame % 64);
+            self.free -= 1;
+        }
+    }
+
+    fn set_free(&mut self, frame: usize) {
+        if self.is_used(frame) {
+            self.bitmap[frame / 64] &= !(1 << (frame % 64));
+            self.free += 1;
+        }
+    }
+
+    /// Frame index range fully inside `[start, end)`
+    fn inner_frames(&self, start: u64, end: u64) -> core::ops::Range<usize> {
+        let start = start.max(self.base).div_ceil(PAGE_SIZE) * PAGE_SIZE;
+        let end = (end.min(self.base + self.frames as u64 * PAGE_SIZE) / PAGE_SIZE) * PAGE_SIZE;
+        if end <= start { return 0..0; }
+        ((start - self.base) / PAGE_SIZE) as usize..((end - self.base) / PAGE_SIZE) as usize
+    }
+
+    /// Frame index range touching any byte of `[start, end)`
+    fn outer_frames(&self, start: u64, end: u64) -> core::ops::Range<usize> {
+        let start = (start.max(self.base) / PAGE_SIZE) * PAGE_SIZE;
+        let end = end.min(self.base + self.frames as u64 * PAGE_SIZE).div_ceil(PAGE_SIZE) * PAGE_SIZE;
+        if end <= start { return 0..0; }
+        ((start - self.base) / PAGE_SIZE) as usize..((end - self.base) / PAGE_SIZE) as usize
+    }
+
+    /// Cover every bank in `ram`, starting with all frames in use.
+    pub fn init(&mut self, ram: &[DtRegion]) {
+        let low = ram.iter().map(|r| r.address).min().unwrap_or(0) / PAGE_SIZE * PAGE_SIZE;
+        let high = ram.iter().map(|r| r.end()).max().unwrap_or(0);
+
+        self.base = low;
+        self.frames = (((high - low) / PAGE_SIZE) as usize).min(MAX_FRAMES);
+        self.free = 0;
+        self.next = 0;
+        self.bitmap.fill(u64::MAX);
+
+        if ((high - low) / PAGE_SIZE) as usize > MAX_FRAMES {
+            klog!(Level::Warn, "MEMORY", "Only the first {} MiB of RAM are usable by the frame allocator.", (MAX_FRAMES as u64 * PAGE_SIZE) >> 20);
+        }
+
+        for bank in ram {
+            for frame in self.inner_frames(bank.address, bank.end()) {
+                self.set_free(frame);
+            }
+        }
+    }
+
+    /// Permanently mark `[start, end)` as used.
+    pub fn reserve(&mut self, start: u64, end: u64) {
+        for frame in self.outer_frames(start, end) {
+            self.set_used(frame);
+        }
+    }
+
+    pub fn alloc_frame(&mut self) -> Option<u64> {
+        if self.free == 0 { return None; }
+
+        let mut frame = self.next % self.frames;
+        let mut scanned = 0;
+        while scanned < self.frames {
+            // Skip whole words that are fully used
+            let step = if frame.is_multiple_of(64) && self.bitmap[frame / 64] == u64::MAX {
+                64
+            } else if !self.is_used(frame) {
+                self.set_used(frame);
+                self.next = frame + 1;
+                return Some(self.base + frame as u64 * PAGE_SIZE);
+            } else {
+                1
+            };
+            scanned += step;
+            frame += step;
+            if frame >= self.frames { frame = 0; }
+        }
+        None
+    }
+
+    /// Allocate `count` physically contiguous frames whose start is aligned to `align` bytes.
+    pub fn alloc_contiguous(&mut self, count: usize, align: u64) -> Option<u64> {
+        if count == 0 || self.free < count { return None; }
+        let align = align.max(PAGE_SIZE);
+        if !align.is_power_of_two() { return None; }
+
+        // First candidate frame index that satisfies the alignment
+        let mut start = ((self.base.next_multiple_of(align) - self.base) / PAGE_SIZE) as usize;
+        let step = (align / PAGE_SIZE) as usize;
+
+        while start + count <= self.frames {
+            match (start..start + count).rev().find(|&f| self.is_used(f)) {
+                None => {
+                    for frame in start..start + count {
+                        self.set_used(frame);
+                    }
+                    return Some(self.base + start as u64 * PAGE_SIZE);
+                }
+                // Jump to the first aligned candidate past the used frame
+                Some(used) => start = used + 1 + (start + step - (used + 1) % step) % step,
+            }
+        }
+        None
+    }
+
+    pub fn free_frames(&mut self, addr: u64, count: usize) {
+        if addr < self.base || !addr.is_multiple_of(PAGE_SIZE) { return; }
+        let first = ((addr - self.base) / PAGE_SIZE) as usize;
+        for frame in first..(first + count).min(self.frames) {
+            self.set_free(frame);
+        }
+        self.next = self.next.min(first);
+    }
+
+    pub fn free_count(&self) -> usize {
+        self.free
+    }
+
+    pub fn total_count(&self) -> usize {
+        self.frames
+    }
+}
+
+/// Set up the frame allocator from the boot memory map (or the platform
+/// defaults when there is none) and reserve everything the kernel already
+/// occupies, the device tree's reservations included.
+pub fn init(boot_info: &BootInfo, dtb: Option<&DeviceTreeParser>) {
+    let default = [DtRegion { address: platform().mem_base, size: platform().mem_size }];
+    let ram = match boot_info.memory() {
+        [] => &default[..],
+        banks => banks,
+    };
+    platform::set_memory(ram[0]);
+
+    // boot64.s moved the kernel to its link address whatever RAM the loader
+    // found, which only works if that address is RAM here
+    let image = (virt_to_phys(&raw const kernel_start as u64), virt_to_phys(&raw const stack_top as u64));
+    if !ram.iter().any(|bank| bank.address <= image.0 && image.1 <= bank.end()) {
+        panic!("Kernel image at {:#x}..{:#x} is not in RAM, KERNEL_LOAD_ADDR (linker64.ld) does not suit this machine", image.0, image.1);
+    }
+
+    let mut allocator = FRAME_ALLOCATOR.lock();
+    allocator.init(ram);
+
+    // Kernel image and boot stack (the linker symbols are direct map addresses)
+    allocator.reserve(virt_to_phys(&raw const kernel_start as u64), virt_to_phys(&raw const kernel_end as u64));
+    allocator.reserve(virt_to_phys(&raw const stack_guard as u64), virt_to_phys(&raw const stack_top as u64));
+
+    if let Some(dtb) = dtb {
+        let blob = virt_to_phys(dtb.base() as u64);
+        allocator.reserve(blob, blob + dtb.total_size() as u64);
+        dtb.scan_memory(|kind, region| {
+            if kind == MemoryKind::Reserved {
+                allocator.reserve(region.address, region.end());
+            }
+        });
+    }
+
+    klog!(Level::Info, "MEMORY", "Frame allocator: {} / {} frames free ({} MiB).", allocator.free_count(), allocator.total_count(), (allocator.free_count() as u64 * PAGE_SIZE) >> 20);
+}
+
+/// Allocate one 4KiB physical frame. Returns 0 if memory is exhausted.
+#[unsafe(no_mangle)]
+pub extern "C" fn alloc_frame() -> u64 {
+    FRAME_ALLOCATOR.lock().alloc_frame().unwrap_or(0)
+}
+
+/// Return a frame obtained from `alloc_frame`.
+#[unsafe(no_mangle)]
+pub extern "C" fn free_frame(addr: u64) {
+    FRAME_ALLOCATOR.lock().free_frames(addr, 1);
+}
+
+/// Allocate `count` contiguous frames aligned to `align` bytes (at least 4KiB).
+/// Returns 0 if no such run exists.
+#[unsafe(no_mangle)]
+pub extern "C" fn alloc_contiguous(count: usize, align: usize) -> u64 {
+    FRAME_ALLOCATOR.lock().alloc_contiguous(count, align as u64).unwrap_or(0)
+}
+
+/// Return `count` frames obtained from `alloc_contiguous`.
+#[unsafe(no_mangle)]
+pub extern "C" fn free_contiguous(addr: u64, count: usize) {
+    FRAME_ALLOCATOR.lock().free_frames(addr, count);
+}
+
+/// Number of free frames.
+pub fn free_frame_count() -> usize {
+    FRAME_ALLOCATOR.lock().free_count()
+}
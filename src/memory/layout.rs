@@ -0,0 +1,48 @@
+//! Virtual memory layout.
+//!
+//! ```text
+//! 0x0000_0000_0000_0000 - 0x0000_ffff_ffff_ffff   user space (TTBR0, one per process)
+//! 0xffff_0000_0000_0000 - 0xffff_7fff_ffff_ffff   direct map of physical memory (TTBR1)
+//!     0xffff_0000_4000_0000                       kernel image, linked inside the direct map
+//! 0xffff_8000_0000_0000 - 0xffff_8000_ffff_ffff   kernel heap window
+//! 0xffff_8001_0000_0000 - ...                     kernel thread stacks, each above a guard page
+//! ```
+//!
+//! Physical address `pa` is reachable at `PHYS_OFFSET + pa`. Devices only
+//! understand physical addresses, so anything handed to a DMA engine has to
+//! go through [`virt_to_phys`] first.
+
+use crate::memory::paging;
+
+/// End of the lower half: user address spaces live below this
+pub const USER_SPACE_END: u64 = 0x0001_0000_0000_0000;
+/// Start of the upper half, translated through the kernel's TTBR1 tables
+pub const KERNEL_SPACE_START: u64 = 0xffff_0000_0000_0000;
+/// Start of the direct map of physical memory
+pub const PHYS_OFFSET: u64 = KERNEL_SPACE_START;
+/// Size of the direct map (128TiB)
+pub const DIRECT_MAP_SIZE: u64 = 0x0000_8000_0000_0000;
+/// Start of the window kernel thread stacks are mapped in
+pub const THREAD_STACKS_START: u64 = 0xffff_8001_0000_0000;
+
+/// Direct map address of physical address `pa`.
+#[unsafe(no_mangle)]
+pub extern "C" fn phys_to_virt(pa: u64) -> u64 {
+    pa + PHYS_OFFSET
+}
+
+/// Physical address behind kernel virtual address `va`, or 0 if it is not
+/// mapped. Direct map addresses are converted directly, anything else (the
+/// heap) is looked up in the kernel page tables.
+#[unsafe(no_mangle)]
+pub extern "C" fn virt_to_phys(va: u64) -> u64 {
+    if (PHYS_OFFSET..PHYS_OFFSET + DIRECT_MAP_SIZE).contains(&va) {
+        return va - PHYS_OFFSET;
+    }
+    paging::translate(va).unwrap_or(0)
+}
+
+/// Whether `va` belongs to the kernel half of the address space.
+pub fn is_kernel_address(va: u64) -> bool {
+    va >= KERNEL_SPACE_START
+}
@@ -1,4 +1,7 @@
 pub mod mmio;
 pub mod mmu;
 pub mod allocator;
-pub mod paging;
\ No newline at end of file
+pub mod frame_allocator;
+pub mod paging;
+pub mod cache;
+pub mod layout;pub mod vma;
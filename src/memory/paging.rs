@@ -1,39 +1,632 @@
-//! Helper functions for the main paging implementation (C source)
-
-use core::arch::asm;
-
-// #[unsafe(no_mangle)]
-// pub unsafe extern "C" fn load_config_tcr_el1(config: usize) {
-//     asm!("ldr x0, ={}", in(reg) config);
-//     asm!("msr tcr_el1, x0");
-// }
-
-// #[unsafe(no_mangle)]
-// pub unsafe extern "C" fn load_config_mair_el1(config: usize) {
-//     asm!("ldr x0, ={}", in(reg) config);
-//     asm!("msr mair_el1, x0");
-// }
-
-// #[unsafe(no_mangle)]
-// pub unsafe extern "C" fn identity_paging_setup(boot_pgd_attr: usize, boot_pud_attr: usize) {
-//     asm!("mov x0, #0"); // PGD's page frame at 0x0
-//     asm!("mov x1, #0x1000"); // PUD's page frame at 0x1000
-
-//     asm!("ldr x2, ={}", in(reg) boot_pgd_attr);
-//     asm!("orr x2, x1, x2"); // Combine the physical address of next level page with attr
-//     asm!("str x2, [x0]");
-
-//     asm!("ldr x2, ={}", in(reg) boot_pud_attr);
-//     asm!("mov x3, #0x00000000");
-//     asm!("orr x3, x2, x3");
-//     asm!("str x3, [x1]"); // 1st 1GB mapped by the 1st entry of PUD
-//     asm!("mov x3, #0x40000000");
-//     asm!("orr x3, x2, x3");
-//     asm!("str x3, [x1, #8]"); // 2nd 1GB mapped by the 2nd entry of PUD
-
-//     asm!("msr ttbr0_el1, x0"); // Load PGD to the bottom translation based register
-    
-//     asm!("mrs x2, sctlr_el1");
-//     asm!("orr x2, x2, #1");
-//     asm!("msr sctlr_el1, x2"); // Enable MMU, cache remains disabled
-// }
\ No newline at end of file
+//! Page table management.
+//!
+//! 4KiB granule, 48-bit virtual addresses, four levels (L0-L3). `map` picks the
+//! largest descriptor that fits (1GiB block at L1, 2MiB block at L2, 4KiB page
+//! at L3) and splits existing blocks when a smaller mapping has to go inside
+//! one. Page tables are physical frames from the frame allocator.
+//!
+//! The kernel runs in the upper half (see [`crate::memory::layout`]). Its
+//! address space lives in [`KERNEL_SPACE`] and is loaded into TTBR1; `init`
+//! builds it, replacing the 1GiB boot tables set up in `boot64.s`, and turns
+//! the caches on. TTBR0 is left for user address spaces
+//! ([`activate_user_space`]). Normal memory is Write-Back cacheable, see
+//! [`crate::memory::cache`] for keeping DMA buffers coherent.
+
+use core::{arch::asm, ops::{BitAnd, BitOr, BitOrAssign, Not}};
+
+use crate::{drivers::platform::platform, exceptions::irq::without_interrupts, memory::{frame_allocator::{PAGE_SIZE, alloc_frame, free_frame}, mmu::verify_MMU, cache::invalidate_icache, layout::{self, is_kernel_address}}, serial_println};
+
+unsafe extern "C" {
+    static kernel_start: u8;
+    static kernel_text_end: u8;
+}
+
+/// MAIR attribute indices
+pub const MAIR_IDX_DEVICE: u64 = 0;
+pub const MAIR_IDX_NORMAL: u64 = 1;
+
+/// MAIR encodings for the indices above
+const MAIR_DEVICE_NGNRNE: u64 = 0b0000_0000;
+/// Normal memory, inner and outer Write-Back, read/write allocate
+const MAIR_NORMAL_WB: u64 = 0b1111_1111;
+
+/// TCR_EL1 granule encodings (TG0 and TG1 differ)
+const TCR_TG0_4K: u64 = 0b00;
+const TCR_TG1_4K: u64 = 0b10;
+
+const ENTRIES: usize = 512;
+/// Address shift of each level (L0 to L3)
+const LEVEL_SHIFT: [u32; 4] = [39, 30, 21, 12];
+
+/// Device registers, EL1 only, never executable
+pub const PAGE_DEVICE: u64 = PageFlags::UXN.0 | PageFlags::PXN.0 | PageFlags::ATTR_DEVICE.0;
+/// Kernel data: normal memory, EL1 read/write, never executable
+pub const PAGE_KERNEL_DATA: u64 = PageFlags::UXN.0 | PageFlags::PXN.0 | PageFlags::SH_INNER.0 | PageFlags::ATTR_NORMAL.0;
+/// Kernel text: normal memory, EL1 read/write/execute
+pub const PAGE_KERNEL_RWX: u64 = PageFlags::UXN.0 | PageFlags::SH_INNER.0 | PageFlags::ATTR_NORMAL.0;
+
+/// Attribute bits of a block or page descriptor.
+///
+/// The descriptor type bits and the access flag are filled in by the mapper.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PageFlags(u64);
+
+impl PageFlags {
+    /// MAIR index of device memory
+    pub const ATTR_DEVICE: Self = Self(MAIR_IDX_DEVICE << 2);
+    /// MAIR index of normal memory
+    pub const ATTR_NORMAL: Self = Self(MAIR_IDX_NORMAL << 2);
+    /// AP[1]: accessible from EL0
+    pub const USER: Self = Self(1 << 6);
+    /// AP[2]: read-only
+    pub const READ_ONLY: Self = Self(1 << 7);
+    /// Outer shareable
+    pub const SH_OUTER: Self = Self(0b10 << 8);
+    /// Inner shareable
+    pub const SH_INNER: Self = Self(0b11 << 8);
+    /// Access flag
+    pub const ACCESSED: Self = Self(1 << 10);
+    /// Entry is tagged with the current ASID
+    pub const NOT_GLOBAL: Self = Self(1 << 11);
+    /// Not executable at EL1
+    pub const PXN: Self = Self(1 << 53);
+    /// Not executable at EL0
+    pub const UXN: Self = Self(1 << 54);
+
+    pub const DEVICE: Self = Self(PAGE_DEVICE);
+    pub const KERNEL_DATA: Self = Self(PAGE_KERNEL_DATA);
+    pub const KERNEL_RWX: Self = Self(PAGE_KERNEL_RWX);
+
+    /// Every bit a `PageFlags` may hold
+    const MASK: u64 = 0b1111_1111_1100 | (0xfff << 52);
+
+    pub const fn empty() -> Self {
+        Self(0)
+    }
+
+    pub const fn bits(&self) -> u64 {
+        self.0
+    }
+
+    /// Keep only the bits that are descriptor attributes.
+    pub const fn from_bits_truncate(bits: u64) -> Self {
+        Self(bits & Self::MASK)
+    }
+
+    pub const fn contains(&self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl BitOr for PageFlags {
+    type Output = Self;
+    fn bitor(self, rhs: Self) -> Self {
+        Self(self.0 | rhs.0)
+    }
+}
+
+impl BitOrAssign for PageFlags {
+    fn bitor_assign(&mut self, rhs: Self) {
+        self.0 |= rhs.0;
+    }
+}
+
+impl BitAnd for PageFlags {
+    type Output = Self;
+    fn bitand(self, rhs: Self) -> Self {
+        Self(self.0 & rhs.0)
+    }
+}
+
+impl Not for PageFlags {
+    type Output = Self;
+    fn not(self) -> Self {
+        Self(!self.0 & Self::MASK)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(transparent)]
+pub struct PageTableEntry(u64);
+
+impl PageTableEntry {
+    const VALID: u64 = 1 << 0;
+    /// Table descriptor at L0-L2, page descriptor at L3
+    const TABLE_OR_PAGE: u64 = 1 << 1;
+    const ADDRESS_MASK: u64 = 0x0000_ffff_ffff_f000;
+
+    pub const fn empty() -> Self {
+        Self(0)
+    }
+
+    fn table(pa: u64) -> Self {
+        Self((pa & Self::ADDRESS_MASK) | Self::TABLE_OR_PAGE | Self::VALID)
+    }
+
+    /// Block (L1/L2) or page (L3) descriptor
+    fn leaf(pa: u64, flags: PageFlags, level: usize) -> Self {
+        let kind = if level == 3 { Self::TABLE_OR_PAGE | Self::VALID } else { Self::VALID };
+        Self((pa & Self::ADDRESS_MASK) | (flags | PageFlags::ACCESSED).bits() | kind)
+    }
+
+    pub fn bits(&self) -> u64 {
+        self.0
+    }
+
+    pub fn is_valid(&self) -> bool {
+        self.0 & Self::VALID != 0
+    }
+
+    /// Points to a next level table (only possible at L0-L2).
+    pub fn is_table(&self, level: usize) -> bool {
+        level < 3 && self.is_valid() && self.0 & Self::TABLE_OR_PAGE != 0
+    }
+
+    /// Maps memory directly: a block at L1/L2 or a page at L3.
+    pub fn is_leaf(&self, level: usize) -> bool {
+        self.is_valid() && !self.is_table(level)
+    }
+
+    pub fn address(&self) -> u64 {
+        self.0 & Self::ADDRESS_MASK
+    }
+
+    pub fn flags(&self) -> PageFlags {
+        PageFlags::from_bits_truncate(self.0)
+    }
+}
+
+#[repr(C, align(4096))]
+pub struct PageTable {
+    pub entries: [PageTableEntry; ENTRIES],
+}
+
+/// Bytes mapped by one entry at `level`
+const fn entry_size(level: usize) -> u64 {
+    1 << LEVEL_SHIFT[level]
+}
+
+fn index(va: u64, level: usize) -> usize {
+    ((va >> LEVEL_SHIFT[level]) & (ENTRIES as u64 - 1)) as usize
+}
+
+/// A page table frame, reached through the direct map
+fn table_at(pa: u64) -> &'static mut PageTable {
+    unsafe { &mut *(layout::phys_to_virt(pa) as *mut PageTable) }
+}
+
+/// A zeroed page table frame.
+fn alloc_table() -> Result<u64, &'static str> {
+    let frame = alloc_frame();
+    if frame == 0 {
+        return Err("[  PAGING   ] \x1b[1;31mOut of physical memory for page tables\x1b[0m");
+    }
+    unsafe { core::ptr::write_bytes(layout::phys_to_virt(frame) as *mut PageTable, 0, 1); }
+    Ok(frame)
+}
+
+/// Free a table and every table below it (mapped memory is left alone).
+fn free_tables(pa: u64, level: usize) {
+    if level < 3 {
+        for entry in table_at(pa).entries.iter().filter(|e| e.is_table(level)) {
+            free_tables(entry.address(), level + 1);
+        }
+    }
+    free_frame(pa);
+}
+
+/// Drop the TLB entries translating `va` on every core.
+fn flush_va(va: u64) {
+    unsafe { asm!("dsb ishst", "tlbi vae1is, {}", "dsb ish", "isb", in(reg) (va >> 12) & 0xfff_ffff_ffff); }
+}
+
+/// Make new entries visible to the table walker.
+fn sync() {
+    unsafe { asm!("dsb ishst", "isb"); }
+}
+
+/// Replace the live `entry` at `level` (mapping `va`) with `new`,
+/// break-before-make: the entry is made invalid and dropped from every TLB
+/// before `new` goes in. A block is one TLB entry, but a table may have left
+/// any page below it cached, so replacing a table flushes the whole TLB. The
+/// range is unmapped in between, so this is one asm sequence, with IRQs
+/// masked, that touches nothing but the entry.
+fn break_before_make(entry: &mut PageTableEntry, new: PageTableEntry, va: u64, level: usize) {
+    let table = entry.is_table(level);
+    without_interrupts(|| unsafe {
+        if table {
+            asm!(
+                "str xzr, [{entry}]",
+                "dsb ishst",
+                "tlbi vmalle1is",
+                "dsb ish",
+                "str {new}, [{entry}]",
+                "dsb ishst",
+                "isb",
+                entry = in(reg) entry as *mut PageTableEntry,
+                new = in(reg) new.0,
+            );
+        } else {
+            asm!(
+                "str xzr, [{entry}]",
+                "dsb ishst",
+                "tlbi vae1is, {page}",
+                "dsb ish",
+                "str {new}, [{entry}]",
+                "dsb ishst",
+                "isb",
+                entry = in(reg) entry as *mut PageTableEntry,
+                page = in(reg) (va >> 12) & 0xfff_ffff_ffff,
+                new = in(reg) new.0,
+            );
+        }
+    });
+}
+
+/// One set of translation tables, identified by its L0 table. The kernel's is
+/// [`KERNEL_SPACE`]; user address spaces are created with `new` and loaded
+/// with [`activate_user_space`].
+pub struct AddressSpace {
+    root: u64,
+}
+
+impl AddressSpace {
+    pub fn new() -> Result<Self, &'static str> {
+        Ok(Self { root: alloc_table()? })
+    }
+
+    /// Physical address of the L0 table (for TTBRn_EL1)
+    pub fn root(&self) -> u64 {
+        self.root
+    }
+
+    /// The table an `entry` at `level` points to, creating it if the entry is
+    /// empty or splitting the block it maps into entries one level down.
+    fn next_table(entry: &mut PageTableEntry, level: usize, va: u64) -> Result<&'static mut PageTable, &'static str> {
+        if entry.is_table(level) {
+            return Ok(table_at(entry.address()));
+        }
+
+        let table = alloc_table()?;
+        if entry.is_leaf(level) {
+            // Same output addresses and attributes, just at a finer grain
+            let (base, flags) = (entry.address(), entry.flags());
+            for (i, child) in table_at(table).entries.iter_mut().enumerate() {
+                *child = PageTableEntry::leaf(base + i as u64 * entry_size(level + 1), flags, level + 1);
+            }
+            break_before_make(entry, PageTableEntry::table(table), va, level);
+        } else {
+            *entry = PageTableEntry::table(table);
+        }
+        Ok(table_at(table))
+    }
+
+    /// Map `[va, va + size)` to `[pa, pa + size)`. Existing mappings in the
+    /// range are replaced. All three values must be 4KiB aligned.
+    pub fn map(&mut self, va: u64, pa: u64, size: u64, flags: PageFlags) -> Result<(), &'static str> {
+        if !(va | pa | size).is_multiple_of(PAGE_SIZE) {
+            return Err("[  PAGING   ] \x1b[1;31mUnaligned mapping\x1b[0m");
+        }
+
+        let mut offset = 0;
+        while offset < size {
+            let (va, pa, left) = (va + offset, pa + offset, size - offset);
+
+            // Largest descriptor both addresses are aligned for (L3 always fits)
+            let level = (1..=3)
+                .find(|&l| va % entry_size(l) == 0 && pa % entry_size(l) == 0 && left >= entry_size(l))
+                .unwrap_or(3);
+
+            let mut table = table_at(self.root);
+            for l in 0..level {
+                table = Self::next_table(&mut table.entries[index(va, l)], l, va)?;
+            }
+
+            let entry = &mut table.entries[index(va, level)];
+            let old = *entry;
+            let new = PageTableEntry::leaf(pa, flags, level);
+
+            if old.is_table(level) {
+                // The old tables are only freed once no walk can still use them
+                break_before_make(entry, new, va, level);
+                free_tables(old.address(), level + 1);
+            } else {
+                *entry = new;
+                if old.is_valid() {
+                    flush_va(va);
+                }
+            }
+
+            offset += entry_size(level);
+        }
+
+        sync();
+        Ok(())
+    }
+
+    /// Remove every mapping in `[va, va + size)`, splitting blocks that are
+    /// only partly covered.
+    pub fn unmap(&mut self, va: u64, size: u64) -> Result<(), &'static str> {
+        if !(va | size).is_multiple_of(PAGE_SIZE) {
+            return Err("[  PAGING   ] \x1b[1;31mUnaligned unmapping\x1b[0m");
+        }
+
+        let mut offset = 0;
+        'outer: while offset < size {
+            let (va, left) = (va + offset, size - offset);
+            let mut table = table_at(self.root);
+
+            for level in 0..4 {
+                let entry = &mut table.entries[index(va, level)];
+                let span = entry_size(level);
+
+                if !entry.is_valid() {
+                    // Nothing mapped up to the end of this entry
+                    offset += (span - va % span).min(left);
+                    continue 'outer;
+                }
+
+                if entry.is_leaf(level) && va % span == 0 && left >= span {
+                    *entry = PageTableEntry::empty();
+                    flush_va(va);
+                    offset += span;
+                    continue 'outer;
+                }
+
+                table = Self::next_table(entry, level, va)?;
+            }
+        }
+
+        sync();
+        Ok(())
+    }
+
+    /// The descriptor mapping `va` and its level, if any.
+    pub fn entry(&self, va: u64) -> Option<(PageTableEntry, usize)> {
+        let mut table = table_at(self.root);
+        for level in 0..4 {
+            let entry = table.entries[index(va, level)];
+            if !entry.is_valid() {
+                return None;
+            }
+            if entry.is_leaf(level) {
+                return Some((entry, level));
+            }
+            table = table_at(entry.address());
+        }
+        None
+    }
+
+    /// Physical address `va` translates to.
+    pub fn translate(&self, va: u64) -> Option<u64> {
+        self.entry(va).map(|(entry, level)| entry.address() + (va & (entry_size(level) - 1)))
+    }
+
+    /// The entries a table walk for `va` reads, from L0 down to the leaf or
+    /// the first invalid entry.
+    pub fn walk(&self, va: u64) -> [Option<PageTableEntry>; 4] {
+        walk_from(self.root, va)
+    }
+}
+
+fn walk_from(root: u64, va: u64) -> [Option<PageTableEntry>; 4] {
+    let mut walk = [None; 4];
+    if root == 0 {
+        return walk;
+    }
+
+    let mut table = table_at(root);
+    for (level, slot) in walk.iter_mut().enumerate() {
+        let entry = table.entries[index(va, level)];
+        *slot = Some(entry);
+        if !entry.is_table(level) {
+            break;
+        }
+        table = table_at(entry.address());
+    }
+    walk
+}
+
+/// Print the table walk for `va` through whatever tables the CPU is using
+/// (TTBR1 for kernel addresses, TTBR0 below). Takes no locks, so it is safe
+/// to call from the fault handler.
+pub fn dump_walk(va: u64) {
+    let ttbr: u64;
+    unsafe {
+        if is_kernel_address(va) {
+            asm!("mrs {}, ttbr1_el1", out(reg) ttbr);
+        } else {
+            asm!("mrs {}, ttbr0_el1", out(reg) ttbr);
+        }
+    }
+    let root = ttbr & PageTableEntry::ADDRESS_MASK;
+    serial_println!("[  PAGING   ] Table walk for {:#018x} (root {:#x}):", va, root);
+    if root == 0 {
+        serial_println!("[  PAGING   ]   no tables loaded");
+        return;
+    }
+
+    for (level, entry) in walk_from(root, va).iter().enumerate() {
+        let Some(entry) = entry else { break };
+        let kind = if !entry.is_valid() {
+            "invalid"
+        } else if entry.is_table(level) {
+            "table"
+        } else if level == 3 {
+            "page"
+        } else {
+            "block"
+        };
+        serial_println!("[  PAGING   ]   L{}[{:>3}] = {:#018x} {}", level, index(va, level), entry.bits(), kind);
+    }
+}
+
+impl Drop for AddressSpace {
+    /// Free the tables (the memory they map is left to its owner).
+    fn drop(&mut self) {
+        if self.root != 0 {
+            free_tables(self.root, 0);
+        }
+    }
+}
+
+/// The kernel's translation tables (TTBR1_EL1)
+pub static KERNEL_SPACE: spin::Mutex<AddressSpace> = spin::Mutex::new(AddressSpace { root: 0 });
+
+/// Map a range in the kernel address space.
+pub fn map(va: u64, pa: u64, size: u64, flags: PageFlags) -> Result<(), &'static str> {
+    if !is_kernel_address(va) {
+        return Err("[  PAGING   ] \x1b[1;31mNot a kernel address\x1b[0m");
+    }
+    KERNEL_SPACE.lock().map(va, pa, size, flags)
+}
+
+/// Unmap a range in the kernel address space.
+pub fn unmap(va: u64, size: u64) -> Result<(), &'static str> {
+    if !is_kernel_address(va) {
+        return Err("[  PAGING   ] \x1b[1;31mNot a kernel address\x1b[0m");
+    }
+    KERNEL_SPACE.lock().unmap(va, size)
+}
+
+/// Translate a kernel virtual address.
+pub fn translate(va: u64) -> Option<u64> {
+    KERNEL_SPACE.lock().translate(va)
+}
+
+/// Map a device register window into the direct map, widened to whole pages.
+fn map_device(space: &mut AddressSpace, base: u64, size: u64) -> Result<(), &'static str> {
+    let start = base & !(PAGE_SIZE - 1);
+    let end = (base + size).next_multiple_of(PAGE_SIZE);
+    space.map(layout::phys_to_virt(start), start, end - start, PageFlags::DEVICE)
+}
+
+fn map_devices(space: &mut AddressSpace) -> Result<(), &'static str> {
+    let platform = platform();
+    map_device(space, platform.uart_base, PAGE_SIZE)?;
+    map_device(space, platform.fw_cfg_base, PAGE_SIZE)?;
+    map_device(space, platform.rtc_base, PAGE_SIZE)?;
+    map_device(space, platform.gicd_base, platform.gicd_size)?;
+    map_device(space, platform.gicc_base, platform.gicc_size)?;
+    map_device(space, platform.gicr_base, platform.gicr_size)?;
+    map_device(space, platform.pci_ecam_base, platform.pci_ecam_size)
+}
+
+/// Map the device windows in `platform()`. Called again once the device tree
+/// has been probed, in case they moved.
+pub fn map_platform() -> Result<(), &'static str> {
+    map_devices(&mut KERNEL_SPACE.lock())
+}
+
+/// TCR_EL1 for the kernel: 48-bit halves, 4KiB granules, Write-Back walks.
+/// `boot64.s` loads the same layout before the jump to the upper half.
+fn tcr(user: bool) -> u64 {
+    // Output size is whatever the CPU supports, so windows above 4GiB (PCIe ECAM) work
+    let mmfr0: u64;
+    unsafe { asm!("mrs {}, id_aa64mmfr0_el1", out(reg) mmfr0); }
+    (64 - 48)                  // T0SZ: 48-bit TTBR0 region
+        | (0b01 << 8)          // IRGN0: walks through Write-Back inner cache
+        | (0b01 << 10)         // ORGN0: walks through Write-Back outer cache
+        | (0b11 << 12)         // SH0: inner shareable
+        | (TCR_TG0_4K << 14)   // TG0: 4KiB granule
+        | ((!user as u64) << 7) // EPD0: no TTBR0 walks without a user address space
+        | ((64 - 48) << 16)    // T1SZ: 48-bit TTBR1 region
+        | (0b01 << 24)         // IRGN1
+        | (0b01 << 26)         // ORGN1
+        | (0b11 << 28)         // SH1
+        | (TCR_TG1_4K << 30)   // TG1: 4KiB granule
+        | ((mmfr0 & 0b111) << 32) // IPS
+}
+
+/// Build the kernel address space (all of RAM in the direct map, kernel image
+/// included, plus the platform devices), switch TTBR1 over to it and enable
+/// the caches. The identity map the boot code left in TTBR0 is dropped.
+pub fn init() -> Result<(), &'static str> {
+    let mut space = KERNEL_SPACE.lock();
+    *space = AddressSpace::new()?;
+
+    // All of RAM reported by the device tree (kernel image, stack and DTB included).
+    // The heap lives in its own window and is mapped by the allocator.
+    let platform = platform();
+    space.map(layout::phys_to_virt(platform.mem_base), platform.mem_base, platform.mem_size, PageFlags::KERNEL_DATA)?;
+
+    // Only the kernel text is executable (up to `kernel_text_end`, see linker64.ld)
+    let (text_start, text_end) = (&raw const kernel_start as u64, &raw const kernel_text_end as u64);
+    space.map(text_start, layout::virt_to_phys(text_start), text_end - text_start, PageFlags::KERNEL_RWX)?;
+    map_devices(&mut space)?;
+
+    load_kernel_tables(space.root());
+    unsafe { verify_MMU(); }
+    Ok(())
+}
+
+/// Switch a secondary CPU from the boot tables to the kernel address space
+/// built by `init`.
+pub fn init_cpu() {
+    let root = KERNEL_SPACE.lock().root();
+    load_kernel_tables(root);
+}
+
+/// Load the kernel tables at `root` into TTBR1 on the calling CPU, drop the
+/// identity map and turn the caches on.
+fn load_kernel_tables(root: u64) {
+    unsafe {
+        let mair = (MAIR_DEVICE_NGNRNE << (MAIR_IDX_DEVICE * 8)) | (MAIR_NORMAL_WB << (MAIR_IDX_NORMAL * 8));
+        asm!("msr mair_el1, {}", in(reg) mair);
+        asm!("msr tcr_el1, {}", in(reg) tcr(false));
+
+        // The new tables map the kernel exactly where the boot tables did
+        asm!("dsb ish", "isb");
+        asm!("msr ttbr1_el1, {}", in(reg) root);
+        asm!("msr ttbr0_el1, xzr");
+        asm!("tlbi vmalle1", "dsb ish", "isb");
+
+        // Nothing may be cached from before the caches are on
+        invalidate_icache();
+
+        let mut sctlr: u64;
+        asm!("mrs {}, sctlr_el1", out(reg) sctlr);
+        sctlr |= (1 << 0)   // M: MMU
+            | (1 << 2)      // C: data cache
+            | (1 << 12);    // I: instruction cache
+        asm!("msr sctlr_el1, {}", "isb", in(reg) sctlr);
+    }
+}
+
+/// Load `space` into TTBR0 as the current user address space.
+pub fn activate_user_space(space: &AddressSpace) {
+    unsafe {
+        asm!("msr ttbr0_el1, {}", in(reg) space.root());
+        asm!("msr tcr_el1, {}", in(reg) tcr(true));
+        // No ASIDs yet: drop whatever the previous user space left in the TLB
+        asm!("isb", "tlbi vmalle1", "dsb nsh", "isb");
+    }
+}
+
+/// Unload the user address space: every lower half access faults again.
+pub fn deactivate_user_space() {
+    unsafe {
+        asm!("msr tcr_el1, {}", in(reg) tcr(false));
+        asm!("msr ttbr0_el1, xzr");
+        asm!("isb", "tlbi vmalle1", "dsb nsh", "isb");
+    }
+}
+
+/// Map `[va, va + size)` to `pa` in the kernel address space with `flags`
+/// (`PAGE_*`). Returns false on failure.
+#[unsafe(no_mangle)]
+pub extern "C" fn paging_map(va: u64, pa: u64, size: u64, flags: u64) -> bool {
+    map(va, pa, size, PageFlags::from_bits_truncate(flags)).is_ok()
+}
+
+/// Unmap `[va, va + size)` from the kernel address space.
+#[unsafe(no_mangle)]
+pub extern "C" fn paging_unmap(va: u64, size: u64) -> bool {
+    unmap(va, size).is_ok()
+}
+
+/// Physical address `va` maps to, or 0 if it is not mapped.
+#[unsafe(no_mangle)]
+pub extern "C" fn paging_translate(va: u64) -> u64 {
+    translate(va).unwrap_or(0)
+}
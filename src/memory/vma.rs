@@ -0,0 +1,134 @@
+//! Kernel virtual memory areas.
+//!
+//! The page fault handler looks faulting addresses up in this list. `Lazy`
+//! areas are backed one zeroed frame at a time on first touch, `Guard` areas
+//! sit below kernel stacks and are never mapped, so running off the end of a
+//! stack faults instead of silently corrupting whatever lies below it.
+//!
+//! The fault handler must not allocate, so the list is a fixed-size table.
+
+use crate::{memory::{frame_allocator::{PAGE_SIZE, alloc_frame, free_frame}, layout::phys_to_virt, paging::{self, KERNEL_SPACE, PageFlags}}, klog, log::Level};
+
+/// Most areas that can be registered at once
+const MAX_VMAS: usize = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VmaKind {
+    /// Backed by zeroed frames as it is touched
+    Lazy,
+    /// Never mapped: the page below the stack of thread `owner`
+    Guard { owner: &'static str },
+}
+
+/// A registered range `[start, end)` of the kernel address space
+#[derive(Debug, Clone, Copy)]
+pub struct Vma {
+    pub start: u64,
+    pub end: u64,
+    pub kind: VmaKind,
+    /// Attributes of the pages mapped on demand
+    pub flags: PageFlags,
+    pub name: &'static str,
+}
+
+impl Vma {
+    pub fn contains(&self, addr: u64) -> bool {
+        (self.start..self.end).contains(&addr)
+    }
+}
+
+/// What the fault handler made of a fault
+pub enum Fault {
+    /// A page was mapped: retry the access
+    Resolved,
+    /// The access hit the guard page of `owner`'s stack
+    StackOverflow(&'static str),
+    /// Not something the handler can fix, with the area it hit (if any)
+    Invalid(Option<Vma>),
+}
+
+static VMAS: spin::Mutex<[Option<Vma>; MAX_VMAS]> = spin::Mutex::new([None; MAX_VMAS]);
+
+/// Add an area. Both ends must be page aligned and it may not overlap another.
+pub fn register(vma: Vma) -> Result<(), &'static str> {
+    if !(vma.start | vma.end).is_multiple_of(PAGE_SIZE) || vma.end <= vma.start {
+        return Err("[    VMA    ] \x1b[1;31mArea is empty or not page aligned\x1b[0m");
+    }
+
+    let mut vmas = VMAS.lock();
+    if vmas.iter().flatten().any(|v| v.start < vma.end && vma.start < v.end) {
+        return Err("[    VMA    ] \x1b[1;31mArea overlaps an existing one\x1b[0m");
+    }
+    match vmas.iter_mut().find(|v| v.is_none()) {
+        Some(slot) => *slot = Some(vma),
+        None => return Err("[    VMA    ] \x1b[1;31mToo many areas\x1b[0m"),
+    }
+    Ok(())
+}
+
+/// Register a lazily backed area of `size` bytes at `start`.
+pub fn reserve_lazy(start: u64, size: u64, flags: PageFlags, name: &'static str) -> Result<(), &'static str> {
+    register(Vma { start, end: start + size, kind: VmaKind::Lazy, flags, name })
+}
+
+/// Unmap the page at `start` and register it as the stack guard of `owner`.
+pub fn add_guard_page(start: u64, owner: &'static str) -> Result<(), &'static str> {
+    paging::unmap(start, PAGE_SIZE)?;
+    register(Vma { start, end: start + PAGE_SIZE, kind: VmaKind::Guard { owner }, flags: PageFlags::empty(), name: "stack guard" })
+}
+
+/// Remove the area starting at `start`. Pages mapped into a lazy area are
+/// left to the caller.
+pub fn unregister(start: u64) -> Option<Vma> {
+    VMAS.lock().iter_mut().find(|v| v.is_some_and(|v| v.start == start)).and_then(|v| v.take())
+}
+
+/// The area containing `addr`, if any
+pub fn find(addr: u64) -> Option<Vma> {
+    VMAS.lock().iter().flatten().find(|v| v.contains(addr)).copied()
+}
+
+/// Try to resolve a fault on `addr`. `translation` is set when nothing was
+/// mapped there (as opposed to a permission or alignment fault).
+pub fn handle_fault(addr: u64, translation: bool) -> Fault {
+    // A fault inside the list or the page tables must not deadlock on their locks
+    let Some(vma) = VMAS.try_lock().and_then(|vmas| vmas.iter().flatten().find(|v| v.contains(addr)).copied()) else {
+        return Fault::Invalid(None);
+    };
+
+    match vma.kind {
+        VmaKind::Guard { owner } => Fault::StackOverflow(owner),
+        VmaKind::Lazy if translation => match map_zeroed(addr & !(PAGE_SIZE - 1), vma.flags) {
+            Ok(()) => Fault::Resolved,
+            Err(e) => {
+                klog!(Level::Error, "MEMORY", "{}", e);
+                Fault::Invalid(Some(vma))
+            }
+        },
+        VmaKind::Lazy => Fault::Invalid(Some(vma)),
+    }
+}
+
+fn map_zeroed(page: u64, flags: PageFlags) -> Result<(), &'static str> {
+    let frame = alloc_frame();
+    if frame == 0 {
+        return Err("[    VMA    ] \x1b[1;31mOut of physical memory for demand paging\x1b[0m");
+    }
+    unsafe { core::ptr::write_bytes(phys_to_virt(frame) as *mut u8, 0, PAGE_SIZE as usize); }
+
+    let Some(mut space) = KERNEL_SPACE.try_lock() else {
+        free_frame(frame);
+        return Err("[    VMA    ] \x1b[1;31mPage fault with the kernel page tables locked\x1b[0m");
+    };
+    space.map(page, frame, PAGE_SIZE, flags).inspect_err(|_| free_frame(frame))
+}
+
+unsafe extern "C" {
+    static stack_guard: u8;
+}
+
+/// Unmap the guard page the linker script puts under the boot stack. Needs
+/// the kernel page tables.
+pub fn init() -> Result<(), &'static str> {
+    add_guard_page(&raw const stack_guard as u64, "kernel_main")
+}
@@ -1,3 +1,5 @@
+use core::fmt::Write;
+
 use crate::{GPU_DEVICE, SCALE, SCREENHEIGHT, SCREENWIDTH, console_print, trinkets::templeos_color_palette::WHITE};
 
 pub static mut CURSOR: (u32, u32) = (4,4);
@@ -27,6 +29,32 @@ pub fn backspace() {
     }
 }
 
+/// Console writer in one color (0xRRGGBB) that implements the Write trait.
+/// Unlike the macros it does not allocate. Needs a GPU.
+pub struct ConsoleWriter {
+    pub color: u32,
+}
+
+impl Write for ConsoleWriter {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        let (r, g, b) = ((self.color >> 16) as u8, (self.color >> 8) as u8, self.color as u8);
+        for c in s.chars() {
+            if c == '\n' {
+                newline();
+                continue;
+            }
+            unsafe {
+                if CURSOR.1 > SCREENWIDTH - 8*SCALE as u32 {
+                    newline();
+                }
+                (*GPU_DEVICE.unwrap()).draw_char(c as usize, r, g, b, CURSOR.1, CURSOR.0, SCALE);
+                CURSOR.1 += (SCALE*8 + 1) as u32;
+            }
+        }
+        Ok(())
+    }
+}
+
 /// Print to the console with an appended newline.
 /// 
 /// Format string arguments are fully supported. The string 
@@ -0,0 +1,121 @@
+//! Shutdown, reboot and halt.
+//!
+//! Powering off and resetting go through PSCI; without PSCI firmware the CPU
+//! halts instead. QEMU exits with status 0 on a PSCI power off, so with the
+//! `semihosting` feature (and QEMU's `-semihosting`) `power_off` asks QEMU to
+//! exit with the given status instead, which lets test runs report a panic.
+
+use core::{arch::asm, str::FromStr, sync::atomic::{AtomicU8, Ordering}};
+
+use crate::{drivers::psci, klog, log::Level, serial_println};
+
+/// What the panic handler does once the panic is reported
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum PanicAction {
+    /// Stop the CPU, keeping the machine up for a debugger
+    Hang = 0,
+    Reboot = 1,
+    /// Power off with exit status 1
+    PowerOff = 2,
+}
+
+/// Parsed from `mvos.panic=hang|reboot|poweroff` on the command line
+impl FromStr for PanicAction {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, ()> {
+        match s {
+            "hang" => Ok(Self::Hang),
+            "reboot" => Ok(Self::Reboot),
+            "poweroff" => Ok(Self::PowerOff),
+            _ => Err(()),
+        }
+    }
+}
+
+static PANIC_ACTION: AtomicU8 = AtomicU8::new(PanicAction::Hang as u8);
+
+pub fn set_panic_action(action: PanicAction) {
+    PANIC_ACTION.store(action as u8, Ordering::Relaxed);
+}
+
+pub fn panic_action() -> PanicAction {
+    match PANIC_ACTION.load(Ordering::Relaxed) {
+        1 => PanicAction::Reboot,
+        2 => PanicAction::PowerOff,
+        _ => PanicAction::Hang,
+    }
+}
+
+/// Stop the calling CPU for good: IRQs masked, waiting for interrupts
+pub fn halt() -> ! {
+    unsafe { asm!("msr daifset, #2"); }
+    loop {
+        unsafe { asm!("wfi"); }
+    }
+}
+
+/// Power the machine off
+pub fn shutdown() -> ! {
+    power_off(0)
+}
+
+/// Power the machine off, exiting QEMU with `status` where that is possible
+pub fn power_off(status: u32) -> ! {
+    klog!(Level::Info, "POWER", "Powering off (status {})", status);
+    if let Err(e) = system_off(status) {
+        klog!(Level::Error, "POWER", "{}", e);
+    }
+    halt()
+}
+
+/// Reset the machine
+pub fn reboot() -> ! {
+    klog!(Level::Info, "POWER", "Rebooting");
+    if let Err(e) = psci::system_reset() {
+        klog!(Level::Error, "POWER", "{}", e);
+    }
+    halt()
+}
+
+/// Carry out the panic action. Called by the panic handler last.
+///
+/// Writes to the UART directly rather than through the log: the panicking
+/// CPU may hold the log's locks.
+pub fn on_panic() -> ! {
+    let result = match panic_action() {
+        PanicAction::Hang => halt(),
+        PanicAction::Reboot => {
+            serial_println!("[   POWER   ] Rebooting");
+            psci::system_reset()
+        }
+        PanicAction::PowerOff => {
+            serial_println!("[   POWER   ] Powering off (status 1)");
+            system_off(1)
+        }
+    };
+    if let Err(e) = result {
+        serial_println!("{}", e);
+    }
+    halt()
+}
+
+/// Power off through semihosting or PSCI. Only returns if that failed.
+fn system_off(status: u32) -> Result<(), &'static str> {
+    #[cfg(feature = "semihosting")]
+    semihosting_exit(status);
+    #[cfg(not(feature = "semihosting"))]
+    let _ = status;
+    psci::system_off()
+}
+
+/// Semihosting `SYS_EXIT_EXTENDED`: make the emulator exit with `status`
+#[cfg(feature = "semihosting")]
+fn semihosting_exit(status: u32) {
+    const SYS_EXIT_EXTENDED: u64 = 0x20;
+    const ADP_STOPPED_APPLICATION_EXIT: u64 = 0x20026;
+
+    let block = [ADP_STOPPED_APPLICATION_EXIT, status as u64];
+    unsafe { asm!("hlt #0xf000", in("x0") SYS_EXIT_EXTENDED, in("x1") block.as_ptr()); }
+}
@@ -5,7 +5,7 @@
 use alloc::vec::Vec;
 use alloc::string::String;
 
-use crate::{BIBLE, TIMER, console_println, SCALE, SCREENHEIGHT, SCREENWIDTH, GPU_DEVICE};
+use crate::{BIBLE, console_println, time, SCALE, SCREENHEIGHT, SCREENWIDTH, GPU_DEVICE};
 
 pub fn random(seed: usize) -> usize {
     let m = 2_usize.pow(32);
@@ -26,7 +26,8 @@ pub fn random_bible_line(seed: usize) -> Option<&'static str> {
 
 pub fn random_x_lines(x: usize) {
     for i in 0..x {
-        let l = match random_bible_line(46748 * i * unsafe {TIMER + 35} + 482943 * unsafe { TIMER + 3 }) {
+        let now = time::uptime().as_millis() as usize;
+        let l = match random_bible_line(46748 * i * (now + 35) + 482943 * (now + 3)) {
             Some(l) => l, 
             None => "--- !!! YOU HAVE REACHED HELL !!! ---",
         };
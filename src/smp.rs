@@ -0,0 +1,181 @@
+//! Secondary CPU bring-up and per-CPU data.
+//!
+//! Every CPU finds its [`PerCpu`] through TPIDR_EL1. The boot CPU is CPU 0,
+//! the others are numbered in device tree order and started one at a time
+//! with PSCI `CPU_ON` at `_secondary_start` (boot64.s). That turns the MMU on
+//! with the kernel tables from [`SECONDARY_BOOT`] and calls [`secondary_main`]
+//! on a stack of its own.
+//!
+//! Once up, a secondary CPU turns into its own idle thread and schedules the
+//! threads placed on it (see `thread::scheduler`).
+
+use core::{arch::asm, sync::atomic::{AtomicU64, Ordering}, time::Duration};
+
+use crate::{drivers::{dtb_parser::DeviceTree, psci}, exceptions::{irq, set_exception_vectors}, ipi, memory::{cache::clean_dcache_range, layout::virt_to_phys, paging::{self, KERNEL_SPACE}}, klog, log::Level, thread::{self, scheduler::MAX_THREADS, stack::KernelStack}, time::Instant};
+
+/// Most CPUs brought up, the boot CPU included
+pub const MAX_CPUS: usize = 16;
+/// Emergency stack for reporting kernel stack overflows, one per CPU
+const EXCEPTION_STACK_SIZE: usize = 0x4000;
+/// Time a secondary CPU gets to report itself online
+const ONLINE_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// Data of one CPU. The exception vectors load `exception_stack_top` at
+/// offset 8, keep it there.
+#[repr(C)]
+pub struct PerCpu {
+    pub id: usize,
+    exception_stack_top: u64,
+    /// MPIDR_EL1 affinity fields
+    pub mpidr: u64,
+    /// Stack `secondary_main` runs on (none for the boot CPU)
+    stack: Option<KernelStack>,
+}
+
+#[repr(C, align(16))]
+struct ExceptionStack([u8; EXCEPTION_STACK_SIZE]);
+
+/// What `_secondary_start` needs before it can run Rust code. Read with the
+/// MMU off, so it is cleaned to memory before each `CPU_ON`.
+#[repr(C)]
+pub struct SecondaryBoot {
+    /// Kernel page tables for TTBR1
+    ttbr1: u64,
+    stack_top: u64,
+}
+
+#[unsafe(no_mangle)]
+pub static mut SECONDARY_BOOT: SecondaryBoot = SecondaryBoot { ttbr1: 0, stack_top: 0 };
+
+static mut CPUS: [PerCpu; MAX_CPUS] = [const { PerCpu { id: 0, exception_stack_top: 0, mpidr: 0, stack: None } }; MAX_CPUS];
+static mut EXCEPTION_STACKS: [ExceptionStack; MAX_CPUS] = [const { ExceptionStack([0; EXCEPTION_STACK_SIZE]) }; MAX_CPUS];
+/// Bit `n` is set once CPU `n` is up
+static ONLINE: AtomicU64 = AtomicU64::new(0);
+
+/// Affinity fields of the calling CPU's MPIDR_EL1
+pub fn mpidr() -> u64 {
+    let mpidr: u64;
+    unsafe { asm!("mrs {}, mpidr_el1", out(reg) mpidr); }
+    mpidr & 0xff_00ff_ffff
+}
+
+/// Per-CPU data of the calling CPU
+pub fn this_cpu() -> &'static PerCpu {
+    let percpu: u64;
+    unsafe {
+        asm!("mrs {}, tpidr_el1", out(reg) percpu);
+        &*(percpu as *const PerCpu)
+    }
+}
+
+/// Per-CPU data of CPU `id`
+pub fn percpu(id: usize) -> &'static PerCpu {
+    unsafe { &*(&raw const CPUS).cast::<PerCpu>().add(id) }
+}
+
+/// Number of the calling CPU, 0 for the boot CPU
+pub fn cpu_id() -> usize {
+    this_cpu().id
+}
+
+/// CPUs up and running
+pub fn num_cpus() -> usize {
+    ONLINE.load(Ordering::Acquire).count_ones() as usize
+}
+
+pub fn is_online(id: usize) -> bool {
+    ONLINE.load(Ordering::Acquire) & (1 << id) != 0
+}
+
+/// Fill in the per-CPU data of CPU `id` and point TPIDR_EL1 at it
+fn init_percpu(id: usize) {
+    unsafe {
+        let cpu = &mut *(&raw mut CPUS).cast::<PerCpu>().add(id);
+        cpu.id = id;
+        cpu.mpidr = mpidr();
+        cpu.exception_stack_top = (&raw const EXCEPTION_STACKS[id]) as u64 + EXCEPTION_STACK_SIZE as u64;
+        asm!("msr tpidr_el1, {}", in(reg) cpu as *const PerCpu);
+    }
+}
+
+/// Set up the boot CPU's per-CPU data. Runs before the exception vectors are
+/// installed, since they use it.
+pub fn init() {
+    init_percpu(0);
+    ONLINE.fetch_or(1, Ordering::Release);
+}
+
+/// Start every other CPU listed under `/cpus`
+pub fn start_secondaries(tree: &DeviceTree) {
+    let Some(cpus) = tree.find_node("/cpus") else { return; };
+    let cells = cpus.prop_u32("#address-cells").unwrap_or(1) as usize;
+    let boot = mpidr();
+
+    let mut id = 1;
+    for node in cpus.children.iter().filter(|n| n.prop_str("device_type") == Some("cpu") && n.is_enabled()) {
+        let Some(reg) = node.property("reg") else { continue; };
+        let mpidr = reg.chunks_exact(4).take(cells).fold(0, |acc, c| acc << 32 | u32::from_be_bytes([c[0], c[1], c[2], c[3]]) as u64);
+        if mpidr == boot {
+            continue;
+        }
+        if id == MAX_CPUS {
+            klog!(Level::Warn, "SMP", "Only {} CPUs supported", MAX_CPUS);
+            break;
+        }
+        // A CPU that failed to come up keeps its number, it might still show up
+        if let Err(e) = start_cpu(id, mpidr) {
+            klog!(Level::Error, "SMP", "{}", e);
+        }
+        id += 1;
+    }
+    klog!(Level::Info, "SMP", "{} CPU(s) online", num_cpus());
+}
+
+/// Start CPU `mpidr` as CPU `id` and wait for it to come online
+fn start_cpu(id: usize, mpidr: u64) -> Result<(), &'static str> {
+    unsafe extern "C" { fn _secondary_start(); }
+
+    // Stack slots above the thread table's are free for the CPU stacks
+    let stack = KernelStack::new(MAX_THREADS + id, "CPU boot stack")?;
+    unsafe {
+        let boot = &raw mut SECONDARY_BOOT;
+        (*boot).ttbr1 = KERNEL_SPACE.lock().root();
+        (*boot).stack_top = stack.top();
+        clean_dcache_range(boot as usize, size_of::<SecondaryBoot>());
+    }
+    let cpu = unsafe { (&raw mut CPUS).cast::<PerCpu>().add(id) };
+    unsafe { (*cpu).stack = Some(stack); }
+    psci::cpu_on(mpidr, virt_to_phys(_secondary_start as *const () as u64), id as u64)
+        .inspect_err(|_| unsafe { (*cpu).stack = None })?;
+
+    let start = Instant::now();
+    while !is_online(id) {
+        if start.elapsed() > ONLINE_TIMEOUT {
+            return Err("[    SMP    ] \x1b[1;31mCPU did not come online\x1b[0m");
+        }
+        core::hint::spin_loop();
+    }
+    Ok(())
+}
+
+/// Rust entry point of secondary CPU `id`, called by `_secondary_start`
+#[unsafe(no_mangle)]
+pub extern "C" fn secondary_main(id: usize) -> ! {
+    paging::init_cpu();
+    init_percpu(id);
+    unsafe { set_exception_vectors(); }
+    irq::init_cpu();
+    if let Err(e) = thread::init_cpu() {
+        panic!("{}", e);
+    }
+    ipi::init_cpu();
+    irq::enable_timer();
+    ONLINE.fetch_or(1 << id, Ordering::Release);
+    klog!(Level::Info, "SMP", "CPU {} online (MPIDR {:#x})", id, this_cpu().mpidr);
+
+    // Idle thread of this CPU from here on
+    unsafe { asm!("msr daifclr, #2"); }
+    loop {
+        unsafe { asm!("wfi"); }
+    }
+}
@@ -1,17 +1,270 @@
-use core::arch::asm;
+//! Kernel threads.
+//!
+//! Every thread runs at EL1 on its own guarded stack and is preempted by the
+//! 1ms timer tick (see `scheduler`). `kernel_main` becomes the first thread
+//! once [`init`] has run, each secondary CPU's boot code its idle thread
+//! ([`init_cpu`]).
 
-use crate::TIMER;
+use core::{arch::asm, fmt};
 
-pub fn sleep(ms: usize) {
+use crate::{exceptions::TrapFrame, klog, log::Level, smp, thread::{scheduler::with_scheduler, stack::{BootStack, KernelStack, ThreadStack}}, timer};
+
+/// `svc` immediate used by [`yield_now`]
+pub const SVC_YIELD: u16 = 0;
+
+/// EL1 using SP_EL1 with all exceptions unmasked
+const SPSR_EL1H: u64 = 0b0101;
+
+/// Thread body, called with the argument given to [`spawn`]
+pub type Entry = fn(usize);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ThreadId(usize);
+
+impl fmt::Display for ThreadId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum State {
+    /// In the run queue
+    Ready,
+    Running,
+    /// Waiting to be woken by another thread
+    Blocked,
+    /// Returned or called `exit`, waiting to be joined
+    Finished,
+}
+
+/// Thread control block
+pub struct Thread {
+    pub name: &'static str,
+    pub state: State,
+    /// Saved `TrapFrame` while the thread is not running
+    frame: u64,
+    /// `None` for the idle threads of secondary CPUs, which keep running on
+    /// their CPU's boot stack
+    stack: Option<ThreadStack>,
+    /// CPU the thread runs on, for its whole life
+    cpu: usize,
+    /// A CPU still runs on the thread's stack (see `scheduler::finish_switch`)
+    on_cpu: bool,
+    /// Thread blocked in `join` on this one
+    joiner: Option<ThreadId>,
+    /// The `JoinHandle` was dropped: nobody will join
+    detached: bool,
+}
+
+impl Thread {
+    fn new(name: &'static str, state: State) -> Self {
+        Self { name, state, frame: 0, stack: None, cpu: smp::cpu_id(), on_cpu: false, joiner: None, detached: false }
+    }
+}
+
+/// Owned permission to join a thread. Dropping it detaches the thread.
+pub struct JoinHandle {
+    id: ThreadId,
+}
+
+impl JoinHandle {
+    pub fn id(&self) -> ThreadId {
+        self.id
+    }
+
+    /// Block until the thread has finished, then free it.
+    pub fn join(self) {
+        let id = self.id;
+        core::mem::forget(self);
+
+        loop {
+            // None: no such thread, Some(None): still running
+            let finished = with_scheduler(|s| {
+                let current = s.current();
+                let thread = s.thread(id.0)?;
+                if thread.state == State::Finished {
+                    // Its CPU may not have left its stack yet: try again
+                    return Some((!thread.on_cpu).then(|| s.remove(id.0)).flatten());
+                }
+                thread.joiner = Some(current);
+                if let Some(current) = s.thread(current.0) {
+                    current.state = State::Blocked;
+                }
+                Some(None)
+            });
+            match finished {
+                // Dropped with interrupts on: unmapping the stack takes locks
+                Some(Some(thread)) => {
+                    drop(thread);
+                    return;
+                }
+                Some(None) => yield_now(),
+                None => return,
+            }
+        }
+    }
+}
+
+impl Drop for JoinHandle {
+    fn drop(&mut self) {
+        with_scheduler(|s| {
+            if let Some(thread) = s.thread(self.id.0) {
+                thread.detached = true;
+            }
+        });
+    }
+}
+
+/// Turn the running code into the boot thread and start the idle thread.
+/// Needs the page tables and the frame allocator for the idle thread's stack.
+pub fn init() -> Result<(), &'static str> {
+    let boot = with_scheduler(|s| {
+        // The boot stack only once in the table, where nothing drops it
+        // before the thread exits. Nobody holds a `JoinHandle` for it, so it
+        // is reaped like a detached thread.
+        s.insert(Thread::new("kernel_main", State::Running)).inspect(|id| {
+            if let Some(thread) = s.thread(id.0) {
+                thread.stack = Some(ThreadStack::Boot(unsafe { BootStack::new() }));
+                thread.detached = true;
+            }
+        })
+    })?;
+    let idle = spawn_thread("idle", idle, 0, State::Blocked)?;
+    with_scheduler(|s| s.start(boot, idle));
+    klog!(Level::Info, "THREAD", "Scheduler started, boot thread {}, idle thread {}.", boot, idle);
+    Ok(())
+}
+
+/// Turn the running code of a secondary CPU into its idle thread and start
+/// scheduling on it. The CPU idles (`wfi` with IRQs on) afterwards.
+pub fn init_cpu() -> Result<(), &'static str> {
+    let idle = with_scheduler(|s| s.insert(Thread::new("idle", State::Running)))?;
+    with_scheduler(|s| s.start(idle, idle));
+    Ok(())
+}
+
+/// Start a thread running `entry(arg)` on the least busy CPU. `name` shows up
+/// in stack overflow reports.
+pub fn spawn(name: &'static str, entry: Entry, arg: usize) -> Result<JoinHandle, &'static str> {
+    reap();
+    let id = spawn_thread(name, entry, arg, State::Ready)?;
+    klog!(Level::Info, "THREAD", "Spawned thread {} ({}).", id, name);
+    Ok(JoinHandle { id })
+}
+
+/// Create a thread whose first `eret` enters `thread_start(arg, entry)`.
+/// It is queued if `state` is `Ready`.
+fn spawn_thread(name: &'static str, entry: Entry, arg: usize, state: State) -> Result<ThreadId, &'static str> {
+    // Hold the slot while the stack is mapped outside the lock
+    let id = with_scheduler(|s| s.insert(Thread::new(name, State::Blocked)))?;
+    let stack = match KernelStack::new(id.0, name) {
+        Ok(stack) => stack,
+        Err(e) => {
+            with_scheduler(|s| s.remove(id.0));
+            return Err(e);
+        }
+    };
+
+    let frame = (stack.top() - size_of::<TrapFrame>() as u64) as *mut TrapFrame;
+    let mut x = [0; 31];
+    x[0] = arg as u64;
+    x[1] = entry as usize as u64;
+    // x29 and x30 stay 0 to end backtraces
     unsafe {
-        let start_timer = TIMER;
-        while TIMER < start_timer + ms {
-            asm!("nop");
+        frame.write(TrapFrame { x, sp_el0: 0, elr: thread_start as *const () as u64, spsr: SPSR_EL1H, esr: 0, far: 0 });
+    }
+
+    with_scheduler(|s| {
+        let cpu = if state == State::Ready { s.pick_cpu() } else { smp::cpu_id() };
+        if let Some(thread) = s.thread(id.0) {
+            thread.frame = frame as u64;
+            thread.stack = Some(ThreadStack::Kernel(stack));
+            thread.cpu = cpu;
+        }
+        if state == State::Ready {
+            s.wake(id.0);
         }
+    });
+    Ok(id)
+}
+
+extern "C" fn thread_start(arg: usize, entry: usize) -> ! {
+    let entry: Entry = unsafe { core::mem::transmute(entry) };
+    entry(arg);
+    exit();
+}
+
+fn idle(_: usize) {
+    loop {
+        reap();
+        unsafe { asm!("wfi"); }
     }
 }
 
+/// Free detached threads that have finished. Their stacks cannot be unmapped
+/// while they still run on them, so this is left to other threads: `spawn`
+/// and the idle thread.
+fn reap() {
+    while let Some(thread) = with_scheduler(|s| {
+        let current = s.current();
+        let id = (0..scheduler::MAX_THREADS).find(|&id| {
+            id != current.0 && s.thread(id).is_some_and(|t| t.detached && t.state == State::Finished && !t.on_cpu)
+        })?;
+        s.remove(id)
+    }) {
+        drop(thread);
+    }
+}
+
+/// Id of the running thread
+pub fn current() -> ThreadId {
+    with_scheduler(|s| s.current())
+}
+
+/// Give the rest of the time slice to the next ready thread.
+pub fn yield_now() {
+    unsafe { asm!("svc #{}", const SVC_YIELD); }
+}
+
+/// Mark the running thread blocked. It keeps running until it yields, then
+/// waits for [`wake`]; a `wake` in between just leaves it queued. Pair it
+/// with whatever will wake the thread with interrupts masked, or a tick may
+/// switch away first. `None` before the scheduler runs.
+pub fn block_current() -> Option<ThreadId> {
+    with_scheduler(|s| s.block_current())
+}
+
+/// Make a blocked thread runnable again
+pub fn wake(id: ThreadId) {
+    with_scheduler(|s| s.wake(id.0));
+}
+
+/// Finish the running thread and wake its joiner.
+pub fn exit() -> ! {
+    with_scheduler(|s| {
+        let current = s.current();
+        let joiner = s.thread(current.0).and_then(|thread| {
+            thread.state = State::Finished;
+            thread.joiner.take()
+        });
+        if let Some(joiner) = joiner {
+            s.wake(joiner.0);
+        }
+    });
+    yield_now();
+    unreachable!("finished thread was scheduled again");
+}
+
+/// Block the running thread for `ms` milliseconds (see `timer::sleep_ms`)
+pub fn sleep(ms: usize) {
+    timer::sleep_ms(ms as u64);
+}
+
 #[unsafe(no_mangle)]
 pub extern "C" fn c_sleep(ms: usize) {
     sleep(ms);
-}
\ No newline at end of file
+}
+
+pub mod scheduler;
+pub mod stack;
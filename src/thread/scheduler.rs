@@ -0,0 +1,278 @@
+//! Round-robin scheduler.
+//!
+//! A thread that is not running is fully described by the `TrapFrame` it was
+//! interrupted with, saved on its own stack by the exception vectors. The end
+//! of every interrupt ([`preempt`]) and `yield_now` (an `svc`) both end up in
+//! [`Scheduler::switch`], which returns the frame of the thread to resume; the
+//! vectors load it and `eret` into that thread.
+//!
+//! Every CPU has its own run queue, current and idle thread. A thread stays on
+//! the CPU it was placed on at spawn (the least busy one), so its stack is
+//! never resumed on one CPU while another is still leaving it. Waking a
+//! thread of an idle CPU sends that CPU a reschedule IPI.
+//!
+//! Interrupt handlers take the scheduler lock, so everything else takes it
+//! with IRQs masked and must not allocate while holding it (the heap lock
+//! may belong to a preempted thread). Hence the fixed-size tables.
+
+use crate::{exceptions::{TrapFrame, irq::without_interrupts}, ipi::{self, IpiKind}, smp::{self, MAX_CPUS}, thread::{State, Thread, ThreadId}};
+
+/// Most threads alive at once, the boot and idle threads included
+pub const MAX_THREADS: usize = 64;
+/// Timer ticks (ms) a thread runs before it is preempted
+const TIME_SLICE: u32 = 10;
+
+/// FIFO of ready threads. Each thread is queued at most once, so it cannot
+/// overflow.
+struct RunQueue {
+    ids: [usize; MAX_THREADS],
+    head: usize,
+    len: usize,
+}
+
+impl RunQueue {
+    const fn new() -> Self {
+        Self { ids: [0; MAX_THREADS], head: 0, len: 0 }
+    }
+
+    fn push(&mut self, id: usize) {
+        self.ids[(self.head + self.len) % MAX_THREADS] = id;
+        self.len += 1;
+    }
+
+    fn pop(&mut self) -> Option<usize> {
+        if self.len == 0 {
+            return None;
+        }
+        let id = self.ids[self.head];
+        self.head = (self.head + 1) % MAX_THREADS;
+        self.len -= 1;
+        Some(id)
+    }
+}
+
+/// Scheduling state of one CPU
+struct CpuState {
+    run_queue: RunQueue,
+    current: usize,
+    /// Runs when nothing else is ready, never queued
+    idle: usize,
+    slice_left: u32,
+    /// The time slice ran out: switch at the end of the interrupt
+    need_resched: bool,
+    /// Set once the CPU's first thread is in the table
+    started: bool,
+    /// Thread switched away from whose stack is still in use until
+    /// `finish_switch`
+    prev: Option<usize>,
+}
+
+impl CpuState {
+    const fn new() -> Self {
+        Self { run_queue: RunQueue::new(), current: 0, idle: 0, slice_left: TIME_SLICE, need_resched: false, started: false, prev: None }
+    }
+
+    /// Running the idle thread with nothing else to do
+    fn is_idle(&self) -> bool {
+        self.started && self.current == self.idle && self.run_queue.len == 0
+    }
+}
+
+pub(super) struct Scheduler {
+    threads: [Option<Thread>; MAX_THREADS],
+    cpus: [CpuState; MAX_CPUS],
+    /// CPUs to send a reschedule IPI once the lock is dropped
+    kick: u64,
+}
+
+static SCHEDULER: spin::Mutex<Scheduler> = spin::Mutex::new(Scheduler {
+    threads: [const { None }; MAX_THREADS],
+    cpus: [const { CpuState::new() }; MAX_CPUS],
+    kick: 0,
+});
+
+impl Scheduler {
+    pub(super) fn thread(&mut self, id: usize) -> Option<&mut Thread> {
+        self.threads.get_mut(id)?.as_mut()
+    }
+
+    pub(super) fn current(&self) -> ThreadId {
+        ThreadId(self.cpus[smp::cpu_id()].current)
+    }
+
+    /// Put `thread` in a free slot, returning its id
+    pub(super) fn insert(&mut self, thread: Thread) -> Result<ThreadId, &'static str> {
+        let Some(id) = self.threads.iter().position(|t| t.is_none()) else {
+            return Err("[  THREAD   ] \x1b[1;31mToo many threads\x1b[0m");
+        };
+        self.threads[id] = Some(thread);
+        Ok(ThreadId(id))
+    }
+
+    pub(super) fn remove(&mut self, id: usize) -> Option<Thread> {
+        self.threads.get_mut(id)?.take()
+    }
+
+    /// Make `current` (already running) the current thread of the calling
+    /// CPU and `idle` its idle thread
+    pub(super) fn start(&mut self, current: ThreadId, idle: ThreadId) {
+        if let Some(thread) = self.thread(current.0) {
+            thread.on_cpu = true;
+        }
+        let cpu = &mut self.cpus[smp::cpu_id()];
+        cpu.current = current.0;
+        cpu.idle = idle.0;
+        cpu.started = true;
+    }
+
+    /// The started CPU with the least work, for a new thread
+    pub(super) fn pick_cpu(&self) -> usize {
+        (0..MAX_CPUS)
+            .filter(|&c| self.cpus[c].started)
+            .min_by_key(|&c| self.cpus[c].run_queue.len + (self.cpus[c].current != self.cpus[c].idle) as usize)
+            .unwrap_or(0)
+    }
+
+    /// Mark the current thread blocked, if the scheduler runs at all
+    pub(super) fn block_current(&mut self) -> Option<ThreadId> {
+        let cpu = &self.cpus[smp::cpu_id()];
+        if !cpu.started {
+            return None;
+        }
+        let id = cpu.current;
+        self.thread(id)?.state = State::Blocked;
+        Some(ThreadId(id))
+    }
+
+    /// Queue a new or blocked thread on its CPU, waking that CPU if it idles
+    pub(super) fn wake(&mut self, id: usize) {
+        let Some(thread) = self.threads.get_mut(id).and_then(|t| t.as_mut()).filter(|t| t.state == State::Blocked) else {
+            return;
+        };
+        thread.state = State::Ready;
+        let target = thread.cpu;
+        let cpu = &mut self.cpus[target];
+        let was_idle = cpu.current == cpu.idle;
+        cpu.run_queue.push(id);
+        if was_idle && target != smp::cpu_id() {
+            self.kick |= 1 << target;
+        }
+    }
+
+    /// Save `frame` as the current thread's state and pick the next thread.
+    /// A current thread that is no longer `Running` (blocked or finished) is
+    /// not queued again.
+    fn switch(&mut self, frame: &mut TrapFrame) -> *mut TrapFrame {
+        let frame = frame as *mut TrapFrame;
+        let cpu = &mut self.cpus[smp::cpu_id()];
+        if !cpu.started {
+            return frame;
+        }
+
+        let current = cpu.current;
+        let still_running = self.threads[current].as_ref().is_some_and(|t| t.state == State::Running);
+        let next = match cpu.run_queue.pop() {
+            Some(next) => next,
+            None if still_running => {
+                cpu.slice_left = TIME_SLICE;
+                cpu.need_resched = false;
+                return frame;
+            }
+            None => cpu.idle,
+        };
+
+        if let Some(thread) = self.threads[current].as_mut() {
+            thread.frame = frame as u64;
+            if still_running {
+                thread.state = State::Ready;
+                if current != cpu.idle {
+                    cpu.run_queue.push(current);
+                }
+            }
+        }
+        if next != current {
+            cpu.prev = Some(current);
+        }
+
+        let thread = self.threads[next].as_mut().expect("scheduled a thread that does not exist");
+        thread.state = State::Running;
+        thread.on_cpu = true;
+        cpu.current = next;
+        cpu.slice_left = TIME_SLICE;
+        cpu.need_resched = false;
+        thread.frame as *mut TrapFrame
+    }
+}
+
+/// Timer tick: charge the current thread for it. Once its time slice is
+/// used up it is preempted at the end of the interrupt.
+pub fn tick() {
+    let mut scheduler = SCHEDULER.lock();
+    let cpu = &mut scheduler.cpus[smp::cpu_id()];
+    cpu.slice_left = cpu.slice_left.saturating_sub(1);
+    if cpu.slice_left == 0 {
+        cpu.need_resched = true;
+    }
+}
+
+/// Reschedule IPI: switch threads at the end of the interrupt
+pub fn resched() {
+    SCHEDULER.lock().cpus[smp::cpu_id()].need_resched = true;
+}
+
+/// End of an interrupt: switch threads if the time slice ran out, or if the
+/// CPU idles and the interrupt woke a thread.
+pub fn preempt(frame: &mut TrapFrame) -> *mut TrapFrame {
+    let mut scheduler = SCHEDULER.lock();
+    let cpu = &scheduler.cpus[smp::cpu_id()];
+    let woken = cpu.current == cpu.idle && cpu.run_queue.len != 0;
+    if !cpu.need_resched && !woken {
+        return frame;
+    }
+    let frame = scheduler.switch(frame);
+    drop(scheduler);
+
+    // A tickless idle CPU may have stopped its tick
+    #[cfg(feature = "tickless")]
+    if woken {
+        crate::time::resume_tick();
+    }
+    frame
+}
+
+/// Whether the calling CPU will idle after this interrupt: the idle thread
+/// runs and nothing else is ready
+pub fn is_idle() -> bool {
+    SCHEDULER.lock().cpus[smp::cpu_id()].is_idle()
+}
+
+/// `yield_now`: switch to the next ready thread, if any
+pub fn schedule(frame: &mut TrapFrame) -> *mut TrapFrame {
+    SCHEDULER.lock().switch(frame)
+}
+
+/// Called by the exception vectors after a switch, once the CPU runs on the
+/// new thread's stack: the previous thread may now be freed or resumed.
+#[unsafe(no_mangle)]
+pub extern "C" fn finish_switch() {
+    let mut scheduler = SCHEDULER.lock();
+    if let Some(prev) = scheduler.cpus[smp::cpu_id()].prev.take()
+        && let Some(thread) = scheduler.thread(prev)
+    {
+        thread.on_cpu = false;
+    }
+}
+
+/// Run `f` on the scheduler with IRQs masked, then kick the CPUs it woke
+/// threads for
+pub(super) fn with_scheduler<R>(f: impl FnOnce(&mut Scheduler) -> R) -> R {
+    let (result, kick) = without_interrupts(|| {
+        let mut scheduler = SCHEDULER.lock();
+        let result = f(&mut scheduler);
+        (result, core::mem::take(&mut scheduler.kick))
+    });
+    for cpu in (0..MAX_CPUS).filter(|&cpu| kick & (1 << cpu) != 0) {
+        ipi::send_ipi(cpu, IpiKind::Reschedule);
+    }
+    result
+}
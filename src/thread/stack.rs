@@ -0,0 +1,100 @@
+//! Kernel thread stacks.
+//!
+//! Each thread table slot owns a fixed place in the stack window (see
+//! `memory::layout`): an unmapped guard page followed by `STACK_SIZE` bytes
+//! of mapped frames. Running off the bottom of a stack hits the guard page,
+//! which the page fault handler reports as an overflow of its owner.
+//!
+//! `kernel_main` runs on the boot stack from the linker script instead
+//! ([`BootStack`]), which goes back to the frame allocator once it exits.
+
+use crate::{memory::{frame_allocator::{PAGE_SIZE, alloc_frame, free_contiguous, free_frame}, layout::{THREAD_STACKS_START, virt_to_phys}, paging::{self, PageFlags}, vma}, klog, log::Level};
+
+unsafe extern "C" {
+    static stack_guard: u8;
+    static stack_top: u8;
+}
+
+/// Usable size of a kernel thread stack (64KiB)
+pub const STACK_SIZE: u64 = 0x10000;
+/// Guard page plus stack
+const SLOT_SIZE: u64 = STACK_SIZE + PAGE_SIZE;
+
+pub struct KernelStack {
+    slot: usize,
+}
+
+impl KernelStack {
+    /// Map the stack of thread table slot `slot`, guarded for thread `owner`.
+    pub fn new(slot: usize, owner: &'static str) -> Result<Self, &'static str> {
+        let guard = THREAD_STACKS_START + slot as u64 * SLOT_SIZE;
+        vma::add_guard_page(guard, owner)?;
+
+        // From here on dropping the stack undoes whatever was mapped
+        let stack = Self { slot };
+        for page in (stack.bottom()..stack.top()).step_by(PAGE_SIZE as usize) {
+            let frame = alloc_frame();
+            if frame == 0 {
+                return Err("[  THREAD   ] \x1b[1;31mOut of physical memory for a thread stack\x1b[0m");
+            }
+            paging::map(page, frame, PAGE_SIZE, PageFlags::KERNEL_DATA).inspect_err(|_| free_frame(frame))?;
+        }
+        Ok(stack)
+    }
+
+    fn guard(&self) -> u64 {
+        THREAD_STACKS_START + self.slot as u64 * SLOT_SIZE
+    }
+
+    pub fn bottom(&self) -> u64 {
+        self.guard() + PAGE_SIZE
+    }
+
+    /// Initial stack pointer (16 byte aligned)
+    pub fn top(&self) -> u64 {
+        self.bottom() + STACK_SIZE
+    }
+}
+
+impl Drop for KernelStack {
+    fn drop(&mut self) {
+        for page in (self.bottom()..self.top()).step_by(PAGE_SIZE as usize) {
+            if let Some(frame) = paging::translate(page) && paging::unmap(page, PAGE_SIZE).is_ok() {
+                free_frame(frame);
+            }
+        }
+        vma::unregister(self.guard());
+    }
+}
+
+/// Memory a thread runs on
+pub enum ThreadStack {
+    Kernel(KernelStack),
+    Boot(BootStack),
+}
+
+/// The boot stack `kernel_main` runs on: a guard page (see `vma::init`) and
+/// the stack above it, in the direct map.
+pub struct BootStack(());
+
+impl BootStack {
+    /// # Safety
+    /// Only for the boot thread, once: dropping the value frees the stack.
+    pub unsafe fn new() -> Self {
+        Self(())
+    }
+}
+
+impl Drop for BootStack {
+    fn drop(&mut self) {
+        let (guard, top) = (&raw const stack_guard as u64, &raw const stack_top as u64);
+        // Freed frames are reached through the direct map, so the guard page
+        // goes back in first
+        vma::unregister(guard);
+        if let Err(e) = paging::map(guard, virt_to_phys(guard), PAGE_SIZE, PageFlags::KERNEL_DATA) {
+            klog!(Level::Error, "THREAD", "{}", e);
+            return;
+        }
+        free_contiguous(virt_to_phys(guard), ((top - guard) / PAGE_SIZE) as usize);
+    }
+}
@@ -0,0 +1,133 @@
+//! Monotonic time from the ARM generic timer.
+//!
+//! Everything is derived from the physical counter (`CNTPCT_EL0`) and its
+//! frequency (`CNTFRQ_EL0`), so timestamps stay correct however many timer
+//! interrupts are missed. The interrupt itself only drives the scheduler and
+//! the `timer` list: it fires every millisecond, or, with the `tickless`
+//! feature, only at the next timer deadline while the CPU idles.
+
+use core::{arch::asm, ops::{Add, Sub}, sync::atomic::{AtomicU64, Ordering}, time::Duration};
+
+#[cfg(feature = "tickless")]
+use crate::{thread::scheduler, timer};
+
+const NANOS_PER_SEC: u128 = 1_000_000_000;
+/// Scheduler tick (1ms)
+const TICK: Duration = Duration::from_millis(1);
+
+/// Counter value `uptime` counts from
+static BOOT_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+/// Current value of the physical counter
+pub fn counter() -> u64 {
+    let count: u64;
+    // Keep the read from being hoisted above earlier instructions
+    unsafe { asm!("isb", "mrs {}, cntpct_el0", out(reg) count); }
+    count
+}
+
+/// Counter ticks per second
+pub fn frequency() -> u64 {
+    let freq: u64;
+    unsafe { asm!("mrs {}, cntfrq_el0", out(reg) freq); }
+    freq
+}
+
+/// Length of `ticks` counter ticks
+pub fn ticks_to_duration(ticks: u64) -> Duration {
+    let nanos = ticks as u128 * NANOS_PER_SEC / frequency() as u128;
+    Duration::new((nanos / NANOS_PER_SEC) as u64, (nanos % NANOS_PER_SEC) as u32)
+}
+
+fn duration_to_ticks(duration: Duration) -> u64 {
+    (duration.as_nanos() * frequency() as u128).div_ceil(NANOS_PER_SEC) as u64
+}
+
+/// A point in time, as a physical counter value
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Instant(u64);
+
+impl Instant {
+    pub fn now() -> Self {
+        Self(counter())
+    }
+
+    /// Raw counter value
+    pub fn ticks(&self) -> u64 {
+        self.0
+    }
+
+    /// Time from `earlier` to `self`, zero if `earlier` is later
+    pub fn duration_since(&self, earlier: Instant) -> Duration {
+        ticks_to_duration(self.0.saturating_sub(earlier.0))
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        Instant::now().duration_since(*self)
+    }
+}
+
+impl Add<Duration> for Instant {
+    type Output = Instant;
+
+    fn add(self, rhs: Duration) -> Instant {
+        Instant(self.0.saturating_add(duration_to_ticks(rhs)))
+    }
+}
+
+impl Sub<Instant> for Instant {
+    type Output = Duration;
+
+    fn sub(self, rhs: Instant) -> Duration {
+        self.duration_since(rhs)
+    }
+}
+
+/// Start counting `uptime` from now. Called first thing in `kernel_main`.
+pub fn init() {
+    BOOT_COUNTER.store(counter(), Ordering::Relaxed);
+}
+
+/// Time since `init`
+pub fn uptime() -> Duration {
+    Instant::now().duration_since(Instant(BOOT_COUNTER.load(Ordering::Relaxed)))
+}
+
+/// Program the timer interrupt for `at`
+fn set_comparator(at: Instant) {
+    unsafe { asm!("msr cntp_cval_el0, {}", in(reg) at.0); }
+}
+
+/// Bring the periodic tick back on a CPU that leaves tickless idle, whose
+/// comparator may be far out
+#[cfg(feature = "tickless")]
+pub fn resume_tick() {
+    let next = Instant::now().0 + duration_to_ticks(TICK);
+    let armed: u64;
+    unsafe { asm!("mrs {}, cntp_cval_el0", out(reg) armed); }
+    if armed > next {
+        set_comparator(Instant(next));
+    }
+}
+
+/// Program the next timer interrupt, after the current one has been handled.
+/// The periodic tick stays on the 1ms grid of the previous deadline, so late
+/// interrupts do not shift it. With `tickless`, an idle CPU is only woken for
+/// the next pending timer.
+pub fn arm_next_tick() {
+    let now = Instant::now();
+
+    #[cfg(feature = "tickless")]
+    if scheduler::is_idle() {
+        // No timer pending: sleep until another interrupt arrives
+        set_comparator(timer::next_deadline().unwrap_or(Instant(u64::MAX)));
+        return;
+    }
+
+    let period = duration_to_ticks(TICK);
+    let last: u64;
+    unsafe { asm!("mrs {}, cntp_cval_el0", out(reg) last); }
+    // Skip the ticks that were missed instead of firing for each of them
+    let missed = now.0.saturating_sub(last) / period;
+    set_comparator(Instant(last + (missed + 1) * period));
+}
@@ -0,0 +1,155 @@
+//! Kernel timers on top of the CNTP timer interrupt.
+//!
+//! Pending timers are kept in a list sorted by deadline, so each tick only
+//! looks at its head. The tick handler works through the list in interrupt
+//! context, so it is a fixed-size table, and callbacks must neither block
+//! nor allocate. Every CPU's tick runs due timers, so a callback may run on
+//! any CPU.
+
+use core::{arch::asm, time::Duration};
+
+use crate::{exceptions::irq::without_interrupts, thread::{self, ThreadId}, time::Instant};
+
+/// Most timers pending at once
+const MAX_TIMERS: usize = 128;
+
+#[derive(Clone, Copy)]
+enum Action {
+    Call(fn()),
+    /// Wake a thread parked in `sleep_ms`
+    Wake(ThreadId),
+}
+
+#[derive(Clone, Copy)]
+struct Entry {
+    id: u64,
+    deadline: Instant,
+    /// Time between firings, zero for one-shot timers
+    period: Duration,
+    action: Action,
+}
+
+struct TimerList {
+    entries: [Option<Entry>; MAX_TIMERS],
+    len: usize,
+    next_id: u64,
+}
+
+impl TimerList {
+    /// Insert behind every entry with the same deadline, so equal timers
+    /// fire in the order they were added
+    fn insert(&mut self, entry: Entry) -> Result<(), &'static str> {
+        if self.len == MAX_TIMERS {
+            return Err("[   TIMER   ] \x1b[1;31mToo many timers\x1b[0m");
+        }
+        let at = self.entries[..self.len].partition_point(|e| e.is_some_and(|e| e.deadline <= entry.deadline));
+        self.entries[at..=self.len].rotate_right(1);
+        self.entries[at] = Some(entry);
+        self.len += 1;
+        Ok(())
+    }
+
+    fn remove(&mut self, at: usize) -> Option<Entry> {
+        let entry = self.entries[at].take();
+        self.entries[at..self.len].rotate_left(1);
+        self.len -= 1;
+        entry
+    }
+
+    /// Take the head if it is due at `now`, re-arming periodic timers
+    fn pop_expired(&mut self, now: Instant) -> Option<Entry> {
+        let head = self.entries[0].filter(|e| e.deadline <= now)?;
+        self.remove(0);
+        if !head.period.is_zero() {
+            // A periodic timer that cannot be re-armed simply stops
+            let _ = self.insert(Entry { deadline: head.deadline + head.period, ..head });
+        }
+        Some(head)
+    }
+}
+
+static TIMERS: spin::Mutex<TimerList> = spin::Mutex::new(TimerList { entries: [None; MAX_TIMERS], len: 0, next_id: 0 });
+
+fn add(after: Duration, period: Duration, action: Action) -> Result<Timer, &'static str> {
+    without_interrupts(|| {
+        let mut timers = TIMERS.lock();
+        let id = timers.next_id;
+        timers.next_id += 1;
+        timers.insert(Entry { id, deadline: Instant::now() + after, period, action })?;
+        Ok(Timer { id })
+    })
+}
+
+/// A pending timer. Dropping the handle leaves the timer armed.
+pub struct Timer {
+    id: u64,
+}
+
+impl Timer {
+    /// Call `f` once, `ms` milliseconds from now (from interrupt context)
+    pub fn after(ms: u64, f: fn()) -> Result<Timer, &'static str> {
+        add(Duration::from_millis(ms), Duration::ZERO, Action::Call(f))
+    }
+
+    /// Call `f` every `ms` milliseconds (from interrupt context)
+    pub fn every(ms: u64, f: fn()) -> Result<Timer, &'static str> {
+        let period = Duration::from_millis(ms.max(1));
+        add(period, period, Action::Call(f))
+    }
+
+    /// Disarm the timer. Returns false if it already fired (one-shot timers).
+    pub fn cancel(self) -> bool {
+        without_interrupts(|| {
+            let mut timers = TIMERS.lock();
+            let len = timers.len;
+            match timers.entries[..len].iter().position(|e| e.is_some_and(|e| e.id == self.id)) {
+                Some(at) => timers.remove(at).is_some(),
+                None => false,
+            }
+        })
+    }
+}
+
+/// Deadline of the next pending timer
+pub fn next_deadline() -> Option<Instant> {
+    TIMERS.lock().entries[0].map(|e| e.deadline)
+}
+
+/// Run the timers due at `now`. Called from the timer interrupt.
+pub fn tick(now: Instant) {
+    loop {
+        // Actions run without the lock: they may add timers or wake threads
+        let Some(entry) = TIMERS.lock().pop_expired(now) else { break; };
+        match entry.action {
+            Action::Call(f) => f(),
+            Action::Wake(id) => thread::wake(id),
+        }
+    }
+}
+
+/// Park the running thread for `ms` milliseconds. Before the scheduler runs
+/// (or if no timer is left) the CPU waits for interrupts instead.
+pub fn sleep_ms(ms: u64) {
+    let deadline = Instant::now() + Duration::from_millis(ms);
+
+    // Blocked and armed under one mask, so the tick cannot switch away in between
+    let parked = without_interrupts(|| {
+        let Some(id) = thread::block_current() else { return false; };
+        match add(Duration::from_millis(ms), Duration::ZERO, Action::Wake(id)) {
+            Ok(_) => true,
+            Err(_) => {
+                thread::wake(id);
+                false
+            }
+        }
+    });
+
+    if parked {
+        thread::yield_now();
+    } else {
+        while Instant::now() < deadline {
+            unsafe { asm!("wfi"); }
+        }
+    }
+}
+